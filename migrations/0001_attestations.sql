@@ -0,0 +1,11 @@
+-- Service signatures over written payloads, see `src/attestation.rs`.
+CREATE TABLE IF NOT EXISTS attestations (
+    txid TEXT PRIMARY KEY,
+    network TEXT NOT NULL,
+    payload BYTEA NOT NULL,
+    timestamp BIGINT NOT NULL,
+    pubkey TEXT NOT NULL,
+    signature BYTEA NOT NULL,
+    on_chain BOOLEAN NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
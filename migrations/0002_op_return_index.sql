@@ -0,0 +1,20 @@
+-- Chain-wide OP_RETURN index, see `src/indexer.rs`.
+CREATE TABLE IF NOT EXISTS indexed_blocks (
+    height INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL,
+    indexed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS op_returns (
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    height INTEGER NOT NULL REFERENCES indexed_blocks (height) ON DELETE CASCADE,
+    payload BYTEA NOT NULL,
+    script BYTEA NOT NULL,
+    -- btree friendly head of the payload for prefix searches
+    prefix BYTEA GENERATED ALWAYS AS (substring(payload FROM 1 FOR 16)) STORED,
+    PRIMARY KEY (txid, vout)
+);
+
+CREATE INDEX IF NOT EXISTS op_returns_height ON op_returns (height);
+CREATE INDEX IF NOT EXISTS op_returns_prefix ON op_returns (prefix);
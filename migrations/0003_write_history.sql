@@ -0,0 +1,36 @@
+-- Transactions written by this service and what happened to them, see `src/tracker.rs`.
+CREATE TABLE IF NOT EXISTS writes (
+    txid TEXT PRIMARY KEY,
+    -- op_return, commit or reveal
+    kind TEXT NOT NULL,
+    -- empty for the commit half of an envelope write
+    payload BYTEA NOT NULL,
+    raw_tx BYTEA NOT NULL,
+    -- unconfirmed or confirmed
+    status TEXT NOT NULL DEFAULT 'unconfirmed',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Every block a write was confirmed in. A reorg sets `reorged_at` instead of deleting the
+-- row, so the history keeps the old height next to the new one.
+CREATE TABLE IF NOT EXISTS write_confirmations (
+    id BIGSERIAL PRIMARY KEY,
+    txid TEXT NOT NULL REFERENCES writes (txid) ON DELETE CASCADE,
+    height INTEGER NOT NULL,
+    block_hash TEXT NOT NULL,
+    confirmed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    reorged_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS write_confirmations_txid ON write_confirmations (txid);
+
+CREATE TABLE IF NOT EXISTS write_events (
+    id BIGSERIAL PRIMARY KEY,
+    txid TEXT NOT NULL REFERENCES writes (txid) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    height INTEGER,
+    previous_height INTEGER,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS write_events_txid ON write_events (txid);
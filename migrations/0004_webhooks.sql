@@ -0,0 +1,35 @@
+-- Webhook subscriptions and their deliveries, see `src/webhooks.rs`.
+ALTER TABLE write_events ADD COLUMN IF NOT EXISTS confirmations INTEGER;
+ALTER TABLE write_events ADD COLUMN IF NOT EXISTS replaced_by TEXT;
+ALTER TABLE write_events ADD COLUMN IF NOT EXISTS reason TEXT;
+
+-- Highest confirmation threshold already announced for this confirmation.
+-- `writes.status` may now also be replaced or failed, neither is tracked any further.
+ALTER TABLE write_confirmations ADD COLUMN IF NOT EXISTS notified_confirmations INTEGER NOT NULL DEFAULT 1;
+
+CREATE TABLE IF NOT EXISTS webhooks (
+    id UUID PRIMARY KEY,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    -- event kinds to deliver, empty for all of them
+    events TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id UUID NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event_id BIGINT NOT NULL REFERENCES write_events (id) ON DELETE CASCADE,
+    -- pending, delivered or failed
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    response_status INTEGER,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due ON webhook_deliveries (next_attempt_at)
+    WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook ON webhook_deliveries (webhook_id, id);
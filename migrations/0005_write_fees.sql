@@ -0,0 +1,2 @@
+-- Fee paid by each write in sats, shown in the write history, see `WriteRecord`.
+ALTER TABLE writes ADD COLUMN IF NOT EXISTS fee BIGINT;
@@ -0,0 +1,5 @@
+-- Funding alerts of the balance monitor are events without a write, see `src/monitor.rs`.
+ALTER TABLE write_events ALTER COLUMN txid DROP NOT NULL;
+ALTER TABLE write_events ADD COLUMN IF NOT EXISTS balance BIGINT;
+ALTER TABLE write_events ADD COLUMN IF NOT EXISTS runway BIGINT;
+ALTER TABLE write_events ADD COLUMN IF NOT EXISTS threshold BIGINT;
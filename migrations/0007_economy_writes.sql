@@ -0,0 +1,16 @@
+-- Writes of the economy tier, anchored in batches under a Merkle root, see `src/batch.rs`.
+CREATE TABLE IF NOT EXISTS economy_writes (
+    id UUID PRIMARY KEY,
+    data TEXT NOT NULL,
+    payload BYTEA NOT NULL,
+    deadline TIMESTAMPTZ NOT NULL,
+    queued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- the anchor transaction and the leaf of the payload, unset while queued
+    txid TEXT,
+    leaf_index INTEGER,
+    batched_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS economy_writes_queued ON economy_writes (queued_at, id)
+    WHERE txid IS NULL;
+CREATE INDEX IF NOT EXISTS economy_writes_batch ON economy_writes (txid, leaf_index);
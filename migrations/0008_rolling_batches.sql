@@ -0,0 +1,2 @@
+-- Economy batches stay open to replacements until their anchor confirms, see `src/batch.rs`.
+ALTER TABLE economy_writes ADD COLUMN IF NOT EXISTS confirmed_at TIMESTAMPTZ;
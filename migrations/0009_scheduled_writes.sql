@@ -0,0 +1,18 @@
+-- Writes held back until a block height or a point in time, see `src/schedule.rs`.
+CREATE TABLE IF NOT EXISTS scheduled_writes (
+    id UUID PRIMARY KEY,
+    data TEXT NOT NULL,
+    payload BYTEA NOT NULL,
+    attest BOOLEAN NOT NULL DEFAULT false,
+    not_before_height INTEGER,
+    not_before_time TIMESTAMPTZ,
+    -- scheduled, sending, sent, failed or cancelled
+    status TEXT NOT NULL DEFAULT 'scheduled',
+    txid TEXT,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sent_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS scheduled_writes_due ON scheduled_writes (created_at)
+    WHERE status = 'scheduled';
@@ -0,0 +1,3 @@
+-- Looks writes up by payload to skip duplicates, see `GRAFFITI_DEDUP`.
+CREATE INDEX IF NOT EXISTS writes_payload_hash ON writes (sha256(payload))
+    WHERE kind = 'op_return';
@@ -0,0 +1,9 @@
+-- Multisig writes waiting for cosigner signatures, see `src/multisig.rs`.
+CREATE TABLE IF NOT EXISTS pending_writes (
+    id UUID PRIMARY KEY,
+    data TEXT NOT NULL,
+    -- base64, with the signatures collected so far
+    psbt TEXT NOT NULL,
+    threshold INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
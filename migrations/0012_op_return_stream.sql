@@ -0,0 +1,13 @@
+-- Live stream of chain-wide OP_RETURN outputs, see `src/stream.rs`. The id is the cursor
+-- clients resume from.
+CREATE TABLE IF NOT EXISTS op_return_events (
+    id BIGSERIAL PRIMARY KEY,
+    event TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    -- unset for mempool events
+    height INTEGER,
+    payload BYTEA NOT NULL,
+    script BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,7 @@
+-- Wallet checkpoints of the rpc backend, see `src/watcher.rs`. Each row is what changed in
+-- the wallet since the previous one, merged into a single row when the service starts.
+CREATE TABLE IF NOT EXISTS wallet_changesets (
+    id BIGSERIAL PRIMARY KEY,
+    changeset JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,23 @@
+-- Writes held back by the mempool ancestor and descendant limits, see `src/queue.rs`. Rows
+-- stay once the write left the queue, so its result can still be looked up.
+CREATE TABLE IF NOT EXISTS queued_writes (
+    id UUID PRIMARY KEY,
+    data TEXT NOT NULL,
+    payload BYTEA NOT NULL,
+    -- signature and timestamp of `Attestation::to_bytes`, with the key that made them
+    attestation BYTEA,
+    attestation_pubkey TEXT,
+    on_chain BOOLEAN NOT NULL,
+    envelope BOOLEAN NOT NULL,
+    fee_target INTEGER NOT NULL,
+    replaceable BOOLEAN NOT NULL,
+    reason TEXT NOT NULL,
+    -- queued, broadcast, pending_signatures or failed
+    status TEXT NOT NULL DEFAULT 'queued',
+    result JSONB,
+    queued_at TIMESTAMPTZ NOT NULL,
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS queued_writes_waiting ON queued_writes (queued_at)
+    WHERE status = 'queued';
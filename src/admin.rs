@@ -0,0 +1,52 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use bdk_wallet::bitcoin::hashes::{sha256, Hash};
+
+use crate::error::{Graffiti, Report};
+use crate::util::GrafittiState;
+
+/// Proof that a request carries `Authorization: Bearer <GRAFFITI_ADMIN_TOKEN>`. Handlers of
+/// the admin API take it as an argument, without a configured token they always refuse.
+pub struct Admin;
+
+#[async_trait]
+impl FromRequestParts<GrafittiState> for Admin {
+    type Rejection = Report;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        gs: &GrafittiState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(token) = gs.config.admin_token.as_deref() else {
+            return Err(Report::from(Graffiti::Unauthorized(
+                "the admin API is disabled, set GRAFFITI_ADMIN_TOKEN".to_string(),
+            )));
+        };
+        let given = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(given) if token_matches(token, given) => Ok(Self),
+            _ => Err(Report::from(Graffiti::Unauthorized(
+                "missing or wrong admin token".to_string(),
+            ))),
+        }
+    }
+}
+
+/// Compares the digests of both tokens in constant time, so neither the bytes nor the length
+/// of the token can be guessed from response times.
+pub fn token_matches(token: &str, given: &str) -> bool {
+    let token = sha256::Hash::hash(token.as_bytes());
+    let given = sha256::Hash::hash(given.as_bytes());
+    token
+        .as_byte_array()
+        .iter()
+        .zip(given.as_byte_array())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
@@ -0,0 +1,125 @@
+use anyhow::anyhow;
+use bdk_wallet::bitcoin::blockdata::opcodes::all::OP_RETURN;
+use bdk_wallet::bitcoin::hashes::{sha256, Hash, HashEngine};
+use bdk_wallet::bitcoin::key::{Keypair, Secp256k1, XOnlyPublicKey};
+use bdk_wallet::bitcoin::script::{PushBytes, PushBytesBuf};
+use bdk_wallet::bitcoin::secp256k1::{schnorr, Message};
+use bdk_wallet::bitcoin::{Network, ScriptBuf, Transaction};
+use serde_json::json;
+
+use crate::util::{op_return_pushes, NETWORK};
+
+/// Domain separator so attestations can't be replayed as signatures over anything else.
+const ATTESTATION_TAG: &[u8] = b"graffiti/attestation";
+/// Signature followed by the big endian timestamp, as pushed on chain.
+pub const ATTESTATION_LEN: usize = 64 + 8;
+/// Data carrier limit of the default relay policy before Bitcoin Core 30.
+pub const MAX_OP_RETURN_DATA: usize = 80;
+
+/// A BIP340 signature by the service key over the network, the payload and a timestamp.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Attestation {
+    pub network: Network,
+    pub timestamp: u64,
+    pub pubkey: XOnlyPublicKey,
+    pub signature: schnorr::Signature,
+}
+
+fn message(network: Network, timestamp: u64, payload: &[u8]) -> Message {
+    let mut engine = sha256::Hash::engine();
+    engine.input(ATTESTATION_TAG);
+    engine.input(network.to_core_arg().as_bytes());
+    engine.input(&timestamp.to_be_bytes());
+    engine.input(payload);
+    Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+impl Attestation {
+    pub fn sign(keypair: &Keypair, payload: &[u8]) -> Self {
+        let secp = Secp256k1::new();
+        let timestamp = chrono::Utc::now().timestamp().unsigned_abs();
+        let signature = secp.sign_schnorr(&message(NETWORK, timestamp, payload), keypair);
+        Self {
+            network: NETWORK,
+            timestamp,
+            pubkey: keypair.x_only_public_key().0,
+            signature,
+        }
+    }
+
+    pub fn verify(&self, payload: &[u8]) -> bool {
+        let secp = Secp256k1::verification_only();
+        let message = message(self.network, self.timestamp, payload);
+        secp.verify_schnorr(&self.signature, &message, &self.pubkey)
+            .is_ok()
+    }
+
+    pub fn to_bytes(&self) -> [u8; ATTESTATION_LEN] {
+        let mut bytes = [0; ATTESTATION_LEN];
+        bytes[..64].copy_from_slice(self.signature.as_ref());
+        bytes[64..].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+
+    /// Reads an attestation pushed on chain. The public key isn't part of it, readers check
+    /// against the key they expect.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if `bytes` is not a serialized attestation
+    pub fn from_bytes(bytes: &[u8], pubkey: XOnlyPublicKey) -> anyhow::Result<Self> {
+        if bytes.len() != ATTESTATION_LEN {
+            return Err(anyhow!("attestations are {ATTESTATION_LEN} bytes"));
+        }
+        let signature = schnorr::Signature::from_slice(&bytes[..64])?;
+        let timestamp = u64::from_be_bytes(bytes[64..].try_into()?);
+        Ok(Self {
+            network: NETWORK,
+            timestamp,
+            pubkey,
+            signature,
+        })
+    }
+
+    /// Whether the payload and this attestation fit together in one standard `OP_RETURN`.
+    pub const fn fits_on_chain(payload: &[u8]) -> bool {
+        // the attestation push needs a length byte, the payload push may need two
+        let push_overhead = if payload.len() > 75 { 2 } else { 1 };
+        payload.len() + push_overhead + 1 + ATTESTATION_LEN <= MAX_OP_RETURN_DATA + 2
+    }
+
+    /// `OP_RETURN <payload> <attestation>`
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the payload is too large for a single push
+    pub fn script(&self, payload: &[u8]) -> anyhow::Result<ScriptBuf> {
+        Ok(ScriptBuf::builder()
+            .push_opcode(OP_RETURN)
+            .push_slice(<&PushBytes>::try_from(payload)?)
+            .push_slice(PushBytesBuf::try_from(self.to_bytes().to_vec())?)
+            .into_script())
+    }
+
+    /// Finds an attestation pushed after the payload of an `OP_RETURN` in `tx`, returning the
+    /// payload along with it.
+    pub fn from_tx(tx: &Transaction, pubkey: XOnlyPublicKey) -> Option<(Vec<u8>, Self)> {
+        tx.output.iter().find_map(|output| {
+            let mut pushes = op_return_pushes(&output.script_pubkey)?;
+            if pushes.len() != 2 {
+                return None;
+            }
+            let attestation = Self::from_bytes(&pushes.pop()?, pubkey).ok()?;
+            Some((pushes.pop()?, attestation))
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "network": self.network,
+            "timestamp": self.timestamp,
+            "pubkey": self.pubkey,
+            "signature": self.signature,
+        })
+    }
+}
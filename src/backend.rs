@@ -0,0 +1,468 @@
+use anyhow::bail;
+use bdk_bitcoind_rpc::{BlockEvent, Emitter};
+use bdk_electrum::bdk_chain::local_chain::CheckPoint;
+use bdk_electrum::bdk_chain::spk_client::{SyncRequest, SyncResult};
+use bdk_electrum::bdk_chain::ConfirmationTimeHeightAnchor;
+use bdk_electrum::electrum_client::{ElectrumApi, Error as ElectrumError};
+use bdk_esplora::esplora_client::{self, AsyncClient as EsploraClient};
+use bdk_esplora::EsploraAsyncExt;
+use bdk_wallet::bitcoin::{Block, BlockHash, FeeRate, OutPoint, ScriptBuf, Transaction, Txid};
+use bdk_wallet::Wallet;
+use bitcoincore_rpc::jsonrpc::error::Error as JsonRpcError;
+use bitcoincore_rpc::{Client as RpcClient, Error as RpcError, RpcApi};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+use crate::config::BackendConfig;
+use crate::electrum::{Client, ElectrumPool};
+use crate::util::{BATCH_SIZE, STOP_GAP};
+
+/// Concurrent requests made to an Esplora server while syncing.
+const PARALLEL_REQUESTS: usize = 5;
+/// Blocks fetched from bitcoind before they are applied to the wallet.
+const BLOCK_BATCH: usize = 100;
+/// `RPC_INVALID_ADDRESS_OR_KEY`, what bitcoind answers for an unknown transaction.
+const RPC_NOT_FOUND: i32 = -5;
+
+/// Why a broadcast failed.
+#[derive(thiserror::Error, Debug)]
+pub enum BroadcastError {
+    /// The server refused the transaction, sending it again won't help.
+    #[error("transaction rejected: {0}")]
+    Rejected(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Where the service reads the chain from and sends transactions to, see [`BackendConfig`].
+pub enum ChainBackend {
+    Electrum(ElectrumPool),
+    Esplora(EsploraClient),
+    /// A Bitcoin Core node, the wallet is synced from the blocks it emits.
+    Rpc {
+        client: Arc<RpcClient>,
+        /// First block scanned for a wallet without history.
+        start_height: u32,
+        /// Mempool transactions already given to the wallet.
+        mempool: Mutex<HashSet<Txid>>,
+    },
+}
+
+/// Runs a blocking RPC call off the async runtime.
+async fn rpc<T, F>(client: &Arc<RpcClient>, call: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&RpcClient) -> bitcoincore_rpc::Result<T> + Send + 'static,
+{
+    let client = client.clone();
+    Ok(tokio::task::spawn_blocking(move || call(&client)).await??)
+}
+
+/// The message of an error answered by bitcoind, as opposed to a transport error.
+fn rpc_rejection(error: &RpcError) -> Option<(i32, &str)> {
+    match error {
+        RpcError::JsonRpc(JsonRpcError::Rpc(e)) => Some((e.code, &e.message)),
+        _ => None,
+    }
+}
+
+impl ChainBackend {
+    /// # Errors
+    ///
+    /// Will return an error if the client can't be created
+    pub fn new(config: &BackendConfig) -> anyhow::Result<Self> {
+        Ok(match config {
+            BackendConfig::Electrum { servers } => Self::Electrum(ElectrumPool::new(servers)),
+            BackendConfig::Esplora { url } => {
+                Self::Esplora(esplora_client::Builder::new(url).build_async()?)
+            }
+            BackendConfig::Rpc { rpc, start_height } => Self::Rpc {
+                client: Arc::new(rpc.client()?),
+                start_height: *start_height,
+                mempool: Mutex::default(),
+            },
+        })
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Electrum(_) => "electrum",
+            Self::Esplora(_) => "esplora",
+            Self::Rpc { .. } => "rpc",
+        }
+    }
+
+    /// The Electrum servers, for what only the Electrum protocol offers such as subscriptions.
+    pub const fn electrum(&self) -> Option<&ElectrumPool> {
+        match self {
+            Self::Electrum(pool) => Some(pool),
+            Self::Esplora(_) | Self::Rpc { .. } => None,
+        }
+    }
+
+    /// Health of the backend for `GET /health`.
+    pub fn status(&self) -> serde_json::Value {
+        match self {
+            Self::Electrum(pool) => serde_json::json!({
+                "backend": self.name(),
+                "healthy": pool.is_healthy(),
+                "electrum": pool.to_json(),
+            }),
+            Self::Esplora(_) | Self::Rpc { .. } => serde_json::json!({
+                "backend": self.name(),
+                "healthy": true,
+            }),
+        }
+    }
+
+    /// Scans every script of `wallet` up to the stop gap and applies what was found.
+    ///
+    /// # Errors
+    ///
+    /// Will return errors if the server can't be reached
+    pub async fn full_scan(&self, wallet: &mut Wallet) -> anyhow::Result<()> {
+        info!("full scan through {}", self.name());
+        let mut update = match self {
+            Self::Electrum(pool) => {
+                // Connections fill their transaction cache from the pool's, so they don't
+                // redownload transactions we already have.
+                pool.cache_txs(wallet.transactions().map(|tx| tx.tx_node.tx.clone()));
+                let update = pool
+                    .call(|| {
+                        let request = wallet.start_full_scan();
+                        move |client: &Client| {
+                            client
+                                .full_scan(request, STOP_GAP, BATCH_SIZE, false)?
+                                .with_confirmation_time_height_anchor(client)
+                        }
+                    })
+                    .await?;
+                pool.cache_txs(update.graph_update.full_txs().map(|node| node.tx));
+                update
+            }
+            Self::Esplora(client) => {
+                client
+                    .full_scan(wallet.start_full_scan(), STOP_GAP, PARALLEL_REQUESTS)
+                    .await?
+            }
+            Self::Rpc { client, .. } => {
+                loop {
+                    let blocks = self.next_blocks(wallet.latest_checkpoint()).await?;
+                    let done = blocks.len() < BLOCK_BATCH;
+                    apply_blocks(wallet, blocks)?;
+                    if done {
+                        break;
+                    }
+                }
+                // A fresh wallet needs the whole mempool, not only what changed since the last
+                // poll.
+                let relevant = Relevant::of(wallet);
+                let (txs, _) = fetch_mempool(client, HashSet::new(), relevant).await?;
+                apply_mempool(wallet, &txs);
+                return Ok(());
+            }
+        };
+
+        let now = std::time::UNIX_EPOCH.elapsed()?.as_secs();
+        let _ = update.graph_update.update_last_seen_unconfirmed(now);
+        wallet.apply_update(update)?;
+        Ok(())
+    }
+
+    /// # Errors
+    ///
+    /// Will return errors if the server can't be reached
+    pub async fn sync(
+        &self,
+        request: SyncRequest,
+    ) -> anyhow::Result<SyncResult<ConfirmationTimeHeightAnchor>> {
+        Ok(match self {
+            // A sync request can't be repeated, it fails over on the next call.
+            Self::Electrum(pool) => {
+                let update = pool
+                    .call_primary(move |client: &Client| {
+                        client
+                            .sync(request, BATCH_SIZE, false)?
+                            .with_confirmation_time_height_anchor(client)
+                    })
+                    .await?;
+                pool.cache_txs(update.graph_update.full_txs().map(|node| node.tx));
+                update
+            }
+            Self::Esplora(client) => client.sync(request, PARALLEL_REQUESTS).await?,
+            Self::Rpc { .. } => {
+                bail!("the rpc backend syncs blocks, see ChainBackend::sync_blocks")
+            }
+        })
+    }
+
+    /// Applies the blocks mined since the tip of `wallet`, then the mempool transactions that
+    /// appeared since the last call. Only the RPC backend syncs this way. Blocks and
+    /// transactions are read from bitcoind without holding the wallet, which is only locked to
+    /// apply them.
+    ///
+    /// # Errors
+    ///
+    /// Will return errors if bitcoind can't be reached
+    pub async fn sync_blocks(&self, wallet: &Mutex<Wallet>) -> anyhow::Result<()> {
+        let Self::Rpc {
+            client, mempool, ..
+        } = self
+        else {
+            bail!("{} syncs scripts, not blocks", self.name());
+        };
+        loop {
+            let checkpoint = wallet.lock().await.latest_checkpoint();
+            let blocks = self.next_blocks(checkpoint).await?;
+            let done = blocks.len() < BLOCK_BATCH;
+            apply_blocks(&mut *wallet.lock().await, blocks)?;
+            if done {
+                break;
+            }
+        }
+
+        let relevant = Relevant::of(&*wallet.lock().await);
+        let mut mempool = mempool.lock().await;
+        let (txs, seen) = fetch_mempool(client, mempool.clone(), relevant).await?;
+        apply_mempool(&mut *wallet.lock().await, &txs);
+        *mempool = seen;
+        Ok(())
+    }
+
+    /// Emits up to [`BLOCK_BATCH`] blocks from bitcoind after `checkpoint`. The emitter checks
+    /// the checkpoint against the node, so blocks of a reorged wallet start below its tip.
+    async fn next_blocks(&self, checkpoint: CheckPoint) -> anyhow::Result<Vec<BlockEvent<Block>>> {
+        let Self::Rpc {
+            client,
+            start_height,
+            ..
+        } = self
+        else {
+            bail!("only the rpc backend emits blocks");
+        };
+        let start_height = *start_height;
+        rpc(client, move |rpc| {
+            let mut emitter = Emitter::new(rpc, checkpoint, start_height);
+            let mut blocks = Vec::new();
+            while blocks.len() < BLOCK_BATCH {
+                match emitter.next_block()? {
+                    Some(event) => blocks.push(event),
+                    None => break,
+                }
+            }
+            Ok(blocks)
+        })
+        .await
+    }
+
+    /// # Errors
+    ///
+    /// Will return [`BroadcastError::Rejected`] if the server refused the transaction
+    pub async fn broadcast(&self, tx: &Transaction) -> Result<(), BroadcastError> {
+        match self {
+            Self::Electrum(pool) => match pool.broadcast(tx).await {
+                Ok(()) => Ok(()),
+                Err(ElectrumError::Protocol(reason)) => {
+                    Err(BroadcastError::Rejected(reason.to_string()))
+                }
+                Err(e) => Err(anyhow::Error::from(e).into()),
+            },
+            Self::Esplora(client) => match client.broadcast(tx).await {
+                Ok(()) => Ok(()),
+                Err(esplora_client::Error::HttpResponse { status, message })
+                    if (400..500).contains(&status) =>
+                {
+                    Err(BroadcastError::Rejected(message))
+                }
+                Err(e) => Err(anyhow::Error::from(e).into()),
+            },
+            Self::Rpc { client, .. } => {
+                let tx = tx.clone();
+                // testmempoolaccept names the failed policy check, a sendrawtransaction error
+                // doesn't always.
+                let rejection = rpc(client, move |rpc| {
+                    let results = rpc.test_mempool_accept(&[&tx])?;
+                    if let Some(rejected) = results.into_iter().find(|result| !result.allowed) {
+                        return Ok(Some(rejected.reject_reason.unwrap_or_default()));
+                    }
+                    match rpc.send_raw_transaction(&tx) {
+                        Ok(_) => Ok(None),
+                        Err(e) => match rpc_rejection(&e) {
+                            Some((_, message)) => Ok(Some(message.to_string())),
+                            None => Err(e),
+                        },
+                    }
+                })
+                .await?;
+                rejection.map_or(Ok(()), |reason| Err(BroadcastError::Rejected(reason)))
+            }
+        }
+    }
+
+    /// Asks the server for a fee rate that should confirm within `target` blocks.
+    ///
+    /// Falls back to the minimum relay fee when the server has no estimate.
+    ///
+    /// # Errors
+    ///
+    /// Will return errors if the server can't be reached
+    pub async fn estimate_fee_rate(&self, target: usize) -> anyhow::Result<FeeRate> {
+        let sat_per_vb = match self {
+            Self::Electrum(pool) => {
+                // BTC/kvB, or -1 if the server doesn't know
+                let btc_per_kvb = pool
+                    .call(|| move |client: &Client| client.inner.estimate_fee(target))
+                    .await?;
+                (btc_per_kvb > 0.0).then_some(btc_per_kvb * 100_000.0)
+            }
+            Self::Esplora(client) => {
+                // Estimates come for a fixed set of targets, take the closest one that is at
+                // least as fast as asked for.
+                let estimates = client.get_fee_estimates().await?;
+                estimates
+                    .into_iter()
+                    .filter(|(blocks, _)| usize::from(*blocks) <= target)
+                    .max_by_key(|(blocks, _)| *blocks)
+                    .map(|(_, sat_per_vb)| sat_per_vb)
+            }
+            Self::Rpc { client, .. } => {
+                let target = u16::try_from(target).unwrap_or(u16::MAX);
+                let estimate = rpc(client, move |rpc| rpc.estimate_smart_fee(target, None)).await?;
+                // BTC/kvB, missing until the node has seen enough blocks
+                #[allow(clippy::cast_precision_loss)]
+                estimate
+                    .fee_rate
+                    .map(|per_kvb| per_kvb.to_sat() as f64 / 1000.0)
+            }
+        };
+        let Some(sat_per_vb) = sat_per_vb else {
+            return Ok(FeeRate::BROADCAST_MIN);
+        };
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let sat_per_kwu = (sat_per_vb * 250.0).ceil() as u64;
+        Ok(FeeRate::from_sat_per_kwu(sat_per_kwu).max(FeeRate::BROADCAST_MIN))
+    }
+
+    /// A transaction the server has in a block or in its mempool.
+    ///
+    /// # Errors
+    ///
+    /// Will return errors if the server can't be reached
+    pub async fn get_tx(&self, txid: Txid) -> anyhow::Result<Option<Transaction>> {
+        match self {
+            Self::Electrum(pool) => match pool
+                .call(|| move |client: &Client| client.inner.transaction_get(&txid))
+                .await
+            {
+                Ok(tx) => Ok(Some(tx)),
+                Err(ElectrumError::Protocol(_)) => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Self::Esplora(client) => Ok(client.get_tx(&txid).await?),
+            // Confirmed transactions outside the wallet need `txindex=1` on the node.
+            Self::Rpc { client, .. } => {
+                rpc(client, move |rpc| {
+                    match rpc.get_raw_transaction(&txid, None) {
+                        Ok(tx) => Ok(Some(tx)),
+                        Err(e) if matches!(rpc_rejection(&e), Some((RPC_NOT_FOUND, _))) => Ok(None),
+                        Err(e) => Err(e),
+                    }
+                })
+                .await
+            }
+        }
+    }
+
+    /// Hash of the best block at `height`.
+    ///
+    /// # Errors
+    ///
+    /// Will return errors if the server can't be reached or has no such block
+    pub async fn block_hash(&self, height: u32) -> anyhow::Result<BlockHash> {
+        match self {
+            Self::Electrum(pool) => Ok(pool
+                .call(|| move |client: &Client| client.inner.block_header(height as usize))
+                .await?
+                .block_hash()),
+            Self::Esplora(client) => Ok(client.get_block_hash(height).await?),
+            Self::Rpc { client, .. } => {
+                rpc(client, move |rpc| rpc.get_block_hash(height.into())).await
+            }
+        }
+    }
+}
+
+fn apply_blocks(wallet: &mut Wallet, blocks: Vec<BlockEvent<Block>>) -> anyhow::Result<()> {
+    if let Some(last) = blocks.last() {
+        debug!("applying blocks up to {}", last.block_height());
+    }
+    for event in blocks {
+        wallet.apply_block_connected_to(
+            &event.block,
+            event.block_height(),
+            event.connected_to(),
+        )?;
+    }
+    Ok(())
+}
+
+/// What makes a mempool transaction worth giving to the wallet: paying one of its scripts,
+/// the lookahead included, or spending one of its coins.
+struct Relevant {
+    scripts: HashSet<ScriptBuf>,
+    outpoints: HashSet<OutPoint>,
+}
+
+impl Relevant {
+    fn of(wallet: &Wallet) -> Self {
+        Self {
+            scripts: wallet.spk_index().all_spks().values().cloned().collect(),
+            outpoints: wallet.list_unspent().map(|utxo| utxo.outpoint).collect(),
+        }
+    }
+
+    fn contains(&self, tx: &Transaction) -> bool {
+        tx.output
+            .iter()
+            .any(|output| self.scripts.contains(&output.script_pubkey))
+            || tx
+                .input
+                .iter()
+                .any(|input| self.outpoints.contains(&input.previous_output))
+    }
+}
+
+/// Fetches the mempool transactions bitcoind has that aren't in `known` and are relevant to
+/// the wallet, along with every txid now in its mempool. Others are dropped as they are read,
+/// so only the wallet's transactions are kept in memory.
+async fn fetch_mempool(
+    client: &Arc<RpcClient>,
+    known: HashSet<Txid>,
+    relevant: Relevant,
+) -> anyhow::Result<(Vec<Transaction>, HashSet<Txid>)> {
+    rpc(client, move |rpc| {
+        let txids: HashSet<Txid> = rpc.get_raw_mempool()?.into_iter().collect();
+        let mut txs = Vec::new();
+        for txid in txids.difference(&known) {
+            match rpc.get_raw_transaction(txid, None) {
+                Ok(tx) if relevant.contains(&tx) => txs.push(tx),
+                Ok(_) => {}
+                // Mined or evicted since the mempool was listed.
+                Err(e) if matches!(rpc_rejection(&e), Some((RPC_NOT_FOUND, _))) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((txs, txids))
+    })
+    .await
+}
+
+/// Gives the wallet the mempool transactions that are relevant to it.
+fn apply_mempool(wallet: &mut Wallet, txs: &[Transaction]) {
+    let now = std::time::UNIX_EPOCH
+        .elapsed()
+        .map_or(0, |elapsed| elapsed.as_secs());
+    wallet.apply_unconfirmed_txs(txs.iter().map(|tx| (tx, now)));
+}
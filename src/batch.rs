@@ -0,0 +1,486 @@
+use bdk_wallet::bitcoin::hashes::{sha256, Hash, HashEngine};
+use bdk_wallet::bitcoin::hex::DisplayHex;
+use bdk_wallet::bitcoin::script::PushBytesBuf;
+use bdk_wallet::bitcoin::{Amount, FeeRate, OutPoint, ScriptBuf, Transaction, Txid};
+use bdk_wallet::chain::ConfirmationTime;
+use bdk_wallet::{SignOptions, Wallet};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::config::EconomyConfig;
+use crate::db;
+use crate::error;
+use crate::multisig::PendingWrite;
+use crate::queue::PreparedWrite;
+use crate::routes::{record_write, send_op_return, WriteOutcome};
+use crate::tracker::WriteKind;
+use crate::util::{insert_broadcast, GrafittiState};
+
+/// Prefix of the `OP_RETURN` payload that anchors a batch, followed by its Merkle root.
+pub const BATCH_MARKER: &[u8; 4] = b"GRFB";
+/// How often the fee rate is checked against the economy threshold.
+const BATCH_INTERVAL: Duration = Duration::from_secs(60);
+/// Most economy writes anchored by one transaction.
+const MAX_BATCH: u32 = 10_000;
+
+/// How urgently a write goes out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tier {
+    /// Broadcast right away at the usual fee target.
+    #[default]
+    Priority,
+    /// Held until fees are low or its deadline passes, then anchored with the other waiting
+    /// writes.
+    Economy,
+}
+
+/// Hash of a payload as a leaf of the batch tree.
+pub fn leaf_hash(payload: &[u8]) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[0]);
+    engine.input(payload);
+    sha256::Hash::from_engine(engine)
+}
+
+fn node_hash(left: &sha256::Hash, right: &sha256::Hash) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[1]);
+    engine.input(left.as_byte_array());
+    engine.input(right.as_byte_array());
+    sha256::Hash::from_engine(engine)
+}
+
+/// The next level up of the tree. A node without a sibling moves up unchanged.
+fn parent_level(level: &[sha256::Hash]) -> Vec<sha256::Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => *single,
+            _ => unreachable!("chunks of two"),
+        })
+        .collect()
+}
+
+/// Merkle root of `leaves`, the hash of the zero leaf for an empty batch.
+pub fn merkle_root(leaves: &[sha256::Hash]) -> sha256::Hash {
+    let mut level = leaves.to_vec();
+    if level.is_empty() {
+        return leaf_hash(&[]);
+    }
+    while level.len() > 1 {
+        level = parent_level(&level);
+    }
+    level[0]
+}
+
+/// A sibling on the path from a leaf to the root.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProofStep {
+    pub hash: sha256::Hash,
+    /// Whether the sibling is the left one of the pair.
+    pub left: bool,
+}
+
+impl ProofStep {
+    pub fn to_json(self) -> serde_json::Value {
+        json!({
+            "hash": self.hash.to_string(),
+            "side": if self.left { "left" } else { "right" },
+        })
+    }
+}
+
+/// The siblings that lead from leaf `index` to the root.
+pub fn merkle_proof(leaves: &[sha256::Hash], mut index: usize) -> Vec<ProofStep> {
+    let mut proof = Vec::new();
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            proof.push(ProofStep {
+                hash: *hash,
+                left: sibling < index,
+            });
+        }
+        level = parent_level(&level);
+        index /= 2;
+    }
+    proof
+}
+
+/// Whether `proof` leads from `leaf` to `root`.
+pub fn verify_proof(leaf: sha256::Hash, proof: &[ProofStep], root: sha256::Hash) -> bool {
+    let computed = proof.iter().fold(leaf, |hash, step| {
+        if step.left {
+            node_hash(&step.hash, &hash)
+        } else {
+            node_hash(&hash, &step.hash)
+        }
+    });
+    computed == root
+}
+
+/// The `OP_RETURN` payload anchoring a batch with `root`.
+pub fn anchor_payload(root: sha256::Hash) -> Vec<u8> {
+    let mut payload = BATCH_MARKER.to_vec();
+    payload.extend_from_slice(root.as_byte_array());
+    payload
+}
+
+/// A write of the economy tier, waiting for a batch or anchored by one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EconomyWrite {
+    pub id: Uuid,
+    pub data: String,
+    pub payload: Vec<u8>,
+    /// Sent with the next batch once this passes, whatever the fee rate.
+    pub deadline: DateTime<Utc>,
+    pub queued_at: DateTime<Utc>,
+    /// The anchor transaction and the position of the payload in its batch. Both change
+    /// while the anchor is replaced to take in more writes.
+    pub batch: Option<(Txid, u32)>,
+    /// Set once the anchor confirmed, the batch is final from then on.
+    pub confirmed_at: Option<DateTime<Utc>>,
+}
+
+impl EconomyWrite {
+    pub fn new(data: String, payload: Vec<u8>, deadline: DateTime<Utc>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            data,
+            payload,
+            deadline,
+            queued_at: Utc::now(),
+            batch: None,
+            confirmed_at: None,
+        }
+    }
+
+    pub const fn status(&self) -> &'static str {
+        match (self.batch, self.confirmed_at) {
+            (None, _) => "queued",
+            (Some(_), None) => "pending",
+            (Some(_), Some(_)) => "confirmed",
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "tier": "economy",
+            "status": self.status(),
+            "data": self.data,
+            "leaf": leaf_hash(&self.payload).to_string(),
+            "deadline": self.deadline,
+            "queued_at": self.queued_at,
+        })
+    }
+}
+
+/// The last fee check of the [`Batcher`], shown with queued economy writes.
+#[derive(Clone, Debug, Default)]
+pub struct EconomyStatus {
+    pub fee_rate: Option<FeeRate>,
+    pub checked_at: Option<DateTime<Utc>>,
+    /// Why the last due batch couldn't be sent.
+    pub blocked: Option<String>,
+}
+
+/// Economy batching state shared between the handlers and the [`Batcher`].
+#[derive(Debug, Default)]
+pub struct Economy {
+    pub status: Mutex<EconomyStatus>,
+    /// Wakes the [`Batcher`] when a write is queued, so it joins a pending anchor right away.
+    /// Writes queued while a replacement is built share the next one.
+    pub trigger: Notify,
+}
+
+pub type SharedEconomy = Arc<Economy>;
+
+impl EconomyStatus {
+    /// When a queued write goes out: at the first check with a low enough fee rate, or with
+    /// the first batch after its deadline.
+    pub fn dispatch_json(&self, config: &EconomyConfig, write: &EconomyWrite) -> serde_json::Value {
+        json!({
+            "target": config.target,
+            "max_fee_rate": config.max_fee_rate.to_sat_per_vb_ceil(),
+            "deadline": write.deadline,
+            "fee_rate": self.fee_rate.map(FeeRate::to_sat_per_vb_ceil),
+            "checked_at": self.checked_at,
+            "blocked": self.blocked,
+        })
+    }
+}
+
+/// Whether the waiting writes go out now: the fee rate dropped to the threshold, or one of
+/// them is past its deadline.
+pub fn batch_due(
+    writes: &[EconomyWrite],
+    fee_rate: FeeRate,
+    max_fee_rate: FeeRate,
+    now: DateTime<Utc>,
+) -> bool {
+    !writes.is_empty()
+        && (fee_rate <= max_fee_rate || writes.iter().any(|write| write.deadline <= now))
+}
+
+/// Wallet coins paid by the unconfirmed `anchors`. A transaction spending one would be evicted
+/// by the next replacement of its anchor, so writes and treasury transactions leave them alone.
+pub fn anchor_coins(wallet: &Wallet, anchors: &[Txid]) -> Vec<OutPoint> {
+    wallet
+        .list_unspent()
+        .filter(|utxo| {
+            anchors.contains(&utxo.outpoint.txid)
+                && matches!(utxo.confirmation_time, ConfirmationTime::Unconfirmed { .. })
+        })
+        .map(|utxo| utxo.outpoint)
+        .collect()
+}
+
+/// Replaces the unconfirmed anchor `txid` with one whose `OP_RETURN` carries `payload`. The
+/// replacement spends the same coins and pays at least 1 sat/vb more, as the mempool requires.
+/// Below that, `fee_rate` is ignored.
+///
+/// # Errors
+///
+/// Will return errors if `txid` isn't an unconfirmed wallet transaction that signals
+/// replaceability, or the replacement can't be funded or signed
+pub fn build_replacement(
+    wallet: &mut Wallet,
+    txid: Txid,
+    payload: Vec<u8>,
+    fee_rate: FeeRate,
+    sign_options: SignOptions,
+) -> anyhow::Result<Transaction> {
+    let original = wallet
+        .get_tx(txid)
+        .ok_or_else(|| anyhow::anyhow!("anchor {txid} is not in the wallet"))?
+        .tx_node
+        .tx
+        .as_ref()
+        .clone();
+    let bumped = wallet.calculate_fee_rate(&original)?.to_sat_per_vb_ceil() + 1;
+    let fee_rate = fee_rate.max(FeeRate::from_sat_per_vb_unchecked(bumped));
+    let script = ScriptBuf::new_op_return(PushBytesBuf::try_from(payload)?);
+
+    let mut tx_builder = wallet.build_fee_bump(txid)?;
+    tx_builder
+        .set_recipients(vec![(script, Amount::ZERO)])
+        .fee_rate(fee_rate)
+        .enable_rbf();
+    let mut psbt = tx_builder.finish()?;
+    anyhow::ensure!(
+        wallet.sign(&mut psbt, sign_options)?,
+        "wallet could not finalize the replacement"
+    );
+    Ok(psbt.extract_tx()?)
+}
+
+fn anchor_for(writes: &[EconomyWrite]) -> sha256::Hash {
+    let leaves: Vec<_> = writes
+        .iter()
+        .map(|write| leaf_hash(&write.payload))
+        .collect();
+    merkle_root(&leaves)
+}
+
+/// Anchors the queued economy writes under one Merkle root once fees allow. Until that anchor
+/// confirms, writes queued in the meantime join it through a replacement with the new root.
+pub struct Batcher {
+    gs: GrafittiState,
+}
+
+impl Batcher {
+    pub const fn new(gs: GrafittiState) -> Self {
+        Self { gs }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.dispatch().await {
+                    warn!("could not send the economy batch, retrying: {e:?}");
+                }
+                tokio::select! {
+                    () = tokio::time::sleep(BATCH_INTERVAL) => {}
+                    () = self.gs.economy.trigger.notified() => {}
+                }
+            }
+        })
+    }
+
+    async fn dispatch(&self) -> error::Result<()> {
+        let config = self.gs.config.economy;
+        let fee_rate = self.gs.blockchain.estimate_fee_rate(config.target).await?;
+        {
+            let mut status = self.gs.economy.status.lock().await;
+            status.fee_rate = Some(fee_rate);
+            status.checked_at = Some(Utc::now());
+        }
+
+        let pending_anchor = self.settle().await?;
+        let writes = db::queued_economy_writes(&self.gs.db, MAX_BATCH).await?;
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let due = batch_due(&writes, fee_rate, config.max_fee_rate, Utc::now());
+        if let Some(txid) = pending_anchor {
+            // Cosigners would have to sign every replacement, so their writes wait instead.
+            if self.gs.config.descriptor_kind().threshold().is_some() {
+                debug!("economy writes wait for anchor {txid} to confirm");
+                return Ok(());
+            }
+            // The anchor's fee is paid once, joining it only costs the incremental relay fee.
+            // The current rate is only paid when a new batch would go out at it.
+            let fee_rate = if due { fee_rate } else { FeeRate::ZERO };
+            return self.replace(txid, writes, fee_rate).await;
+        }
+        if !due {
+            debug!(
+                "{} economy writes wait at {} sat/vb",
+                writes.len(),
+                fee_rate.to_sat_per_vb_ceil()
+            );
+            return Ok(());
+        }
+
+        let root = anchor_for(&writes);
+        let anchor = PreparedWrite {
+            data: format!("batch of {} economy writes", writes.len()),
+            payload: anchor_payload(root),
+            attestation: None,
+            on_chain: false,
+            fee_target: config.target,
+            replaceable: true,
+            envelope: false,
+        };
+        let txid = match send_op_return(&self.gs, anchor).await? {
+            WriteOutcome::Sent(txid, _) | WriteOutcome::Pending(txid, _) => txid,
+            WriteOutcome::Blocked(reason) => {
+                self.gs.economy.status.lock().await.blocked = Some(reason);
+                return Ok(());
+            }
+        };
+        self.gs.economy.status.lock().await.blocked = None;
+
+        let ids: Vec<Uuid> = writes.iter().map(|write| write.id).collect();
+        db::mark_batched(&self.gs.db, txid, &ids).await?;
+        info!(
+            "anchored {} economy writes under {} in {}",
+            writes.len(),
+            root,
+            txid
+        );
+        Ok(())
+    }
+
+    /// Finalizes batches whose anchor confirmed and queues the writes of anchors that left the
+    /// mempool again. Returns the anchor still waiting for a block, if any.
+    async fn settle(&self) -> error::Result<Option<Txid>> {
+        let cosigned: Vec<Txid> = self
+            .gs
+            .pending
+            .lock()
+            .await
+            .values()
+            .map(PendingWrite::txid)
+            .collect();
+        let mut pending_anchor = None;
+        for txid in db::open_batches(&self.gs.db).await? {
+            let confirmed = self
+                .gs
+                .wallet
+                .lock()
+                .await
+                .get_tx(txid)
+                .map(|tx| tx.chain_position.is_confirmed());
+            match confirmed {
+                Some(true) => {
+                    db::confirm_batch(&self.gs.db, txid).await?;
+                    info!("economy batch {txid} confirmed");
+                }
+                Some(false) => pending_anchor = Some(txid),
+                None if cosigned.contains(&txid) => pending_anchor = Some(txid),
+                None => {
+                    db::requeue_batch(&self.gs.db, txid).await?;
+                    warn!("economy batch {txid} left the mempool, its writes are queued again");
+                }
+            }
+        }
+        Ok(pending_anchor)
+    }
+
+    /// Replaces the pending anchor `txid` with one that also commits to `queued`.
+    async fn replace(
+        &self,
+        txid: Txid,
+        queued: Vec<EconomyWrite>,
+        fee_rate: FeeRate,
+    ) -> error::Result<()> {
+        let mut writes = db::batch_writes(&self.gs.db, txid).await?;
+        let room = usize::try_from(MAX_BATCH)?.saturating_sub(writes.len());
+        if room == 0 {
+            debug!("anchor {txid} is full, economy writes wait for it to confirm");
+            return Ok(());
+        }
+        writes.extend(queued.into_iter().take(room));
+        let root = anchor_for(&writes);
+        let payload = anchor_payload(root);
+
+        let mut wallet = self.gs.wallet.lock().await;
+        let sign_options = self.gs.config.descriptor_kind().sign_options();
+        let tx = build_replacement(&mut wallet, txid, payload.clone(), fee_rate, sign_options)?;
+        let fee = wallet.calculate_fee(&tx)?;
+        self.gs.blockchain.broadcast(&tx).await?;
+        insert_broadcast(&mut wallet, &[&tx]);
+        drop(wallet);
+
+        let replacement = tx.compute_txid();
+        record_write(&self.gs, &tx, WriteKind::OpReturn, &payload, fee).await?;
+        let ids: Vec<Uuid> = writes.iter().map(|write| write.id).collect();
+        db::mark_batched(&self.gs.db, replacement, &ids).await?;
+        info!(
+            "replaced anchor {} with {}, now {} economy writes under {}",
+            txid,
+            replacement,
+            writes.len(),
+            root
+        );
+        Ok(())
+    }
+}
+
+/// An anchored economy write. While the anchor is pending, its txid and root are provisional.
+/// Once it confirmed, the receipt holds the path from the payload to the root it committed to.
+pub fn receipt_json(write: &EconomyWrite, batch: &[EconomyWrite]) -> serde_json::Value {
+    let mut j = write.to_json();
+    let Some((txid, index)) = write.batch else {
+        return j;
+    };
+    let leaves: Vec<_> = batch
+        .iter()
+        .map(|write| leaf_hash(&write.payload))
+        .collect();
+    let root = merkle_root(&leaves);
+    j["txid"] = json!(txid);
+    j["leaf_index"] = json!(index);
+    j["root"] = json!(root.to_string());
+    j["anchor"] = json!(anchor_payload(root).to_lower_hex_string());
+    if write.confirmed_at.is_some() {
+        let proof = merkle_proof(&leaves, index as usize);
+        debug_assert!(verify_proof(leaf_hash(&write.payload), &proof, root));
+        let proof: Vec<_> = proof.into_iter().map(ProofStep::to_json).collect();
+        j["proof"] = json!(proof);
+        j["confirmed_at"] = json!(write.confirmed_at);
+    }
+    j
+}
@@ -0,0 +1,697 @@
+use anyhow::{anyhow, bail};
+use bdk_wallet::bitcoin::key::Keypair;
+use bdk_wallet::bitcoin::secp256k1::Secp256k1;
+use bdk_wallet::bitcoin::Network::{Bitcoin, Signet, Testnet};
+use bdk_wallet::bitcoin::{Address, Amount, FeeRate, Weight};
+use bdk_wallet::miniscript::descriptor::{Descriptor, DescriptorPublicKey, WshInner};
+use bdk_wallet::miniscript::Terminal;
+use bdk_wallet::signer::TapLeavesOptions;
+use bdk_wallet::wallet::ChangeSet;
+use bdk_wallet::{SignOptions, Wallet};
+use bitcoincore_rpc::Auth;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::tracker::FINALITY_DEPTH;
+use crate::util::NETWORK;
+use crate::{EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR};
+
+/// The script types the funding wallet may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DescriptorKind {
+    /// Native segwit v0, `wpkh(...)`.
+    Wpkh,
+    /// Taproot key-path spends, `tr(...)` (BIP86).
+    Tr,
+    /// `threshold`-of-`signers` segwit v0 multisig, `wsh(sortedmulti(...))`.
+    WshMulti { threshold: usize, signers: usize },
+    /// `threshold`-of-`signers` taproot multisig with a single `multi_a` leaf, spent through
+    /// the script path.
+    TrMulti { threshold: usize, signers: usize },
+}
+
+/// Length of the compact size prefix in front of a script of `len` bytes.
+pub(crate) const fn compact_size_len(len: u64) -> u64 {
+    if len < 0xfd {
+        1
+    } else if len <= 0xffff {
+        3
+    } else {
+        5
+    }
+}
+
+impl DescriptorKind {
+    /// Parses `descriptor` and returns its kind, rejecting anything we can't spend from.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the descriptor does not parse or is of an unsupported type
+    pub fn of(descriptor: &str) -> anyhow::Result<Self> {
+        let secp = Secp256k1::new();
+        let (descriptor, _) =
+            Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, descriptor)?;
+        match descriptor {
+            Descriptor::Wpkh(_) => Ok(Self::Wpkh),
+            Descriptor::Wsh(wsh) => match wsh.as_inner() {
+                WshInner::SortedMulti(multi) => Ok(Self::WshMulti {
+                    threshold: multi.k(),
+                    signers: multi.pks().len(),
+                }),
+                WshInner::Ms(_) => bail!("wsh descriptors must use sortedmulti"),
+            },
+            Descriptor::Tr(tr) => {
+                let Some(tree) = tr.tap_tree() else {
+                    return Ok(Self::Tr);
+                };
+                let mut leaves = tree.iter();
+                match (leaves.next(), leaves.next()) {
+                    (Some((_, ms)), None) => match &ms.node {
+                        Terminal::MultiA(thresh) => Ok(Self::TrMulti {
+                            threshold: thresh.k(),
+                            signers: thresh.n(),
+                        }),
+                        _ => bail!("the taproot leaf must be a multi_a policy"),
+                    },
+                    _ => bail!("taproot multisig descriptors must have exactly one leaf"),
+                }
+            }
+            other => Err(anyhow!(
+                "unsupported descriptor type {:?}, expected wpkh, tr or wsh(sortedmulti)",
+                other.desc_type()
+            )),
+        }
+    }
+
+    /// Number of signatures needed to spend, `None` for single key wallets.
+    pub const fn threshold(self) -> Option<usize> {
+        match self {
+            Self::Wpkh | Self::Tr => None,
+            Self::WshMulti { threshold, .. } | Self::TrMulti { threshold, .. } => Some(threshold),
+        }
+    }
+
+    /// Signing options for wallets of this kind.
+    ///
+    /// Single key taproot wallets only ever spend through the key path, so script leaves are
+    /// skipped. Taproot multisig does the opposite, a key path signature would bypass the
+    /// threshold.
+    pub fn sign_options(self) -> SignOptions {
+        match self {
+            Self::Wpkh | Self::WshMulti { .. } => SignOptions::default(),
+            Self::Tr => SignOptions {
+                sign_with_tap_internal_key: true,
+                tap_leaves_options: TapLeavesOptions::None,
+                ..SignOptions::default()
+            },
+            Self::TrMulti { .. } => SignOptions {
+                sign_with_tap_internal_key: false,
+                tap_leaves_options: TapLeavesOptions::All,
+                ..SignOptions::default()
+            },
+        }
+    }
+
+    /// Weight of one input spending an output of this kind, witness included.
+    pub const fn input_weight(self) -> Weight {
+        // outpoint (36) + script_sig length (1) + sequence (4), all non-witness
+        let base = 41 * 4;
+        let witness = match self {
+            // item count, signature with sighash flag, compressed public key
+            Self::Wpkh => 1 + 1 + 72 + 1 + 33,
+            // item count, 64 byte schnorr signature with the default sighash
+            Self::Tr => 1 + 1 + 64,
+            Self::WshMulti { threshold, signers } => {
+                // OP_k <pubkey>... OP_n OP_CHECKMULTISIG
+                let script = 34 * signers as u64 + 3;
+                // item count, the empty CHECKMULTISIG dummy, signatures, witness script
+                1 + 1 + 73 * threshold as u64 + compact_size_len(script) + script
+            }
+            Self::TrMulti { threshold, signers } => {
+                // <xonly> OP_CHECKSIG <xonly> OP_CHECKSIGADD ... <k> OP_NUMEQUAL
+                let script = 34 * signers as u64 + 2;
+                // item count, signatures or empty pushes, leaf script, control block
+                let signatures = 65 * threshold as u64 + (signers - threshold) as u64;
+                1 + signatures + compact_size_len(script) + script + 1 + 33
+            }
+        };
+        Weight::from_wu(base + witness)
+    }
+
+    /// Weight of one output paying to a script of this kind.
+    pub const fn output_weight(self) -> Weight {
+        // value (8) + script length (1) + script
+        let script = match self {
+            Self::Wpkh => 22,
+            Self::Tr | Self::WshMulti { .. } | Self::TrMulti { .. } => 34,
+        };
+        Weight::from_wu((8 + 1 + script) * 4)
+    }
+}
+
+/// Where to reach a Bitcoin Core node over JSON-RPC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcConfig {
+    pub url: String,
+    pub auth: Auth,
+}
+
+impl RpcConfig {
+    /// Reads `GRAFFITI_BITCOIND_RPC_URL` along with either a cookie file or a user and password.
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(url) = env::var("GRAFFITI_BITCOIND_RPC_URL") else {
+            return Ok(None);
+        };
+        let auth = match (
+            env::var("GRAFFITI_BITCOIND_RPC_COOKIE").ok(),
+            env::var("GRAFFITI_BITCOIND_RPC_USER").ok(),
+            env::var("GRAFFITI_BITCOIND_RPC_PASSWORD").ok(),
+        ) {
+            (Some(cookie), None, None) => Auth::CookieFile(PathBuf::from(cookie)),
+            (None, Some(user), Some(password)) => Auth::UserPass(user, password),
+            (None, None, None) => Auth::None,
+            _ => bail!("set either a bitcoind cookie file or a user and password, not both"),
+        };
+        Ok(Some(Self { url, auth }))
+    }
+
+    /// # Errors
+    ///
+    /// Will return an error if the cookie file can't be read
+    pub fn client(&self) -> anyhow::Result<bitcoincore_rpc::Client> {
+        Ok(bitcoincore_rpc::Client::new(&self.url, self.auth.clone())?)
+    }
+}
+
+/// Fee rate up to which small coins are consolidated unless
+/// `GRAFFITI_CONSOLIDATION_MAX_FEE_RATE` says otherwise.
+const DEFAULT_CONSOLIDATION_MAX_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(3);
+/// Value of pool coins unless `GRAFFITI_POOL_COIN` says otherwise.
+const DEFAULT_POOL_COIN: Amount = Amount::from_sat(20_000);
+/// Confirmation target of economy writes unless `GRAFFITI_ECONOMY_TARGET` says otherwise.
+const DEFAULT_ECONOMY_TARGET: usize = 144;
+/// Fee rate economy writes wait for unless `GRAFFITI_ECONOMY_MAX_FEE_RATE` says otherwise.
+const DEFAULT_ECONOMY_MAX_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(2);
+/// Longest economy writes wait unless `GRAFFITI_ECONOMY_MAX_DELAY` says otherwise.
+const DEFAULT_ECONOMY_MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Runtime configuration, read from the environment on startup.
+///
+/// The descriptors fall back to the built-in signet wallet so `cargo run` keeps working
+/// without any setup.
+///
+/// Multisig descriptors carry the private keys of the local signers and the public keys of
+/// the external ones, whose signatures are collected through PSBT round-trips.
+#[derive(Clone)]
+pub struct Config {
+    pub external_descriptor: String,
+    pub internal_descriptor: String,
+    /// Descriptors of a previous `wpkh` wallet whose funds can be swept into the current one.
+    pub legacy_descriptors: Option<(String, String)>,
+    /// Postgres connection string, where off-chain attestations are kept.
+    pub database_url: Option<String>,
+    /// Key the service signs payload attestations with.
+    pub attestation_key: Option<Keypair>,
+    pub bitcoind_rpc: Option<RpcConfig>,
+    /// First block of the chain-wide `OP_RETURN` index, the indexer only runs when set.
+    pub index_start_height: Option<u32>,
+    /// Confirmation counts past the first that are announced with a `confirmations` event.
+    pub confirmation_thresholds: Vec<u32>,
+    pub backend: BackendConfig,
+    /// Bearer token of the admin API, which is disabled without one.
+    pub admin_token: Option<String>,
+    /// Remaining write counts below which the balance monitor raises an alert, highest first.
+    pub runway_thresholds: Vec<u64>,
+    /// Cold storage address that excess funds and decommissioning sweeps go to.
+    pub cold_address: Option<Address>,
+    /// Most the hot wallet should hold, anything above it may be swept to the cold address.
+    pub hot_ceiling: Option<Amount>,
+    /// Highest fee rate at which small coins are consolidated.
+    pub consolidation_max_fee_rate: FeeRate,
+    /// The coin pool of `GRAFFITI_POOL_SIZE`, writes chain off change without one.
+    pub fan_out: Option<FanOutConfig>,
+    pub economy: EconomyConfig,
+    /// Answer writes of a payload already in the history with the earlier txid.
+    pub dedup: bool,
+}
+
+/// The chain source, picked with `GRAFFITI_BACKEND`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendConfig {
+    /// The servers of `GRAFFITI_ELECTRUM_SERVERS`, or public ones of the network.
+    Electrum { servers: Vec<ElectrumServerConfig> },
+    /// The Esplora HTTP API of `GRAFFITI_ESPLORA_URL`.
+    Esplora { url: String },
+    /// The node of `GRAFFITI_BITCOIND_RPC_URL`, scanned from `GRAFFITI_WALLET_START_HEIGHT`.
+    Rpc { rpc: RpcConfig, start_height: u32 },
+}
+
+impl BackendConfig {
+    fn from_env(bitcoind_rpc: Option<&RpcConfig>) -> anyhow::Result<Self> {
+        match env::var("GRAFFITI_BACKEND").as_deref() {
+            Err(_) | Ok("electrum") => {
+                let servers = match (env::var("GRAFFITI_ELECTRUM_SERVERS"), NETWORK) {
+                    (Ok(servers), _) => parse_electrum_servers(&servers)
+                        .map_err(|e| anyhow!("invalid GRAFFITI_ELECTRUM_SERVERS: {e}"))?,
+                    (Err(_), Bitcoin) => vec![
+                        ElectrumServerConfig::new("ssl://electrum.blockstream.info:50002"),
+                        ElectrumServerConfig::new("ssl://mempool.space:50002"),
+                    ],
+                    (Err(_), Testnet) => {
+                        vec![ElectrumServerConfig::new(
+                            "ssl://electrum.blockstream.info:60002",
+                        )]
+                    }
+                    (Err(_), Signet) => {
+                        vec![ElectrumServerConfig::new("ssl://mempool.space:60602")]
+                    }
+                    (Err(_), _) => {
+                        bail!("set GRAFFITI_ELECTRUM_SERVERS to use electrum on {NETWORK}")
+                    }
+                };
+                Ok(Self::Electrum { servers })
+            }
+            Ok("esplora") => {
+                // Esplora is asked about every wallet address over plain HTTP requests, so a
+                // public instance would learn the whole wallet. Pick the server on purpose.
+                let url = env::var("GRAFFITI_ESPLORA_URL")
+                    .map_err(|_| anyhow!("the esplora backend needs GRAFFITI_ESPLORA_URL"))?;
+                Ok(Self::Esplora { url })
+            }
+            Ok("rpc") => {
+                let rpc = bitcoind_rpc
+                    .cloned()
+                    .ok_or_else(|| anyhow!("the rpc backend needs GRAFFITI_BITCOIND_RPC_URL"))?;
+                // Scanning from genesis takes days on mainnet, the birthday has to be chosen.
+                let start_height = env::var("GRAFFITI_WALLET_START_HEIGHT")
+                    .map_err(|_| anyhow!("the rpc backend needs GRAFFITI_WALLET_START_HEIGHT"))?
+                    .parse()
+                    .map_err(|e| anyhow!("invalid GRAFFITI_WALLET_START_HEIGHT: {e}"))?;
+                Ok(Self::Rpc { rpc, start_height })
+            }
+            Ok(other) => {
+                bail!("unknown GRAFFITI_BACKEND {other}, expected electrum, esplora or rpc")
+            }
+        }
+    }
+}
+
+/// One server of the Electrum pool.
+#[derive(Clone, Debug, PartialEq, Eq, serde_derive::Deserialize)]
+pub struct ElectrumServerConfig {
+    /// `ssl://host:port` or `tcp://host:port`.
+    pub url: String,
+    /// Whether the TLS certificate must match the host, off for self-signed servers.
+    #[serde(default = "default_validate_domain")]
+    pub validate_domain: bool,
+    /// `host:port` of a SOCKS5 proxy to connect through, e.g. Tor.
+    #[serde(default)]
+    pub socks5: Option<String>,
+    /// Seconds before a request is given up and the next server is tried.
+    #[serde(default = "default_electrum_timeout")]
+    pub timeout: Option<u8>,
+    /// Connections requests are spread over, on top of the one holding subscriptions.
+    #[serde(default = "default_electrum_connections")]
+    pub connections: usize,
+}
+
+const fn default_validate_domain() -> bool {
+    true
+}
+
+#[allow(clippy::unnecessary_wraps)]
+const fn default_electrum_timeout() -> Option<u8> {
+    Some(10)
+}
+
+const fn default_electrum_connections() -> usize {
+    4
+}
+
+impl ElectrumServerConfig {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            validate_domain: default_validate_domain(),
+            socks5: None,
+            timeout: default_electrum_timeout(),
+            connections: default_electrum_connections(),
+        }
+    }
+}
+
+/// A pool of similarly sized confirmed coins that writes spend in parallel, see
+/// [`crate::fanout`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FanOutConfig {
+    /// Coins the pool is kept at.
+    pub size: usize,
+    /// Value of the coins split off for the pool.
+    pub coin: Amount,
+}
+
+impl FanOutConfig {
+    /// Whether a coin of `value` counts towards the pool. Writes shrink pool coins by their
+    /// fee, so anything from half to twice the coin value does.
+    pub fn is_pool_coin(&self, value: Amount) -> bool {
+        (self.coin / 2..=self.coin * 2).contains(&value)
+    }
+}
+
+/// When economy writes are anchored, see [`crate::batch`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EconomyConfig {
+    /// Confirmation target the fee rate is estimated for.
+    pub target: usize,
+    /// Economy writes wait while the estimate is above this.
+    pub max_fee_rate: FeeRate,
+    /// Longest an economy write waits, whatever the fee rate.
+    pub max_delay: Duration,
+}
+
+/// Parses `GRAFFITI_ELECTRUM_SERVERS`: either comma separated urls, or a JSON array of
+/// objects with a `url` and optionally `validate_domain`, `socks5`, `timeout` and
+/// `connections`.
+///
+/// # Errors
+///
+/// Will return an error if the list is empty or a url has no `ssl://` or `tcp://` scheme
+pub fn parse_electrum_servers(s: &str) -> anyhow::Result<Vec<ElectrumServerConfig>> {
+    let servers: Vec<ElectrumServerConfig> = if s.trim_start().starts_with('[') {
+        serde_json::from_str(s)?
+    } else {
+        s.split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(ElectrumServerConfig::new)
+            .collect()
+    };
+    if servers.is_empty() {
+        bail!("no electrum servers");
+    }
+    if let Some(server) = servers
+        .iter()
+        .find(|server| !server.url.starts_with("ssl://") && !server.url.starts_with("tcp://"))
+    {
+        bail!("{} must start with ssl:// or tcp://", server.url);
+    }
+    Ok(servers)
+}
+
+/// Parses a comma separated list of write counts, highest first and without duplicates.
+///
+/// # Errors
+///
+/// Will return an error if a count is not a positive number
+pub fn parse_runway_thresholds(thresholds: &str) -> anyhow::Result<Vec<u64>> {
+    let mut parsed = thresholds
+        .split(',')
+        .map(str::trim)
+        .filter(|threshold| !threshold.is_empty())
+        .map(|threshold| {
+            let count: u64 = threshold.parse()?;
+            if count == 0 {
+                bail!("runway thresholds must be positive, an empty wallet is always reported");
+            }
+            Ok(count)
+        })
+        .collect::<anyhow::Result<Vec<u64>>>()?;
+    parsed.sort_unstable_by(|a, b| b.cmp(a));
+    parsed.dedup();
+    Ok(parsed)
+}
+
+/// Parses a comma separated list of confirmation counts, sorted and without duplicates.
+///
+/// # Errors
+///
+/// Will return an error if a count is not between 2 and [`FINALITY_DEPTH`]
+pub fn parse_thresholds(thresholds: &str) -> anyhow::Result<Vec<u32>> {
+    let mut parsed = thresholds
+        .split(',')
+        .map(str::trim)
+        .filter(|threshold| !threshold.is_empty())
+        .map(|threshold| {
+            let count: u32 = threshold.parse()?;
+            if !(2..=FINALITY_DEPTH).contains(&count) {
+                bail!(
+                    "confirmation thresholds must be between 2 and {FINALITY_DEPTH}, got {count}"
+                );
+            }
+            Ok(count)
+        })
+        .collect::<anyhow::Result<Vec<u32>>>()?;
+    parsed.sort_unstable();
+    parsed.dedup();
+    Ok(parsed)
+}
+
+impl Config {
+    /// # Errors
+    ///
+    /// Will return an error if only one half of a descriptor pair is set, the attestation key
+    /// is not a hex encoded secret key or the admin token is too short
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::from_env_with_backend(None)
+    }
+
+    /// Like [`Config::from_env`], but reads the chain through `backend` instead of the one
+    /// the environment names.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error for the same settings as [`Config::from_env`]
+    pub fn from_env_with_backend(backend: Option<BackendConfig>) -> anyhow::Result<Self> {
+        let external_descriptor = env::var("GRAFFITI_EXTERNAL_DESCRIPTOR")
+            .unwrap_or_else(|_| EXTERNAL_DESCRIPTOR.to_string());
+        let internal_descriptor = env::var("GRAFFITI_INTERNAL_DESCRIPTOR")
+            .unwrap_or_else(|_| INTERNAL_DESCRIPTOR.to_string());
+
+        let legacy_descriptors = match (
+            env::var("GRAFFITI_LEGACY_EXTERNAL_DESCRIPTOR").ok(),
+            env::var("GRAFFITI_LEGACY_INTERNAL_DESCRIPTOR").ok(),
+        ) {
+            (Some(external), Some(internal)) => Some((external, internal)),
+            (None, None) => None,
+            _ => bail!("both legacy descriptors must be set to enable wallet migration"),
+        };
+
+        let database_url = env::var("DATABASE_URL").ok();
+
+        let attestation_key = env::var("GRAFFITI_ATTESTATION_KEY")
+            .ok()
+            .map(|secret| Keypair::from_seckey_str(&Secp256k1::new(), &secret))
+            .transpose()
+            .map_err(|e| anyhow!("invalid GRAFFITI_ATTESTATION_KEY: {e}"))?;
+
+        let bitcoind_rpc = RpcConfig::from_env()?;
+        let index_start_height = env::var("GRAFFITI_INDEX_START_HEIGHT")
+            .ok()
+            .map(|height| height.parse())
+            .transpose()
+            .map_err(|e| anyhow!("invalid GRAFFITI_INDEX_START_HEIGHT: {e}"))?;
+        if index_start_height.is_some() && bitcoind_rpc.is_none() {
+            bail!("the OP_RETURN index reads blocks from bitcoind, set GRAFFITI_BITCOIND_RPC_URL");
+        }
+
+        let confirmation_thresholds = parse_thresholds(
+            &env::var("GRAFFITI_CONFIRMATION_THRESHOLDS").unwrap_or_else(|_| "6".to_string()),
+        )
+        .map_err(|e| anyhow!("invalid GRAFFITI_CONFIRMATION_THRESHOLDS: {e}"))?;
+
+        let backend = match backend {
+            Some(backend) => backend,
+            None => BackendConfig::from_env(bitcoind_rpc.as_ref())?,
+        };
+
+        let runway_thresholds = parse_runway_thresholds(
+            &env::var("GRAFFITI_RUNWAY_THRESHOLDS").unwrap_or_else(|_| "100,10".to_string()),
+        )
+        .map_err(|e| anyhow!("invalid GRAFFITI_RUNWAY_THRESHOLDS: {e}"))?;
+
+        let cold_address = env::var("GRAFFITI_COLD_ADDRESS")
+            .ok()
+            .map(|address| {
+                Address::from_str(&address)?
+                    .require_network(NETWORK)
+                    .map_err(anyhow::Error::from)
+            })
+            .transpose()
+            .map_err(|e| anyhow!("invalid GRAFFITI_COLD_ADDRESS: {e}"))?;
+        let hot_ceiling = env::var("GRAFFITI_HOT_CEILING")
+            .ok()
+            .map(|sats| sats.parse().map(Amount::from_sat))
+            .transpose()
+            .map_err(|e| anyhow!("invalid GRAFFITI_HOT_CEILING: {e}"))?;
+        if hot_ceiling.is_some() && cold_address.is_none() {
+            bail!("excess funds are swept to the cold address, set GRAFFITI_COLD_ADDRESS");
+        }
+        let consolidation_max_fee_rate = env::var("GRAFFITI_CONSOLIDATION_MAX_FEE_RATE")
+            .ok()
+            .map(|rate| rate.parse())
+            .transpose()
+            .map_err(|e| anyhow!("invalid GRAFFITI_CONSOLIDATION_MAX_FEE_RATE: {e}"))?
+            .and_then(FeeRate::from_sat_per_vb)
+            .unwrap_or(DEFAULT_CONSOLIDATION_MAX_FEE_RATE);
+
+        let pool_size: usize = env::var("GRAFFITI_POOL_SIZE")
+            .ok()
+            .map(|size| size.parse())
+            .transpose()
+            .map_err(|e| anyhow!("invalid GRAFFITI_POOL_SIZE: {e}"))?
+            .unwrap_or(0);
+        let pool_coin = env::var("GRAFFITI_POOL_COIN")
+            .ok()
+            .map(|sats| sats.parse().map(Amount::from_sat))
+            .transpose()
+            .map_err(|e| anyhow!("invalid GRAFFITI_POOL_COIN: {e}"))?
+            .unwrap_or(DEFAULT_POOL_COIN);
+        if pool_coin == Amount::ZERO {
+            bail!("GRAFFITI_POOL_COIN must be positive");
+        }
+        let fan_out = (pool_size > 0).then_some(FanOutConfig {
+            size: pool_size,
+            coin: pool_coin,
+        });
+
+        let economy = EconomyConfig {
+            target: env::var("GRAFFITI_ECONOMY_TARGET")
+                .ok()
+                .map(|target| target.parse())
+                .transpose()
+                .map_err(|e| anyhow!("invalid GRAFFITI_ECONOMY_TARGET: {e}"))?
+                .unwrap_or(DEFAULT_ECONOMY_TARGET),
+            max_fee_rate: env::var("GRAFFITI_ECONOMY_MAX_FEE_RATE")
+                .ok()
+                .map(|rate| rate.parse())
+                .transpose()
+                .map_err(|e| anyhow!("invalid GRAFFITI_ECONOMY_MAX_FEE_RATE: {e}"))?
+                .and_then(FeeRate::from_sat_per_vb)
+                .unwrap_or(DEFAULT_ECONOMY_MAX_FEE_RATE),
+            max_delay: env::var("GRAFFITI_ECONOMY_MAX_DELAY")
+                .ok()
+                .map(|secs| secs.parse().map(Duration::from_secs))
+                .transpose()
+                .map_err(|e| anyhow!("invalid GRAFFITI_ECONOMY_MAX_DELAY: {e}"))?
+                .unwrap_or(DEFAULT_ECONOMY_MAX_DELAY),
+        };
+        if economy.target == 0 {
+            bail!("GRAFFITI_ECONOMY_TARGET must be at least one block");
+        }
+
+        let dedup = env::var("GRAFFITI_DEDUP")
+            .ok()
+            .map(|dedup| dedup.parse())
+            .transpose()
+            .map_err(|e| anyhow!("invalid GRAFFITI_DEDUP, expected true or false: {e}"))?
+            .unwrap_or(false);
+
+        let admin_token = env::var("GRAFFITI_ADMIN_TOKEN").ok();
+        if admin_token.as_deref().is_some_and(|token| token.len() < 16) {
+            bail!("GRAFFITI_ADMIN_TOKEN must be at least 16 characters");
+        }
+
+        Ok(Self {
+            external_descriptor,
+            internal_descriptor,
+            legacy_descriptors,
+            database_url,
+            attestation_key,
+            bitcoind_rpc,
+            index_start_height,
+            confirmation_thresholds,
+            backend,
+            admin_token,
+            runway_thresholds,
+            cold_address,
+            hot_ceiling,
+            consolidation_max_fee_rate,
+            fan_out,
+            economy,
+            dedup,
+        })
+    }
+
+    /// Checks that the descriptors are usable before the server starts taking requests.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error describing the first problem found
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let external = DescriptorKind::of(&self.external_descriptor)?;
+        let internal = DescriptorKind::of(&self.internal_descriptor)?;
+        if external != internal {
+            bail!("external ({external:?}) and internal ({internal:?}) descriptors must be the same type");
+        }
+        if self.external_descriptor == self.internal_descriptor {
+            bail!("external and internal descriptors must differ");
+        }
+        // Catches keys for the wrong network as well.
+        Wallet::new(
+            &self.external_descriptor,
+            &self.internal_descriptor,
+            NETWORK,
+        )?;
+
+        if let Some((legacy_external, legacy_internal)) = &self.legacy_descriptors {
+            if DescriptorKind::of(legacy_external)? != DescriptorKind::Wpkh
+                || DescriptorKind::of(legacy_internal)? != DescriptorKind::Wpkh
+            {
+                bail!("legacy descriptors must be wpkh");
+            }
+            if legacy_external == &self.external_descriptor {
+                bail!("legacy wallet is the same as the current wallet");
+            }
+            Wallet::new(legacy_external, legacy_internal, NETWORK)?;
+        }
+
+        if self.fan_out.is_some() && external.threshold().is_some() {
+            bail!("the coin pool is split on the spot, it needs a wallet that signs on its own");
+        }
+
+        Ok(())
+    }
+
+    /// Kind of the funding wallet, assumes [`Config::validate`] has passed.
+    pub fn descriptor_kind(&self) -> DescriptorKind {
+        DescriptorKind::of(&self.external_descriptor).expect("validated on startup")
+    }
+
+    /// # Errors
+    ///
+    /// Will return an error if the descriptors are invalid
+    pub fn wallet(&self) -> anyhow::Result<Wallet> {
+        Ok(Wallet::new(
+            &self.external_descriptor,
+            &self.internal_descriptor,
+            NETWORK,
+        )?)
+    }
+
+    /// The wallet as saved in `changeset`.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the changeset was saved for other descriptors
+    pub fn wallet_from(&self, changeset: ChangeSet) -> anyhow::Result<Wallet> {
+        Ok(Wallet::new_or_load(
+            &self.external_descriptor,
+            &self.internal_descriptor,
+            Some(changeset),
+            NETWORK,
+        )?)
+    }
+
+    /// # Errors
+    ///
+    /// Will return an error if the descriptors are invalid
+    pub fn legacy_wallet(&self) -> anyhow::Result<Option<Wallet>> {
+        self.legacy_descriptors
+            .as_ref()
+            .map(|(external, internal)| Ok(Wallet::new(external, internal, NETWORK)?))
+            .transpose()
+    }
+}
@@ -0,0 +1,1491 @@
+use bdk_electrum::bdk_chain::Append;
+use bdk_wallet::bitcoin::consensus::{deserialize, serialize};
+use bdk_wallet::bitcoin::hex::DisplayHex;
+use bdk_wallet::bitcoin::key::XOnlyPublicKey;
+use bdk_wallet::bitcoin::secp256k1::schnorr;
+use bdk_wallet::bitcoin::{Amount, BlockHash, Psbt, Transaction, Txid};
+use bdk_wallet::wallet::ChangeSet;
+use sqlx::postgres::{PgPoolOptions, PgRow};
+use sqlx::{PgConnection, PgPool, Postgres, QueryBuilder, Row};
+use std::str::FromStr;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::attestation::Attestation;
+use crate::batch::EconomyWrite;
+use crate::events::{EventDetail, EventKind, WriteEvent};
+use crate::indexer::IndexedOpReturn;
+use crate::multisig::PendingWrite;
+use crate::queue::{FinishedWrite, PreparedWrite, QueuedWrite};
+use crate::schedule::{ScheduleStatus, ScheduledWrite};
+use crate::stream::{GraffitiRecord, StreamEvent};
+use crate::tracker::{Confirmation, TrackedWrite, WriteKind, WriteRecord};
+use crate::util::NETWORK;
+use crate::webhooks::{Delivery, DueDelivery, Outcome, Webhook};
+
+const MAX_CONNECTIONS: u32 = 5;
+
+/// Connects to Postgres and brings the schema up to date.
+///
+/// # Errors
+///
+/// Will return errors if the database can't be reached or a migration fails
+pub async fn connect(database_url: &str) -> anyhow::Result<PgPool> {
+    let pool = PgPoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .connect(database_url)
+        .await?;
+    sqlx::migrate!().run(&pool).await?;
+    info!("connected to database");
+    Ok(pool)
+}
+
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn save_attestation(
+    db: &PgPool,
+    txid: Txid,
+    payload: &[u8],
+    attestation: &Attestation,
+    on_chain: bool,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO attestations (txid, network, payload, timestamp, pubkey, signature, on_chain)
+         VALUES ($1, $2, $3, $4, $5, $6, $7)
+         ON CONFLICT (txid) DO NOTHING",
+    )
+    .bind(txid.to_string())
+    .bind(attestation.network.to_string())
+    .bind(payload)
+    .bind(i64::try_from(attestation.timestamp)?)
+    .bind(attestation.pubkey.serialize().to_lower_hex_string())
+    .bind(attestation.signature.as_ref().to_vec())
+    .bind(on_chain)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Returns the attestation stored for `txid` together with the payload it signs.
+///
+/// # Errors
+///
+/// Will return errors if the query fails or the stored row is corrupt
+pub async fn get_attestation(
+    db: &PgPool,
+    txid: Txid,
+) -> anyhow::Result<Option<(Vec<u8>, Attestation)>> {
+    let row = sqlx::query(
+        "SELECT payload, timestamp, pubkey, signature FROM attestations
+         WHERE txid = $1 AND network = $2",
+    )
+    .bind(txid.to_string())
+    .bind(NETWORK.to_string())
+    .fetch_optional(db)
+    .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let signature: Vec<u8> = row.try_get("signature")?;
+    let attestation = Attestation {
+        network: NETWORK,
+        timestamp: u64::try_from(row.try_get::<i64, _>("timestamp")?)?,
+        pubkey: XOnlyPublicKey::from_str(row.try_get("pubkey")?)?,
+        signature: schnorr::Signature::from_slice(&signature)?,
+    };
+    Ok(Some((row.try_get("payload")?, attestation)))
+}
+
+/// The highest block in the `OP_RETURN` index.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn last_indexed_block(db: &PgPool) -> anyhow::Result<Option<(u32, BlockHash)>> {
+    let row = sqlx::query("SELECT height, hash FROM indexed_blocks ORDER BY height DESC LIMIT 1")
+        .fetch_optional(db)
+        .await?;
+    row.map(|row| {
+        let height = u32::try_from(row.try_get::<i32, _>("height")?)?;
+        let hash = BlockHash::from_str(row.try_get("hash")?)?;
+        Ok((height, hash))
+    })
+    .transpose()
+}
+
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn indexed_block_hash(db: &PgPool, height: u32) -> anyhow::Result<Option<BlockHash>> {
+    let hash: Option<String> =
+        sqlx::query_scalar("SELECT hash FROM indexed_blocks WHERE height = $1")
+            .bind(i32::try_from(height)?)
+            .fetch_optional(db)
+            .await?;
+    Ok(hash.map(|hash| BlockHash::from_str(&hash)).transpose()?)
+}
+
+/// Drops the blocks from `height` up, along with their `OP_RETURN` outputs, and records a
+/// `reorged` stream event for each output.
+///
+/// # Errors
+///
+/// Will return errors if the delete fails
+pub async fn remove_indexed_blocks_from(
+    db: &PgPool,
+    height: u32,
+) -> anyhow::Result<Vec<GraffitiRecord>> {
+    let height = i32::try_from(height)?;
+    let mut tx = db.begin().await?;
+    let removed: Vec<IndexedOpReturn> = sqlx::query(
+        "SELECT txid, vout, height, payload, script FROM op_returns
+         WHERE height >= $1 ORDER BY height, txid, vout",
+    )
+    .bind(height)
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(indexed_op_return)
+    .collect::<anyhow::Result<_>>()?;
+    let mut records = Vec::with_capacity(removed.len());
+    for op_return in removed {
+        records.push(insert_stream_event(&mut *tx, StreamEvent::Reorged, op_return).await?);
+    }
+    sqlx::query("DELETE FROM indexed_blocks WHERE height >= $1")
+        .bind(height)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(records)
+}
+
+/// Records a block and its `OP_RETURN` outputs in one transaction, along with a `confirmed`
+/// stream event for each output.
+///
+/// # Errors
+///
+/// Will return errors if an insert fails
+pub async fn insert_indexed_block(
+    db: &PgPool,
+    height: u32,
+    hash: BlockHash,
+    op_returns: &[IndexedOpReturn],
+) -> anyhow::Result<Vec<GraffitiRecord>> {
+    let block_height = height;
+    let height = i32::try_from(height)?;
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT INTO indexed_blocks (height, hash) VALUES ($1, $2)")
+        .bind(height)
+        .bind(hash.to_string())
+        .execute(&mut *tx)
+        .await?;
+    let mut records = Vec::with_capacity(op_returns.len());
+    for op_return in op_returns {
+        sqlx::query(
+            "INSERT INTO op_returns (txid, vout, height, payload, script)
+             VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (txid, vout) DO UPDATE SET height = EXCLUDED.height",
+        )
+        .bind(op_return.txid.to_string())
+        .bind(i32::try_from(op_return.vout)?)
+        .bind(height)
+        .bind(&op_return.payload)
+        .bind(&op_return.script)
+        .execute(&mut *tx)
+        .await?;
+        let confirmed = IndexedOpReturn {
+            height: Some(block_height),
+            ..op_return.clone()
+        };
+        records.push(insert_stream_event(&mut *tx, StreamEvent::Confirmed, confirmed).await?);
+    }
+    tx.commit().await?;
+    Ok(records)
+}
+
+async fn insert_stream_event(
+    conn: &mut PgConnection,
+    event: StreamEvent,
+    op_return: IndexedOpReturn,
+) -> anyhow::Result<GraffitiRecord> {
+    let cursor: i64 = sqlx::query_scalar(
+        "INSERT INTO op_return_events (event, txid, vout, height, payload, script)
+         VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+    )
+    .bind(event.as_str())
+    .bind(op_return.txid.to_string())
+    .bind(i32::try_from(op_return.vout)?)
+    .bind(op_return.height.map(i32::try_from).transpose()?)
+    .bind(&op_return.payload)
+    .bind(&op_return.script)
+    .fetch_one(conn)
+    .await?;
+    Ok(GraffitiRecord {
+        cursor,
+        event,
+        op_return,
+    })
+}
+
+/// Records a `mempool` stream event for each output, in one transaction.
+///
+/// # Errors
+///
+/// Will return errors if an insert fails
+pub async fn insert_mempool_events(
+    db: &PgPool,
+    op_returns: Vec<IndexedOpReturn>,
+) -> anyhow::Result<Vec<GraffitiRecord>> {
+    let mut tx = db.begin().await?;
+    let mut records = Vec::with_capacity(op_returns.len());
+    for op_return in op_returns {
+        records.push(insert_stream_event(&mut *tx, StreamEvent::Mempool, op_return).await?);
+    }
+    tx.commit().await?;
+    Ok(records)
+}
+
+/// Stream records after `cursor` whose payload starts with `prefix`, oldest first.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn graffiti_records_after(
+    db: &PgPool,
+    cursor: i64,
+    prefix: &[u8],
+    limit: u32,
+) -> anyhow::Result<Vec<GraffitiRecord>> {
+    sqlx::query(
+        "SELECT id, event, txid, vout, height, payload, script FROM op_return_events
+         WHERE id > $1 AND substring(payload FROM 1 FOR $2) = $3
+         ORDER BY id LIMIT $4",
+    )
+    .bind(cursor)
+    .bind(i32::try_from(prefix.len())?)
+    .bind(prefix)
+    .bind(i64::from(limit))
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(|row| {
+        Ok(GraffitiRecord {
+            cursor: row.try_get("id")?,
+            event: StreamEvent::from_str(row.try_get("event")?)?,
+            op_return: indexed_op_return(row)?,
+        })
+    })
+    .collect()
+}
+
+/// Filters for searching the `OP_RETURN` index, all optional.
+#[derive(Clone, Debug, Default)]
+pub struct OpReturnFilter {
+    pub prefix: Option<Vec<u8>>,
+    pub from_height: Option<u32>,
+    pub to_height: Option<u32>,
+    pub txid: Option<Txid>,
+    pub limit: u32,
+}
+
+/// Smallest byte string greater than everything starting with `prefix`, `None` if there is
+/// no such string because the prefix is all `0xff`.
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
+fn indexed_op_return(row: &PgRow) -> anyhow::Result<IndexedOpReturn> {
+    Ok(IndexedOpReturn {
+        txid: Txid::from_str(row.try_get("txid")?)?,
+        vout: u32::try_from(row.try_get::<i32, _>("vout")?)?,
+        height: optional_u32(row, "height")?,
+        payload: row.try_get("payload")?,
+        script: row.try_get("script")?,
+    })
+}
+
+/// Searches the `OP_RETURN` index, oldest first.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn find_op_returns(
+    db: &PgPool,
+    filter: &OpReturnFilter,
+) -> anyhow::Result<Vec<IndexedOpReturn>> {
+    let mut query: QueryBuilder<Postgres> =
+        QueryBuilder::new("SELECT txid, vout, height, payload, script FROM op_returns WHERE TRUE");
+
+    if let Some(prefix) = &filter.prefix {
+        // The indexed head narrows the search, the substring makes it exact for long prefixes.
+        let head = &prefix[..prefix.len().min(16)];
+        query.push(" AND prefix >= ").push_bind(head.to_vec());
+        if let Some(upper) = prefix_upper_bound(head) {
+            query.push(" AND prefix < ").push_bind(upper);
+        }
+        query
+            .push(" AND substring(payload FROM 1 FOR ")
+            .push_bind(i32::try_from(prefix.len())?)
+            .push(") = ")
+            .push_bind(prefix.clone());
+    }
+    if let Some(from_height) = filter.from_height {
+        query
+            .push(" AND height >= ")
+            .push_bind(i32::try_from(from_height)?);
+    }
+    if let Some(to_height) = filter.to_height {
+        query
+            .push(" AND height <= ")
+            .push_bind(i32::try_from(to_height)?);
+    }
+    if let Some(txid) = filter.txid {
+        query.push(" AND txid = ").push_bind(txid.to_string());
+    }
+    query
+        .push(" ORDER BY height, txid, vout LIMIT ")
+        .push_bind(i64::from(filter.limit));
+
+    query
+        .build()
+        .fetch_all(db)
+        .await?
+        .iter()
+        .map(indexed_op_return)
+        .collect()
+}
+
+/// Adds a broadcast transaction to the write history.
+///
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn insert_write(
+    db: &PgPool,
+    tx: &Transaction,
+    kind: WriteKind,
+    payload: &[u8],
+    fee: Amount,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO writes (txid, kind, payload, raw_tx, fee) VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (txid) DO NOTHING",
+    )
+    .bind(tx.compute_txid().to_string())
+    .bind(kind.as_str())
+    .bind(payload)
+    .bind(serialize(tx))
+    .bind(i64::try_from(fee.to_sat())?)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Writes that are unconfirmed or confirmed at or above `min_height`, oldest first so a
+/// commit is always rebroadcast before its reveal.
+///
+/// # Errors
+///
+/// Will return errors if the query fails or a stored transaction is corrupt
+pub async fn tracked_writes(db: &PgPool, min_height: u32) -> anyhow::Result<Vec<TrackedWrite>> {
+    let rows = sqlx::query(
+        "SELECT w.txid, w.raw_tx, c.height, c.block_hash, c.notified_confirmations,
+                (SELECT r.height FROM write_confirmations r
+                 WHERE r.txid = w.txid AND r.reorged_at IS NOT NULL
+                 ORDER BY r.reorged_at DESC LIMIT 1) AS reorged_height
+         FROM writes w
+         LEFT JOIN write_confirmations c ON c.txid = w.txid AND c.reorged_at IS NULL
+         WHERE w.status IN ('unconfirmed', 'confirmed') AND (c.height IS NULL OR c.height >= $1)
+         ORDER BY w.created_at",
+    )
+    .bind(i32::try_from(min_height)?)
+    .fetch_all(db)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            let raw_tx: Vec<u8> = row.try_get("raw_tx")?;
+            let height: Option<i32> = row.try_get("height")?;
+            let block_hash: Option<String> = row.try_get("block_hash")?;
+            let confirmation = match (height, block_hash) {
+                (Some(height), Some(hash)) => {
+                    Some((u32::try_from(height)?, BlockHash::from_str(&hash)?))
+                }
+                _ => None,
+            };
+            Ok(TrackedWrite {
+                txid: Txid::from_str(row.try_get("txid")?)?,
+                tx: deserialize(&raw_tx)?,
+                confirmation,
+                reorged_height: optional_u32(row, "reorged_height")?,
+                notified_confirmations: optional_u32(row, "notified_confirmations")?.unwrap_or(1),
+            })
+        })
+        .collect()
+}
+
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn confirm_write(
+    db: &PgPool,
+    txid: Txid,
+    height: u32,
+    block_hash: BlockHash,
+) -> anyhow::Result<()> {
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT INTO write_confirmations (txid, height, block_hash) VALUES ($1, $2, $3)")
+        .bind(txid.to_string())
+        .bind(i32::try_from(height)?)
+        .bind(block_hash.to_string())
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE writes SET status = 'confirmed' WHERE txid = $1")
+        .bind(txid.to_string())
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn set_notified_confirmations(
+    db: &PgPool,
+    txid: Txid,
+    confirmations: u32,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE write_confirmations SET notified_confirmations = $2
+         WHERE txid = $1 AND reorged_at IS NULL",
+    )
+    .bind(txid.to_string())
+    .bind(i32::try_from(confirmations)?)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Sets a final status, `replaced` or `failed`, after which the tracker stops following the
+/// write.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn set_write_status(db: &PgPool, txid: Txid, status: &str) -> anyhow::Result<()> {
+    sqlx::query("UPDATE writes SET status = $2 WHERE txid = $1")
+        .bind(txid.to_string())
+        .bind(status)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Marks the standing confirmation of a write as reorged out.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn unconfirm_write(db: &PgPool, txid: Txid) -> anyhow::Result<()> {
+    let mut tx = db.begin().await?;
+    sqlx::query(
+        "UPDATE write_confirmations SET reorged_at = now()
+         WHERE txid = $1 AND reorged_at IS NULL",
+    )
+    .bind(txid.to_string())
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE writes SET status = 'unconfirmed' WHERE txid = $1")
+        .bind(txid.to_string())
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+fn confirmation(row: &PgRow) -> anyhow::Result<Confirmation> {
+    Ok(Confirmation {
+        height: u32::try_from(row.try_get::<i32, _>("height")?)?,
+        block_hash: BlockHash::from_str(row.try_get("block_hash")?)?,
+        confirmed_at: row.try_get("confirmed_at")?,
+        reorged_at: row.try_get("reorged_at")?,
+    })
+}
+
+/// Loads the confirmations of the write in `row`.
+async fn write_record(db: &PgPool, row: &PgRow) -> anyhow::Result<WriteRecord> {
+    let txid = Txid::from_str(row.try_get("txid")?)?;
+    let confirmations = sqlx::query(
+        "SELECT height, block_hash, confirmed_at, reorged_at FROM write_confirmations
+         WHERE txid = $1 ORDER BY id",
+    )
+    .bind(txid.to_string())
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(confirmation)
+    .collect::<anyhow::Result<_>>()?;
+
+    Ok(WriteRecord {
+        txid,
+        kind: WriteKind::from_str(row.try_get("kind")?)?,
+        payload: row.try_get("payload")?,
+        status: row.try_get("status")?,
+        fee: row
+            .try_get::<Option<i64>, _>("fee")?
+            .map(u64::try_from)
+            .transpose()?
+            .map(Amount::from_sat),
+        created_at: row.try_get("created_at")?,
+        confirmations,
+    })
+}
+
+/// Recent writes, newest first, each with its confirmations.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn list_writes(db: &PgPool, limit: u32) -> anyhow::Result<Vec<WriteRecord>> {
+    let rows = sqlx::query(
+        "SELECT txid, kind, payload, status, fee, created_at FROM writes
+         ORDER BY created_at DESC LIMIT $1",
+    )
+    .bind(i64::from(limit))
+    .fetch_all(db)
+    .await?;
+
+    let mut writes = Vec::with_capacity(rows.len());
+    for row in &rows {
+        writes.push(write_record(db, row).await?);
+    }
+    Ok(writes)
+}
+
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn get_write(db: &PgPool, txid: Txid) -> anyhow::Result<Option<WriteRecord>> {
+    let row = sqlx::query(
+        "SELECT txid, kind, payload, status, fee, created_at FROM writes WHERE txid = $1",
+    )
+    .bind(txid.to_string())
+    .fetch_optional(db)
+    .await?;
+    match row {
+        Some(row) => Ok(Some(write_record(db, &row).await?)),
+        None => Ok(None),
+    }
+}
+
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn insert_event(
+    db: &PgPool,
+    txid: Option<Txid>,
+    kind: EventKind,
+    detail: EventDetail,
+) -> anyhow::Result<WriteEvent> {
+    let row = sqlx::query(
+        "INSERT INTO write_events
+             (txid, kind, height, previous_height, confirmations, replaced_by, reason,
+              balance, runway, threshold)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+         RETURNING id, created_at",
+    )
+    .bind(txid.map(|txid| txid.to_string()))
+    .bind(kind.as_str())
+    .bind(detail.height.map(i32::try_from).transpose()?)
+    .bind(detail.previous_height.map(i32::try_from).transpose()?)
+    .bind(detail.confirmations.map(i32::try_from).transpose()?)
+    .bind(detail.replaced_by.map(|txid| txid.to_string()))
+    .bind(&detail.reason)
+    .bind(detail.balance.map(i64::try_from).transpose()?)
+    .bind(detail.runway.map(i64::try_from).transpose()?)
+    .bind(detail.threshold.map(i64::try_from).transpose()?)
+    .fetch_one(db)
+    .await?;
+
+    Ok(WriteEvent {
+        id: row.try_get("id")?,
+        txid,
+        kind,
+        detail,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+fn optional_u32(row: &PgRow, column: &str) -> anyhow::Result<Option<u32>> {
+    let value: Option<i32> = row.try_get(column)?;
+    Ok(value.map(u32::try_from).transpose()?)
+}
+
+fn optional_u64(row: &PgRow, column: &str) -> anyhow::Result<Option<u64>> {
+    let value: Option<i64> = row.try_get(column)?;
+    Ok(value.map(u64::try_from).transpose()?)
+}
+
+const EVENT_COLUMNS: &str = "id, txid, kind, height, previous_height, confirmations, \
+     replaced_by, reason, balance, runway, threshold, created_at";
+
+fn write_event(row: &PgRow) -> anyhow::Result<WriteEvent> {
+    let replaced_by: Option<String> = row.try_get("replaced_by")?;
+    let txid: Option<&str> = row.try_get("txid")?;
+    Ok(WriteEvent {
+        id: row.try_get("id")?,
+        txid: txid.map(Txid::from_str).transpose()?,
+        kind: EventKind::from_str(row.try_get("kind")?)?,
+        detail: EventDetail {
+            height: optional_u32(row, "height")?,
+            previous_height: optional_u32(row, "previous_height")?,
+            confirmations: optional_u32(row, "confirmations")?,
+            replaced_by: replaced_by.map(|txid| Txid::from_str(&txid)).transpose()?,
+            reason: row.try_get("reason")?,
+            balance: optional_u64(row, "balance")?,
+            runway: optional_u64(row, "runway")?,
+            threshold: optional_u64(row, "threshold")?,
+        },
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+/// Events of one write, oldest first.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn write_events(db: &PgPool, txid: Txid) -> anyhow::Result<Vec<WriteEvent>> {
+    sqlx::query(&format!(
+        "SELECT {EVENT_COLUMNS} FROM write_events WHERE txid = $1 ORDER BY id"
+    ))
+    .bind(txid.to_string())
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(write_event)
+    .collect()
+}
+
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn create_webhook(db: &PgPool, webhook: &Webhook) -> anyhow::Result<()> {
+    let events: Vec<&str> = webhook.events.iter().map(|kind| kind.as_str()).collect();
+    sqlx::query(
+        "INSERT INTO webhooks (id, url, secret, events, created_at) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(webhook.id)
+    .bind(&webhook.url)
+    .bind(&webhook.secret)
+    .bind(events)
+    .bind(webhook.created_at)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+fn webhook(row: &PgRow) -> anyhow::Result<Webhook> {
+    let events: Vec<String> = row.try_get("events")?;
+    Ok(Webhook {
+        id: row.try_get("id")?,
+        url: row.try_get("url")?,
+        secret: row.try_get("secret")?,
+        events: events
+            .iter()
+            .map(|kind| EventKind::from_str(kind))
+            .collect::<anyhow::Result<_>>()?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn list_webhooks(db: &PgPool) -> anyhow::Result<Vec<Webhook>> {
+    sqlx::query("SELECT id, url, secret, events, created_at FROM webhooks ORDER BY created_at")
+        .fetch_all(db)
+        .await?
+        .iter()
+        .map(webhook)
+        .collect()
+}
+
+/// Removes a webhook and its delivery log, returning whether it existed.
+///
+/// # Errors
+///
+/// Will return errors if the delete fails
+pub async fn delete_webhook(db: &PgPool, id: Uuid) -> anyhow::Result<bool> {
+    let result = sqlx::query("DELETE FROM webhooks WHERE id = $1")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Queues a delivery of `event` to every webhook subscribed to its kind.
+///
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn queue_deliveries(db: &PgPool, event: &WriteEvent) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO webhook_deliveries (webhook_id, event_id)
+         SELECT id, $1 FROM webhooks WHERE cardinality(events) = 0 OR $2 = ANY(events)",
+    )
+    .bind(event.id)
+    .bind(event.kind.as_str())
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// How long a claimed delivery is hidden from other dispatchers while it is being sent.
+const DELIVERY_LEASE_SECS: i32 = 60;
+
+/// Claims pending deliveries that are due, oldest first. Claimed deliveries aren't handed out
+/// again until their lease runs out, so several instances can dispatch from the same table.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn claim_due_deliveries(db: &PgPool, limit: u32) -> anyhow::Result<Vec<DueDelivery>> {
+    let rows = sqlx::query(
+        "UPDATE webhook_deliveries d
+         SET next_attempt_at = now() + make_interval(secs => $2)
+         FROM webhooks h, write_events e
+         WHERE d.id IN (
+             SELECT id FROM webhook_deliveries
+             WHERE status = 'pending' AND next_attempt_at <= now()
+             ORDER BY next_attempt_at LIMIT $1
+             FOR UPDATE SKIP LOCKED
+         ) AND h.id = d.webhook_id AND e.id = d.event_id
+         RETURNING d.id AS delivery_id, d.webhook_id, d.attempts, h.url, h.secret,
+                   e.id, e.txid, e.kind, e.height, e.previous_height, e.confirmations,
+                   e.replaced_by, e.reason, e.balance, e.runway, e.threshold, e.created_at",
+    )
+    .bind(i64::from(limit))
+    .bind(DELIVERY_LEASE_SECS)
+    .fetch_all(db)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(DueDelivery {
+                id: row.try_get("delivery_id")?,
+                webhook_id: row.try_get("webhook_id")?,
+                attempts: u32::try_from(row.try_get::<i32, _>("attempts")?)?,
+                url: row.try_get("url")?,
+                secret: row.try_get("secret")?,
+                event: write_event(row)?,
+            })
+        })
+        .collect()
+}
+
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn record_delivery(db: &PgPool, id: i64, outcome: &Outcome) -> anyhow::Result<()> {
+    let query = match outcome {
+        Outcome::Delivered { status } => sqlx::query(
+            "UPDATE webhook_deliveries
+             SET status = 'delivered', attempts = attempts + 1, response_status = $2,
+                 last_error = NULL, delivered_at = now()
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(i32::from(*status)),
+        Outcome::Retry {
+            status,
+            error,
+            retry_in,
+        } => sqlx::query(
+            "UPDATE webhook_deliveries
+             SET attempts = attempts + 1, response_status = $2, last_error = $3,
+                 next_attempt_at = now() + make_interval(secs => $4)
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(status.map(i32::from))
+        .bind(error)
+        .bind(retry_in.as_secs_f64()),
+        Outcome::Failed { status, error } => sqlx::query(
+            "UPDATE webhook_deliveries
+             SET status = 'failed', attempts = attempts + 1, response_status = $2,
+                 last_error = $3
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(status.map(i32::from))
+        .bind(error),
+    };
+    query.execute(db).await?;
+    Ok(())
+}
+
+fn delivery(row: &PgRow) -> anyhow::Result<Delivery> {
+    let response_status: Option<i32> = row.try_get("response_status")?;
+    Ok(Delivery {
+        id: row.try_get("id")?,
+        webhook_id: row.try_get("webhook_id")?,
+        event_id: row.try_get("event_id")?,
+        status: row.try_get("status")?,
+        attempts: u32::try_from(row.try_get::<i32, _>("attempts")?)?,
+        next_attempt_at: row.try_get("next_attempt_at")?,
+        response_status: response_status.map(u16::try_from).transpose()?,
+        last_error: row.try_get("last_error")?,
+        created_at: row.try_get("created_at")?,
+        delivered_at: row.try_get("delivered_at")?,
+    })
+}
+
+/// The delivery log of a webhook, newest first.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn list_deliveries(
+    db: &PgPool,
+    webhook_id: Uuid,
+    status: Option<&str>,
+    limit: u32,
+) -> anyhow::Result<Vec<Delivery>> {
+    sqlx::query(
+        "SELECT id, webhook_id, event_id, status, attempts, next_attempt_at, response_status,
+                last_error, created_at, delivered_at
+         FROM webhook_deliveries
+         WHERE webhook_id = $1 AND ($2::TEXT IS NULL OR status = $2)
+         ORDER BY id DESC LIMIT $3",
+    )
+    .bind(webhook_id)
+    .bind(status)
+    .bind(i64::from(limit))
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(delivery)
+    .collect()
+}
+
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn insert_economy_write(db: &PgPool, write: &EconomyWrite) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO economy_writes (id, data, payload, deadline, queued_at)
+         VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(write.id)
+    .bind(&write.data)
+    .bind(&write.payload)
+    .bind(write.deadline)
+    .bind(write.queued_at)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+const ECONOMY_COLUMNS: &str =
+    "id, data, payload, deadline, queued_at, txid, leaf_index, confirmed_at";
+
+fn economy_write(row: &PgRow) -> anyhow::Result<EconomyWrite> {
+    let txid: Option<&str> = row.try_get("txid")?;
+    let leaf_index: Option<i32> = row.try_get("leaf_index")?;
+    let batch = match (txid, leaf_index) {
+        (Some(txid), Some(index)) => Some((Txid::from_str(txid)?, u32::try_from(index)?)),
+        _ => None,
+    };
+    Ok(EconomyWrite {
+        id: row.try_get("id")?,
+        data: row.try_get("data")?,
+        payload: row.try_get("payload")?,
+        deadline: row.try_get("deadline")?,
+        queued_at: row.try_get("queued_at")?,
+        batch,
+        confirmed_at: row.try_get("confirmed_at")?,
+    })
+}
+
+/// Economy writes that wait for a batch, oldest first.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn queued_economy_writes(db: &PgPool, limit: u32) -> anyhow::Result<Vec<EconomyWrite>> {
+    sqlx::query(&format!(
+        "SELECT {ECONOMY_COLUMNS} FROM economy_writes WHERE txid IS NULL
+         ORDER BY queued_at, id LIMIT $1"
+    ))
+    .bind(i64::from(limit))
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(economy_write)
+    .collect()
+}
+
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn get_economy_write(db: &PgPool, id: Uuid) -> anyhow::Result<Option<EconomyWrite>> {
+    sqlx::query(&format!(
+        "SELECT {ECONOMY_COLUMNS} FROM economy_writes WHERE id = $1"
+    ))
+    .bind(id)
+    .fetch_optional(db)
+    .await?
+    .as_ref()
+    .map(economy_write)
+    .transpose()
+}
+
+/// 1-based position of a queued economy write among those waiting.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn economy_position(db: &PgPool, write: &EconomyWrite) -> anyhow::Result<u64> {
+    let ahead: i64 = sqlx::query_scalar(
+        "SELECT count(*) FROM economy_writes WHERE txid IS NULL AND (queued_at, id) < ($1, $2)",
+    )
+    .bind(write.queued_at)
+    .bind(write.id)
+    .fetch_one(db)
+    .await?;
+    Ok(u64::try_from(ahead)? + 1)
+}
+
+/// Economy writes anchored by `txid`, in the order of their leaves.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn batch_writes(db: &PgPool, txid: Txid) -> anyhow::Result<Vec<EconomyWrite>> {
+    sqlx::query(&format!(
+        "SELECT {ECONOMY_COLUMNS} FROM economy_writes WHERE txid = $1 ORDER BY leaf_index"
+    ))
+    .bind(txid.to_string())
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(economy_write)
+    .collect()
+}
+
+/// Anchors of economy batches that haven't confirmed yet, the latest last.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn open_batches(db: &PgPool) -> anyhow::Result<Vec<Txid>> {
+    let txids: Vec<String> = sqlx::query_scalar(
+        "SELECT txid FROM economy_writes WHERE txid IS NOT NULL AND confirmed_at IS NULL
+         GROUP BY txid ORDER BY max(batched_at)",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(txids
+        .iter()
+        .map(|txid| Txid::from_str(txid))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Makes the batch of `txid` final, its anchor confirmed.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn confirm_batch(db: &PgPool, txid: Txid) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE economy_writes SET confirmed_at = now() WHERE txid = $1 AND confirmed_at IS NULL",
+    )
+    .bind(txid.to_string())
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Queues the writes of the batch of `txid` again, its anchor is gone.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn requeue_batch(db: &PgPool, txid: Txid) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE economy_writes SET txid = NULL, leaf_index = NULL, batched_at = NULL
+         WHERE txid = $1 AND confirmed_at IS NULL",
+    )
+    .bind(txid.to_string())
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Records that `ids` were anchored by `txid`, each at its position in `ids`.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn mark_batched(db: &PgPool, txid: Txid, ids: &[Uuid]) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE economy_writes e SET txid = $1, leaf_index = (b.ord - 1)::integer,
+                batched_at = now()
+         FROM unnest($2::uuid[]) WITH ORDINALITY AS b(id, ord)
+         WHERE e.id = b.id",
+    )
+    .bind(txid.to_string())
+    .bind(ids)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn insert_scheduled_write(db: &PgPool, write: &ScheduledWrite) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO scheduled_writes
+             (id, data, payload, attest, not_before_height, not_before_time, status, created_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(write.id)
+    .bind(&write.data)
+    .bind(&write.payload)
+    .bind(write.attest)
+    .bind(write.not_before_height.map(i32::try_from).transpose()?)
+    .bind(write.not_before_time)
+    .bind(write.status.as_str())
+    .bind(write.created_at)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+const SCHEDULED_COLUMNS: &str = "id, data, payload, attest, not_before_height, not_before_time, \
+     status, txid, error, created_at, sent_at";
+
+fn scheduled_write(row: &PgRow) -> anyhow::Result<ScheduledWrite> {
+    let not_before_height: Option<i32> = row.try_get("not_before_height")?;
+    let txid: Option<&str> = row.try_get("txid")?;
+    Ok(ScheduledWrite {
+        id: row.try_get("id")?,
+        data: row.try_get("data")?,
+        payload: row.try_get("payload")?,
+        attest: row.try_get("attest")?,
+        not_before_height: not_before_height.map(u32::try_from).transpose()?,
+        not_before_time: row.try_get("not_before_time")?,
+        status: ScheduleStatus::from_str(row.try_get("status")?)?,
+        txid: txid.map(Txid::from_str).transpose()?,
+        error: row.try_get("error")?,
+        created_at: row.try_get("created_at")?,
+        sent_at: row.try_get("sent_at")?,
+    })
+}
+
+/// Writes still waiting for their height or time, oldest first.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn waiting_scheduled_writes(db: &PgPool) -> anyhow::Result<Vec<ScheduledWrite>> {
+    sqlx::query(&format!(
+        "SELECT {SCHEDULED_COLUMNS} FROM scheduled_writes WHERE status = 'scheduled'
+         ORDER BY created_at"
+    ))
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(scheduled_write)
+    .collect()
+}
+
+/// Scheduled writes, newest first, only those with `status` if given.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn list_scheduled_writes(
+    db: &PgPool,
+    status: Option<ScheduleStatus>,
+    limit: u32,
+) -> anyhow::Result<Vec<ScheduledWrite>> {
+    sqlx::query(&format!(
+        "SELECT {SCHEDULED_COLUMNS} FROM scheduled_writes
+         WHERE $1::text IS NULL OR status = $1
+         ORDER BY created_at DESC LIMIT $2"
+    ))
+    .bind(status.map(ScheduleStatus::as_str))
+    .bind(i64::from(limit))
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(scheduled_write)
+    .collect()
+}
+
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn get_scheduled_write(db: &PgPool, id: Uuid) -> anyhow::Result<Option<ScheduledWrite>> {
+    sqlx::query(&format!(
+        "SELECT {SCHEDULED_COLUMNS} FROM scheduled_writes WHERE id = $1"
+    ))
+    .bind(id)
+    .fetch_optional(db)
+    .await?
+    .as_ref()
+    .map(scheduled_write)
+    .transpose()
+}
+
+/// Marks a scheduled write as being sent, returning false if it is no longer scheduled.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn claim_scheduled_write(db: &PgPool, id: Uuid) -> anyhow::Result<bool> {
+    let result = sqlx::query(
+        "UPDATE scheduled_writes SET status = 'sending' WHERE id = $1 AND status = 'scheduled'",
+    )
+    .bind(id)
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Records how sending a claimed write went. Back to `scheduled` keeps it for the next round.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn finish_scheduled_write(
+    db: &PgPool,
+    id: Uuid,
+    status: ScheduleStatus,
+    txid: Option<Txid>,
+    error: Option<&str>,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE scheduled_writes SET status = $2, txid = $3, error = $4,
+                sent_at = CASE WHEN $3::text IS NULL THEN NULL ELSE now() END
+         WHERE id = $1",
+    )
+    .bind(id)
+    .bind(status.as_str())
+    .bind(txid.map(|txid| txid.to_string()))
+    .bind(error)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Cancels a write that hasn't been sent, returning false if it isn't scheduled any more.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn cancel_scheduled_write(db: &PgPool, id: Uuid) -> anyhow::Result<bool> {
+    let result = sqlx::query(
+        "UPDATE scheduled_writes SET status = 'cancelled' WHERE id = $1 AND status = 'scheduled'",
+    )
+    .bind(id)
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Fails writes a previous run claimed but never finished.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn fail_interrupted_schedules(db: &PgPool) -> anyhow::Result<()> {
+    let result = sqlx::query(
+        "UPDATE scheduled_writes SET status = 'failed',
+                error = 'interrupted while sending, check the wallet before scheduling it again'
+         WHERE status = 'sending'",
+    )
+    .execute(db)
+    .await?;
+    if result.rows_affected() > 0 {
+        info!(
+            "failed {} scheduled writes interrupted by a restart",
+            result.rows_affected()
+        );
+    }
+    Ok(())
+}
+
+/// The latest `OP_RETURN` write of `payload` that wasn't replaced by another transaction or
+/// rejected by the node, if any. Writes that left the mempool are rebroadcast and still count.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn find_duplicate_write(db: &PgPool, payload: &[u8]) -> anyhow::Result<Option<Txid>> {
+    let txid: Option<String> = sqlx::query_scalar(
+        "SELECT txid FROM writes
+         WHERE kind = 'op_return' AND sha256(payload) = sha256($1) AND payload = $1
+           AND status NOT IN ('replaced', 'failed')
+         ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(payload)
+    .fetch_optional(db)
+    .await?;
+    Ok(txid.as_deref().map(Txid::from_str).transpose()?)
+}
+
+/// Stores a write waiting for cosigners, or the signatures it gained since.
+///
+/// # Errors
+///
+/// Will return errors if the upsert fails
+pub async fn save_pending_write(db: &PgPool, write: &PendingWrite) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO pending_writes (id, data, psbt, threshold, created_at)
+         VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (id) DO UPDATE SET psbt = EXCLUDED.psbt",
+    )
+    .bind(write.id)
+    .bind(&write.data)
+    .bind(write.psbt.to_string())
+    .bind(i32::try_from(write.threshold)?)
+    .bind(write.created_at)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Forgets a pending write once it has been broadcast.
+///
+/// # Errors
+///
+/// Will return errors if the delete fails
+pub async fn delete_pending_write(db: &PgPool, id: Uuid) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM pending_writes WHERE id = $1")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Writes still waiting for cosigners, to pick up where the previous run left off.
+///
+/// # Errors
+///
+/// Will return errors if the query fails or a stored PSBT is corrupt
+pub async fn pending_writes(db: &PgPool) -> anyhow::Result<Vec<PendingWrite>> {
+    sqlx::query("SELECT id, data, psbt, threshold, created_at FROM pending_writes")
+        .fetch_all(db)
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(PendingWrite {
+                id: row.try_get("id")?,
+                data: row.try_get("data")?,
+                psbt: Psbt::from_str(row.try_get("psbt")?)?,
+                threshold: usize::try_from(row.try_get::<i32, _>("threshold")?)?,
+                created_at: row.try_get("created_at")?,
+            })
+        })
+        .collect()
+}
+
+/// Stores a write held back by the mempool limits, so it is still queued after a restart.
+///
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn insert_queued_write(db: &PgPool, queued: &QueuedWrite) -> anyhow::Result<()> {
+    let write = &queued.write;
+    sqlx::query(
+        "INSERT INTO queued_writes (id, data, payload, attestation, attestation_pubkey, on_chain,
+                                    envelope, fee_target, replaceable, reason, queued_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+    )
+    .bind(queued.id)
+    .bind(&write.data)
+    .bind(&write.payload)
+    .bind(write.attestation.as_ref().map(|a| a.to_bytes().to_vec()))
+    .bind(write.attestation.as_ref().map(|a| a.pubkey.to_string()))
+    .bind(write.on_chain)
+    .bind(write.envelope)
+    .bind(i32::try_from(write.fee_target)?)
+    .bind(write.replaceable)
+    .bind(&queued.reason)
+    .bind(queued.queued_at)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Records why a queued write is still held back.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn block_queued_write(db: &PgPool, id: Uuid, reason: &str) -> anyhow::Result<()> {
+    sqlx::query("UPDATE queued_writes SET reason = $2 WHERE id = $1")
+        .bind(id)
+        .bind(reason)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Takes a write off the queue with what became of it.
+///
+/// # Errors
+///
+/// Will return errors if the update fails
+pub async fn finish_queued_write(
+    db: &PgPool,
+    id: Uuid,
+    status: &str,
+    result: &serde_json::Value,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE queued_writes SET status = $2, result = $3, finished_at = now() WHERE id = $1",
+    )
+    .bind(id)
+    .bind(status)
+    .bind(result)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Writes still queued, in the order they came in, to pick up where the previous run left off.
+///
+/// # Errors
+///
+/// Will return errors if the query fails or a stored attestation is corrupt
+pub async fn waiting_queued_writes(db: &PgPool) -> anyhow::Result<Vec<QueuedWrite>> {
+    sqlx::query(
+        "SELECT id, data, payload, attestation, attestation_pubkey, on_chain, envelope,
+                fee_target, replaceable, reason, queued_at
+         FROM queued_writes WHERE status = 'queued' ORDER BY queued_at",
+    )
+    .fetch_all(db)
+    .await?
+    .iter()
+    .map(|row| {
+        let attestation: Option<Vec<u8>> = row.try_get("attestation")?;
+        let pubkey: Option<&str> = row.try_get("attestation_pubkey")?;
+        let attestation = match (attestation, pubkey) {
+            (Some(bytes), Some(pubkey)) => Some(Attestation::from_bytes(
+                &bytes,
+                XOnlyPublicKey::from_str(pubkey)?,
+            )?),
+            _ => None,
+        };
+        Ok(QueuedWrite {
+            id: row.try_get("id")?,
+            write: PreparedWrite {
+                data: row.try_get("data")?,
+                payload: row.try_get("payload")?,
+                attestation,
+                on_chain: row.try_get("on_chain")?,
+                fee_target: usize::try_from(row.try_get::<i32, _>("fee_target")?)?,
+                replaceable: row.try_get("replaceable")?,
+                envelope: row.try_get("envelope")?,
+            },
+            reason: row.try_get("reason")?,
+            queued_at: row.try_get("queued_at")?,
+        })
+    })
+    .collect()
+}
+
+/// A write that left the queue, `None` while it is still queued or if there is none.
+///
+/// # Errors
+///
+/// Will return errors if the query fails
+pub async fn finished_queued_write(db: &PgPool, id: Uuid) -> anyhow::Result<Option<FinishedWrite>> {
+    sqlx::query(
+        "SELECT status, result, finished_at FROM queued_writes
+         WHERE id = $1 AND status <> 'queued'",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await?
+    .map(|row| {
+        Ok(FinishedWrite {
+            status: row.try_get("status")?,
+            result: row.try_get("result")?,
+            finished_at: row.try_get("finished_at")?,
+        })
+    })
+    .transpose()
+}
+
+/// Saves what changed in the wallet since the last call.
+///
+/// # Errors
+///
+/// Will return errors if the insert fails
+pub async fn save_wallet_changeset(db: &PgPool, changeset: &ChangeSet) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO wallet_changesets (changeset) VALUES ($1)")
+        .bind(serde_json::to_value(changeset)?)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Every saved wallet change merged into one, which then replaces them. `None` if nothing was
+/// saved yet.
+///
+/// # Errors
+///
+/// Will return errors if the query fails or a saved changeset is corrupt
+pub async fn wallet_checkpoint(db: &PgPool) -> anyhow::Result<Option<ChangeSet>> {
+    let mut tx = db.begin().await?;
+    let rows: Vec<serde_json::Value> =
+        sqlx::query_scalar("SELECT changeset FROM wallet_changesets ORDER BY id")
+            .fetch_all(&mut *tx)
+            .await?;
+    let Some(first) = rows.first() else {
+        return Ok(None);
+    };
+    let mut merged: ChangeSet = serde_json::from_value(first.clone())?;
+    for row in &rows[1..] {
+        merged.append(serde_json::from_value(row.clone())?);
+    }
+    if rows.len() > 1 {
+        sqlx::query("DELETE FROM wallet_changesets")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO wallet_changesets (changeset) VALUES ($1)")
+            .bind(serde_json::to_value(&merged)?)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(Some(merged))
+}
+
+/// Forgets the saved wallet, the next start scans from the birthday.
+///
+/// # Errors
+///
+/// Will return errors if the delete fails
+pub async fn clear_wallet_changesets(db: &PgPool) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM wallet_changesets")
+        .execute(db)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,522 @@
+use bdk_electrum::bdk_chain::tx_graph::TxGraph;
+use bdk_electrum::electrum_client::{
+    Client as ElectrumClient, ConfigBuilder, ElectrumApi, Error as ElectrumError, Socks5Config,
+};
+use bdk_electrum::BdkElectrumClient;
+use bdk_wallet::bitcoin::Transaction;
+use serde_json::json;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{info, warn};
+
+use crate::config::ElectrumServerConfig;
+
+/// How often every server is asked for its tip.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+/// A server further behind the best tip seen than this is only used when no other is up.
+const MAX_LAG: u32 = 2;
+/// Consecutive failures that open the circuit breaker of a server.
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long an open breaker keeps a server out before it is probed again.
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+/// Servers a broadcast is sent to at once.
+const BROADCAST_FANOUT: usize = 3;
+
+/// What the pool knows about a server from its last requests and health checks.
+#[derive(Debug, Default)]
+struct Health {
+    tip: Option<u32>,
+    latency: Option<Duration>,
+    /// Failed requests since the last one that went through.
+    failures: u32,
+    /// Set while the circuit breaker is open.
+    open_until: Option<Instant>,
+    last_error: Option<String>,
+}
+
+/// How usable a server is, best first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum State {
+    Healthy,
+    /// Behind the best tip or failed its last request.
+    Degraded,
+    /// The circuit breaker is open.
+    Open,
+}
+
+impl State {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Healthy => "healthy",
+            Self::Degraded => "degraded",
+            Self::Open => "open",
+        }
+    }
+}
+
+/// Transactions fetched through any connection, so no connection downloads them again.
+type TxCache = Arc<std::sync::Mutex<TxGraph>>;
+
+/// A client of one Electrum connection.
+pub type Client = BdkElectrumClient<ElectrumClient>;
+
+/// A slot that holds a connection once one was needed.
+type Slot = Arc<Mutex<Option<Client>>>;
+
+/// A connection checked out of a server, given back when dropped. It owns its slot, so it can
+/// be moved to a blocking thread.
+pub struct Connection {
+    slot: OwnedMutexGuard<Option<Client>>,
+}
+
+impl Deref for Connection {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        self.slot.as_ref().expect("connected on checkout")
+    }
+}
+
+impl Connection {
+    /// Closes the socket, the next checkout of this slot connects again.
+    fn reset(mut self) {
+        *self.slot = None;
+    }
+}
+
+/// One server of the pool. Requests are spread over several connections, each opened on first
+/// use and reopened after a transport error. The client blocks on its socket, so connecting and
+/// every request run on the blocking thread pool.
+pub struct ElectrumServer {
+    pub config: ElectrumServerConfig,
+    connections: Vec<Slot>,
+    /// Holds the subscriptions of the watcher, out of the rotation so requests never read its
+    /// notifications.
+    subscriber: Slot,
+    /// Holds the header subscription of the health checks, also out of the rotation.
+    probe: Slot,
+    /// Where the next checkout starts looking for a free connection.
+    next: AtomicUsize,
+    tx_cache: TxCache,
+    health: std::sync::Mutex<Health>,
+}
+
+impl ElectrumServer {
+    fn new(config: ElectrumServerConfig, tx_cache: TxCache) -> Self {
+        let connections = (0..config.connections.max(1))
+            .map(|_| Slot::default())
+            .collect();
+        Self {
+            config,
+            connections,
+            subscriber: Slot::default(),
+            probe: Slot::default(),
+            next: AtomicUsize::new(0),
+            tx_cache,
+            health: std::sync::Mutex::default(),
+        }
+    }
+
+    fn connect(&self) -> Result<Client, ElectrumError> {
+        let mut builder = ConfigBuilder::new()
+            .validate_domain(self.config.validate_domain)
+            .timeout(self.config.timeout);
+        if let Some(proxy) = &self.config.socks5 {
+            builder = builder.socks5(Some(Socks5Config::new(proxy)));
+        }
+        let client = ElectrumClient::from_config(&self.config.url, builder.build())?;
+        info!("connected to electrum server {}", self.config.url);
+        Ok(BdkElectrumClient::new(client))
+    }
+
+    /// Connects `slot` if it isn't yet. Blocks on the socket.
+    fn checkout(
+        &self,
+        mut slot: OwnedMutexGuard<Option<Client>>,
+    ) -> Result<Connection, ElectrumError> {
+        if slot.is_none() {
+            *slot = Some(self.connect()?);
+        }
+        let connection = Connection { slot };
+        let cache = self
+            .tx_cache
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        connection.populate_tx_cache(&*cache);
+        drop(cache);
+        Ok(connection)
+    }
+
+    /// A free connection slot, or the next one to free up when all are busy.
+    async fn free_slot(&self) -> OwnedMutexGuard<Option<Client>> {
+        let count = self.connections.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let free = (0..count).find_map(|i| {
+            self.connections[(start + i) % count]
+                .clone()
+                .try_lock_owned()
+                .ok()
+        });
+        match free {
+            Some(slot) => slot,
+            None => self.connections[start % count].clone().lock_owned().await,
+        }
+    }
+
+    /// The connection holding the subscriptions of the watcher. A `fresh` one starts without
+    /// subscriptions.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the server can't be reached
+    pub async fn subscriber(self: &Arc<Self>, fresh: bool) -> Result<Connection, ElectrumError> {
+        let mut slot = self.subscriber.clone().lock_owned().await;
+        if fresh {
+            *slot = None;
+        }
+        let server = self.clone();
+        tokio::task::spawn_blocking(move || server.checkout(slot))
+            .await
+            .unwrap_or_else(|e| Err(ElectrumError::Message(e.to_string())))
+    }
+
+    /// Runs `op` on a connection of `slot` off the async runtime and records how it went. A
+    /// connection that failed with a transport error is closed, so the next request on it
+    /// reconnects.
+    async fn run_on<T, F>(
+        self: &Arc<Self>,
+        slot: OwnedMutexGuard<Option<Client>>,
+        op: F,
+    ) -> Result<T, ElectrumError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Client) -> Result<T, ElectrumError> + Send + 'static,
+    {
+        let server = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = match server.checkout(slot) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    server.record_failure(&e);
+                    return Err(e);
+                }
+            };
+            let result = op(&connection);
+            match &result {
+                Ok(_) | Err(ElectrumError::Protocol(_)) => server.record_success(None, None),
+                Err(e) => {
+                    server.record_failure(e);
+                    connection.reset();
+                }
+            }
+            result
+        })
+        .await
+        .unwrap_or_else(|e| Err(ElectrumError::Message(e.to_string())))
+    }
+
+    /// Runs `op` on one of the request connections, see [`Self::run_on`].
+    async fn run<T, F>(self: &Arc<Self>, op: F) -> Result<T, ElectrumError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Client) -> Result<T, ElectrumError> + Send + 'static,
+    {
+        let slot = self.free_slot().await;
+        self.run_on(slot, op).await
+    }
+
+    fn health(&self) -> std::sync::MutexGuard<'_, Health> {
+        self.health
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn state(&self, best_tip: Option<u32>) -> State {
+        let health = self.health();
+        if health
+            .open_until
+            .is_some_and(|open_until| open_until > Instant::now())
+        {
+            return State::Open;
+        }
+        let lagging = match (health.tip, best_tip) {
+            (Some(tip), Some(best)) => tip + MAX_LAG < best,
+            _ => false,
+        };
+        if lagging || health.failures > 0 {
+            State::Degraded
+        } else {
+            State::Healthy
+        }
+    }
+
+    pub(crate) fn record_success(&self, tip: Option<u32>, latency: Option<Duration>) {
+        let mut health = self.health();
+        if health.failures >= FAILURE_THRESHOLD {
+            info!("electrum server {} is back", self.config.url);
+        }
+        health.failures = 0;
+        health.open_until = None;
+        if tip.is_some() {
+            health.tip = tip;
+        }
+        if latency.is_some() {
+            health.latency = latency;
+        }
+    }
+
+    /// Counts a failed request, opening the breaker after [`FAILURE_THRESHOLD`] in a row. A
+    /// probe that fails after the cooldown opens it again right away.
+    pub fn record_failure(&self, error: &impl fmt::Display) {
+        let mut health = self.health();
+        health.failures += 1;
+        health.last_error = Some(error.to_string());
+        if health.failures >= FAILURE_THRESHOLD {
+            if health.open_until.is_none() {
+                warn!(
+                    "electrum server {} failed {} times, taking it out for {:?}",
+                    self.config.url, health.failures, BREAKER_COOLDOWN
+                );
+            }
+            health.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+        }
+    }
+
+    /// Asks the server for its tip. Servers with an open breaker are left alone until the
+    /// cooldown is over.
+    async fn check(self: &Arc<Self>) {
+        if self.state(None) == State::Open {
+            return;
+        }
+        let started = Instant::now();
+        let slot = self.probe.clone().lock_owned().await;
+        let result = self
+            .run_on(slot, |client| {
+                let header = client.inner.block_headers_subscribe()?;
+                // Only checks use the probe connection, each one reads the notifications
+                // pushed since the last so they don't pile up.
+                while client.inner.block_headers_pop()?.is_some() {}
+                Ok(header)
+            })
+            .await;
+        match result {
+            Ok(header) => {
+                let tip = u32::try_from(header.height).ok();
+                self.record_success(tip, Some(started.elapsed()));
+            }
+            Err(e) => warn!("health check of {} failed: {}", self.config.url, e),
+        }
+    }
+
+    fn to_json(&self, best_tip: Option<u32>, primary: bool) -> serde_json::Value {
+        let state = self.state(best_tip);
+        let health = self.health();
+        json!({
+            "url": self.config.url,
+            "primary": primary,
+            "state": state.as_str(),
+            "tip": health.tip,
+            "lag": health.tip.zip(best_tip).map(|(tip, best)| best.saturating_sub(tip)),
+            "latency_ms": health.latency.map(|latency| latency.as_millis()),
+            "connections": self.connections.len(),
+            "failures": health.failures,
+            "last_error": health.last_error,
+        })
+    }
+}
+
+/// The configured Electrum servers. Requests go to the best server and fail over to the next
+/// one when it times out or drops the connection, broadcasts go to several at once.
+pub struct ElectrumPool {
+    servers: Vec<Arc<ElectrumServer>>,
+    tx_cache: TxCache,
+}
+
+impl ElectrumPool {
+    /// # Panics
+    ///
+    /// Panics without servers, the configuration always has at least one
+    pub fn new(configs: &[ElectrumServerConfig]) -> Self {
+        assert!(!configs.is_empty(), "an electrum pool needs a server");
+        let tx_cache = TxCache::default();
+        Self {
+            servers: configs
+                .iter()
+                .cloned()
+                .map(|config| Arc::new(ElectrumServer::new(config, tx_cache.clone())))
+                .collect(),
+            tx_cache,
+        }
+    }
+
+    /// Shares transactions a sync found with every connection.
+    pub fn cache_txs(&self, txs: impl IntoIterator<Item = Arc<Transaction>>) {
+        let mut cache = self
+            .tx_cache
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        for tx in txs {
+            let _ = cache.insert_tx(Transaction::clone(&tx));
+        }
+    }
+
+    pub fn server_count(&self) -> usize {
+        self.servers.len()
+    }
+
+    fn best_tip(&self) -> Option<u32> {
+        self.servers
+            .iter()
+            .filter_map(|server| server.health().tip)
+            .max()
+    }
+
+    /// Servers in the order they should be tried: healthy ones first, in configured order,
+    /// then the degraded ones and last those whose breaker is open.
+    fn ranked(&self) -> Vec<Arc<ElectrumServer>> {
+        let best_tip = self.best_tip();
+        let mut servers = self.servers.clone();
+        servers.sort_by_key(|server| server.state(best_tip));
+        servers
+    }
+
+    /// The server requests go to first.
+    pub fn primary(&self) -> Arc<ElectrumServer> {
+        self.ranked().swap_remove(0)
+    }
+
+    /// Whether any server is usable without failing over to a degraded one.
+    pub fn is_healthy(&self) -> bool {
+        let best_tip = self.best_tip();
+        self.servers
+            .iter()
+            .any(|server| server.state(best_tip) == State::Healthy)
+    }
+
+    /// Runs the request `make_op` builds on the best server, failing over to the next on
+    /// transport errors with a new one. An error the server answered with is returned as is,
+    /// another server would most likely give the same answer.
+    ///
+    /// # Errors
+    ///
+    /// Will return the error of the last server tried
+    pub async fn call<T, F>(&self, mut make_op: impl FnMut() -> F) -> Result<T, ElectrumError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Client) -> Result<T, ElectrumError> + Send + 'static,
+    {
+        let mut last_error = None;
+        for server in self.ranked() {
+            match server.run(make_op()).await {
+                Err(e) if !matches!(e, ElectrumError::Protocol(_)) => {
+                    warn!("electrum server {} failed: {}", server.config.url, e);
+                    last_error = Some(e);
+                }
+                result => return result,
+            }
+        }
+        Err(last_error.expect("the pool has at least one server"))
+    }
+
+    /// Runs `op` on the primary server only, for requests that can't be repeated.
+    ///
+    /// # Errors
+    ///
+    /// Will return errors if the server can't be reached
+    pub async fn call_primary<T, F>(&self, op: F) -> Result<T, ElectrumError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Client) -> Result<T, ElectrumError> + Send + 'static,
+    {
+        self.primary().run(op).await
+    }
+
+    /// Sends `tx` to the [`BROADCAST_FANOUT`] best servers at once. It went out if any of them
+    /// took it, and was rejected only if none did and at least one refused it.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`ElectrumError::Protocol`] if the transaction was rejected
+    pub async fn broadcast(&self, tx: &Transaction) -> Result<(), ElectrumError> {
+        let mut broadcasts = JoinSet::new();
+        for server in self.ranked().into_iter().take(BROADCAST_FANOUT) {
+            let tx = tx.clone();
+            broadcasts.spawn(async move {
+                let result = server
+                    .run(move |client| client.transaction_broadcast(&tx).map(|_| ()))
+                    .await;
+                (server, result)
+            });
+        }
+
+        let mut results = Vec::new();
+        while let Some(joined) = broadcasts.join_next().await {
+            let (server, result) = joined.map_err(|e| ElectrumError::Message(e.to_string()))?;
+            match &result {
+                Ok(()) => {}
+                Err(ElectrumError::Protocol(reason)) => {
+                    info!("{} refused the broadcast: {}", server.config.url, reason);
+                }
+                Err(e) => warn!("broadcast through {} failed: {}", server.config.url, e),
+            }
+            results.push(result);
+        }
+        broadcast_outcome(results)
+    }
+
+    /// Checks the tip of every server in the background, which is how lagging servers are
+    /// noticed and open breakers get closed again.
+    pub fn spawn_health_checks(&self) -> JoinHandle<()> {
+        let servers = self.servers.clone();
+        tokio::spawn(async move {
+            loop {
+                for server in &servers {
+                    server.check().await;
+                }
+                tokio::time::sleep(HEALTH_INTERVAL).await;
+            }
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let best_tip = self.best_tip();
+        let primary = self.primary();
+        let servers: Vec<_> = self
+            .servers
+            .iter()
+            .map(|server| server.to_json(best_tip, Arc::ptr_eq(server, &primary)))
+            .collect();
+        json!({
+            "tip": best_tip,
+            "servers": servers,
+        })
+    }
+}
+
+/// What the servers a broadcast went to answered, taken together: accepted if any server took
+/// it, rejected if none did and one refused it, otherwise the last transport error.
+pub(crate) fn broadcast_outcome(
+    results: impl IntoIterator<Item = Result<(), ElectrumError>>,
+) -> Result<(), ElectrumError> {
+    let mut rejection = None;
+    let mut last_error = None;
+    for result in results {
+        match result {
+            Ok(()) => return Ok(()),
+            Err(ElectrumError::Protocol(reason)) => rejection = Some(reason),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match (rejection, last_error) {
+        (Some(reason), _) => Err(ElectrumError::Protocol(reason)),
+        (None, Some(e)) => Err(e),
+        (None, None) => Err(ElectrumError::Message(
+            "no server to broadcast to".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, ensure};
+use bdk_wallet::bitcoin::hashes::{sha256, Hash, HashEngine};
+use bdk_wallet::bitcoin::secp256k1::ecdh::SharedSecret;
+use bdk_wallet::bitcoin::secp256k1::{rand, PublicKey, Secp256k1, SecretKey};
+use bdk_wallet::bitcoin::Transaction;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::attestation::MAX_OP_RETURN_DATA;
+use crate::envelope::{extract_envelope, PROTOCOL_TAG};
+use crate::util::op_return_payload;
+
+/// Version byte following the protocol tag in encrypted payloads.
+const ENCRYPTED_VERSION: u8 = 1;
+/// Tag, version and ephemeral public key.
+const HEADER_LEN: usize = PROTOCOL_TAG.len() + 1 + 33;
+const MAC_LEN: usize = 16;
+/// Bytes an encrypted payload adds to the plaintext.
+pub const ENCRYPTION_OVERHEAD: usize = HEADER_LEN + MAC_LEN;
+/// Largest plaintext whose encrypted payload still fits in a standard `OP_RETURN`.
+pub const MAX_ENCRYPTED_OP_RETURN: usize = MAX_OP_RETURN_DATA - ENCRYPTION_OVERHEAD;
+
+/// Every payload is encrypted under a fresh key, so a fixed nonce is never reused.
+const NONCE: [u8; 12] = [0; 12];
+
+/// Derives the symmetric key from the ECDH secret, bound to both public keys.
+fn derive_key(shared: &SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let mut engine = sha256::Hash::engine();
+    engine.input(PROTOCOL_TAG);
+    engine.input(&shared.secret_bytes());
+    engine.input(&ephemeral.serialize());
+    engine.input(&recipient.serialize());
+    Key::clone_from_slice(sha256::Hash::from_engine(engine).as_byte_array())
+}
+
+/// Encrypts `plaintext` so only the holder of the secret key behind `recipient` can read it.
+///
+/// The payload is laid out as
+///
+/// ```text
+/// "graffiti" 0x01 <ephemeral public key, 33 bytes> <ChaCha20-Poly1305 ciphertext and tag>
+/// ```
+///
+/// # Errors
+///
+/// Will return an error if encryption fails
+pub fn encrypt(recipient: &PublicKey, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let secp = Secp256k1::new();
+    let (ephemeral_secret, ephemeral) = secp.generate_keypair(&mut rand::thread_rng());
+    let shared = SharedSecret::new(recipient, &ephemeral_secret);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(&shared, &ephemeral, recipient));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&NONCE), plaintext)
+        .map_err(|_| anyhow!("failed to encrypt payload"))?;
+
+    let mut payload = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    payload.extend_from_slice(PROTOCOL_TAG);
+    payload.push(ENCRYPTED_VERSION);
+    payload.extend_from_slice(&ephemeral.serialize());
+    payload.extend_from_slice(&ciphertext);
+    Ok(payload)
+}
+
+/// Whether `payload` is framed as an encrypted payload.
+pub fn is_encrypted(payload: &[u8]) -> bool {
+    payload.len() >= ENCRYPTION_OVERHEAD
+        && payload.starts_with(PROTOCOL_TAG)
+        && payload[PROTOCOL_TAG.len()] == ENCRYPTED_VERSION
+}
+
+/// Recovers the plaintext of a payload produced by [`encrypt`].
+///
+/// # Errors
+///
+/// Will return an error if the payload is malformed or was encrypted to another key
+pub fn decrypt(secret: &SecretKey, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    ensure!(is_encrypted(payload), "payload is not encrypted");
+    let ephemeral = PublicKey::from_slice(&payload[PROTOCOL_TAG.len() + 1..HEADER_LEN])?;
+
+    let secp = Secp256k1::new();
+    let recipient = secret.public_key(&secp);
+    let shared = SharedSecret::new(&ephemeral, secret);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(&shared, &ephemeral, &recipient));
+    cipher
+        .decrypt(Nonce::from_slice(&NONCE), &payload[HEADER_LEN..])
+        .map_err(|_| anyhow!("payload could not be decrypted with this key"))
+}
+
+/// Finds the encrypted payload in `tx`, either in an `OP_RETURN` output or in an envelope,
+/// and decrypts it.
+///
+/// # Errors
+///
+/// Will return an error if the transaction has no encrypted payload for this key
+pub fn decrypt_tx(secret: &SecretKey, tx: &Transaction) -> anyhow::Result<Vec<u8>> {
+    let mut payloads = extract_envelope(tx)
+        .into_iter()
+        .chain(
+            tx.output
+                .iter()
+                .filter_map(|output| op_return_payload(&output.script_pubkey)),
+        )
+        .filter(|payload| is_encrypted(payload))
+        .peekable();
+    ensure!(
+        payloads.peek().is_some(),
+        "transaction {} has no encrypted payload",
+        tx.compute_txid()
+    );
+
+    payloads
+        .find_map(|payload| decrypt(secret, &payload).ok())
+        .ok_or_else(|| anyhow!("payload could not be decrypted with this key"))
+}
@@ -0,0 +1,401 @@
+use anyhow::{anyhow, ensure};
+use bdk_wallet::bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_ENDIF, OP_IF};
+use bdk_wallet::bitcoin::blockdata::opcodes::OP_FALSE;
+use bdk_wallet::bitcoin::hashes::{sha256, Hash, HashEngine};
+use bdk_wallet::bitcoin::key::{Keypair, Secp256k1, XOnlyPublicKey};
+use bdk_wallet::bitcoin::psbt::{self, Psbt};
+use bdk_wallet::bitcoin::script::{Instruction, PushBytes, PushBytesBuf};
+use bdk_wallet::bitcoin::secp256k1::{Message, SecretKey};
+use bdk_wallet::bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bdk_wallet::bitcoin::taproot::{
+    self, ControlBlock, LeafVersion, TapLeafHash, TaprootBuilder, TaprootSpendInfo,
+};
+use bdk_wallet::bitcoin::{
+    Amount, FeeRate, OutPoint, Script, ScriptBuf, Transaction, TxOut, Weight, Witness,
+};
+use bdk_wallet::{KeychainKind, SignOptions, Wallet};
+
+use crate::config::{compact_size_len, DescriptorKind};
+
+/// Marks our envelopes in the leaf script and in the `OP_RETURN` of the reveal transaction.
+pub const PROTOCOL_TAG: &[u8] = b"graffiti";
+/// Largest payload accepted in envelope mode, keeps the reveal below the standard weight limit.
+pub const MAX_ENVELOPE_SIZE: usize = 380_000;
+/// Script pushes are limited to 520 bytes, larger payloads are split over several pushes.
+const MAX_PUSH_SIZE: usize = 520;
+/// Left over in the reveal on top of its fee, returned to the wallet as change.
+const REVEAL_CHANGE: Amount = Amount::from_sat(1_000);
+/// Heaviest transaction Bitcoin Core relays.
+const MAX_STANDARD_TX_WEIGHT: Weight = Weight::from_wu(400_000);
+
+/// A single leaf taproot output whose script path spend reveals the payload:
+///
+/// ```text
+/// <key> OP_CHECKSIG OP_FALSE OP_IF "graffiti" <chunk> <chunk> ... OP_ENDIF
+/// ```
+///
+/// The key is derived from the wallet and the payload, see [`envelope_keypair`], so the
+/// envelope output can always be spent again from the wallet alone.
+pub struct Envelope {
+    keypair: Keypair,
+    script: ScriptBuf,
+    spend_info: TaprootSpendInfo,
+}
+
+impl Envelope {
+    /// # Errors
+    ///
+    /// Will return an error if the payload is too large
+    pub fn new(data: &[u8], keypair: Keypair) -> anyhow::Result<Self> {
+        ensure!(
+            data.len() <= MAX_ENVELOPE_SIZE,
+            "payload is {} bytes, envelopes hold at most {MAX_ENVELOPE_SIZE}",
+            data.len()
+        );
+        let secp = Secp256k1::new();
+        let (internal_key, _) = XOnlyPublicKey::from_keypair(&keypair);
+
+        let mut builder = ScriptBuf::builder()
+            .push_x_only_key(&internal_key)
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(<&PushBytes>::try_from(PROTOCOL_TAG)?);
+        for chunk in data.chunks(MAX_PUSH_SIZE) {
+            builder = builder.push_slice(<&PushBytes>::try_from(chunk)?);
+        }
+        let script = builder.push_opcode(OP_ENDIF).into_script();
+
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, script.clone())?
+            .finalize(&secp, internal_key)
+            .map_err(|_| anyhow!("could not finalize the envelope tap tree"))?;
+
+        Ok(Self {
+            keypair,
+            script,
+            spend_info,
+        })
+    }
+
+    /// Output script the commit transaction pays to.
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        ScriptBuf::new_p2tr_tweaked(self.spend_info.output_key())
+    }
+
+    fn control_block(&self) -> ControlBlock {
+        self.spend_info
+            .control_block(&(self.script.clone(), LeafVersion::TapScript))
+            .expect("the envelope script is the only leaf")
+    }
+
+    /// Script path witness: signature, leaf script and control block.
+    pub(crate) fn witness(&self, signature: &[u8]) -> Witness {
+        let mut witness = Witness::new();
+        witness.push(signature);
+        witness.push(self.script.as_bytes());
+        witness.push(self.control_block().serialize());
+        witness
+    }
+
+    pub fn satisfaction_weight(&self) -> Weight {
+        Weight::from_wu(self.witness(&[0; 64]).size() as u64)
+    }
+
+    /// Signs input `index` of the reveal through the script path and finalizes it.
+    fn sign_reveal(&self, psbt: &mut Psbt, index: usize) -> anyhow::Result<()> {
+        let prevouts = psbt
+            .inputs
+            .iter()
+            .map(|input| input.witness_utxo.clone())
+            .collect::<Option<Vec<TxOut>>>()
+            .ok_or_else(|| anyhow!("reveal input is missing its previous output"))?;
+
+        let leaf_hash = TapLeafHash::from_script(&self.script, LeafVersion::TapScript);
+        let sighash = SighashCache::new(&psbt.unsigned_tx).taproot_script_spend_signature_hash(
+            index,
+            &Prevouts::All(&prevouts),
+            leaf_hash,
+            TapSighashType::Default,
+        )?;
+
+        let secp = Secp256k1::new();
+        let message = Message::from_digest(sighash.to_byte_array());
+        let signature = taproot::Signature {
+            signature: secp.sign_schnorr(&message, &self.keypair),
+            sighash_type: TapSighashType::Default,
+        };
+
+        psbt.inputs[index].final_script_witness = Some(self.witness(&signature.to_vec()));
+        Ok(())
+    }
+
+    /// Checks a signed reveal before its commit goes out, a reveal the network refuses would
+    /// leave the commit output stranded. The reveal must spend `prevout`, the envelope output,
+    /// with a valid script path signature, pay at least the minimum relay fee and stay within
+    /// the standard weight.
+    pub(crate) fn verify_reveal(
+        &self,
+        prevout: &TxOut,
+        reveal: &Transaction,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            prevout.script_pubkey == self.script_pubkey(),
+            "the reveal must spend the envelope output"
+        );
+        let [input] = reveal.input.as_slice() else {
+            return Err(anyhow!("the reveal must only spend the envelope output"));
+        };
+        ensure!(
+            reveal.weight() <= MAX_STANDARD_TX_WEIGHT,
+            "the reveal weighs {}, more than the standard {MAX_STANDARD_TX_WEIGHT}",
+            reveal.weight()
+        );
+        let outputs: Amount = reveal.output.iter().map(|output| output.value).sum();
+        let fee = prevout
+            .value
+            .checked_sub(outputs)
+            .ok_or_else(|| anyhow!("the reveal spends more than the envelope holds"))?;
+        let min_fee = FeeRate::BROADCAST_MIN
+            .fee_wu(reveal.weight())
+            .ok_or_else(|| anyhow!("reveal fee overflows"))?;
+        ensure!(
+            fee >= min_fee,
+            "the reveal pays {fee}, below the minimum relay fee of {min_fee}"
+        );
+
+        ensure!(
+            input.witness.tapscript() == Some(self.script.as_script()),
+            "the reveal does not carry the envelope script"
+        );
+        let secp = Secp256k1::verification_only();
+        let control_block = input
+            .witness
+            .last()
+            .map(ControlBlock::decode)
+            .transpose()?
+            .ok_or_else(|| anyhow!("the reveal witness is empty"))?;
+        ensure!(
+            control_block.verify_taproot_commitment(
+                &secp,
+                self.spend_info.output_key().to_inner(),
+                &self.script
+            ),
+            "the control block does not commit to the envelope output"
+        );
+
+        let signature = input
+            .witness
+            .nth(0)
+            .map(taproot::Signature::from_slice)
+            .transpose()?
+            .ok_or_else(|| anyhow!("the reveal witness is empty"))?;
+        let leaf_hash = TapLeafHash::from_script(&self.script, LeafVersion::TapScript);
+        let sighash = SighashCache::new(reveal).taproot_script_spend_signature_hash(
+            0,
+            &Prevouts::All(&[prevout]),
+            leaf_hash,
+            signature.sighash_type,
+        )?;
+        let message = Message::from_digest(sighash.to_byte_array());
+        let (key, _) = XOnlyPublicKey::from_keypair(&self.keypair);
+        secp.verify_schnorr(&signature.signature, &message, &key)?;
+        Ok(())
+    }
+}
+
+/// The envelope key for `data`, a hash of the payload and the secret keys of the wallet's
+/// change keychain. The same payload always gets the same key, which lets a reveal be signed
+/// again from the wallet if the one built with the commit is lost.
+///
+/// # Errors
+///
+/// Will return an error if the wallet holds no secret keys
+pub fn envelope_keypair(wallet: &Wallet, data: &[u8]) -> anyhow::Result<Keypair> {
+    let secp = Secp256k1::new();
+    let mut secrets: Vec<String> = wallet
+        .get_signers(KeychainKind::Internal)
+        .as_key_map(&secp)
+        .values()
+        .map(ToString::to_string)
+        .collect();
+    ensure!(
+        !secrets.is_empty(),
+        "envelope writes need a wallet that can sign on its own"
+    );
+    secrets.sort();
+
+    let mut engine = sha256::Hash::engine();
+    engine.input(PROTOCOL_TAG);
+    for secret in &secrets {
+        engine.input(secret.as_bytes());
+    }
+    engine.input(sha256::Hash::hash(data).as_byte_array());
+    let secret = SecretKey::from_slice(sha256::Hash::from_engine(engine).as_byte_array())?;
+    Ok(Keypair::from_secret_key(&secp, &secret))
+}
+
+/// `OP_RETURN` payload of the reveal, our tag followed by the hash of the enveloped data.
+pub(crate) fn reveal_marker(data: &[u8]) -> PushBytesBuf {
+    let mut marker = PROTOCOL_TAG.to_vec();
+    marker.extend_from_slice(sha256::Hash::hash(data).as_byte_array());
+    PushBytesBuf::try_from(marker).expect("marker is 40 bytes")
+}
+
+/// Weight of the commit and reveal pair of a payload of `data_len` bytes, spending one coin of
+/// a `kind` wallet. The payload is witness data of the reveal and counts one weight unit per
+/// byte, not four as in an `OP_RETURN`.
+pub fn estimate_envelope_weight(kind: DescriptorKind, data_len: usize) -> Weight {
+    // version, locktime, input and output counts, plus the segwit marker and flag
+    let overhead = Weight::from_wu(10 * 4 + 2);
+    // the envelope output pays to a taproot key
+    let envelope_output = Weight::from_vb_unchecked(8 + 1 + 34);
+    let commit = overhead + kind.input_weight() + envelope_output + kind.output_weight();
+
+    let push = |len: u64| {
+        let prefix = match len {
+            0..=75 => 1,
+            76..=255 => 2,
+            _ => 3,
+        };
+        prefix + len
+    };
+    let full_chunks = (data_len / MAX_PUSH_SIZE) as u64 * push(MAX_PUSH_SIZE as u64);
+    let last_chunk = match (data_len % MAX_PUSH_SIZE) as u64 {
+        0 => 0,
+        len => push(len),
+    };
+    // <key> OP_CHECKSIG OP_FALSE OP_IF "graffiti" <chunk>... OP_ENDIF
+    let script = push(32) + 3 + push(PROTOCOL_TAG.len() as u64) + full_chunks + last_chunk + 1;
+    // item count, signature, leaf script and a control block without siblings
+    let witness = 1 + push(64) + compact_size_len(script) + script + push(33);
+    let marker = Weight::from_vb_unchecked(8 + 1 + 2 + 40);
+    let reveal = overhead + Weight::from_wu(41 * 4 + witness) + marker + kind.output_weight();
+
+    commit + reveal
+}
+
+/// Builds and signs the commit transaction, which funds the envelope output from the wallet
+/// without spending `reserved` coins, and the reveal transaction, which spends it and returns
+/// what is left to the wallet. The reveal is verified against the commit before either is
+/// returned.
+///
+/// # Errors
+///
+/// Will return errors if the wallet can't fund or sign the commit transaction, or the reveal
+/// fails verification
+pub fn build_commit_reveal(
+    wallet: &mut Wallet,
+    data: &[u8],
+    fee_rate: FeeRate,
+    reserved: &[OutPoint],
+    sign_options: SignOptions,
+) -> anyhow::Result<(Transaction, Transaction)> {
+    let envelope = Envelope::new(data, envelope_keypair(wallet, data)?)?;
+    let marker = reveal_marker(data);
+    let change = wallet.reveal_next_address(KeychainKind::Internal);
+
+    // version, locktime, counts, segwit marker and flag, then the envelope input and the
+    // marker and change outputs
+    let reveal_weight = Weight::from_wu(10 * 4 + 2)
+        + Weight::from_wu(41 * 4)
+        + envelope.satisfaction_weight()
+        + Weight::from_vb_unchecked(8 + 1 + 2 + marker.len() as u64)
+        + Weight::from_vb_unchecked(8 + 1 + change.script_pubkey().len() as u64);
+    let reveal_fee = fee_rate
+        .fee_wu(reveal_weight)
+        .ok_or_else(|| anyhow!("reveal fee overflows"))?;
+
+    let mut tx_builder = wallet.build_tx();
+    tx_builder
+        .add_recipient(envelope.script_pubkey(), reveal_fee + REVEAL_CHANGE)
+        .unspendable(reserved.to_vec())
+        .fee_rate(fee_rate);
+    let mut psbt = tx_builder.finish()?;
+    ensure!(
+        wallet.sign(&mut psbt, sign_options)?,
+        "envelope writes need a wallet that can sign on its own"
+    );
+    let commit = psbt.extract_tx()?;
+
+    let vout = commit
+        .output
+        .iter()
+        .position(|output| output.script_pubkey == envelope.script_pubkey())
+        .expect("commit pays to the envelope");
+    let outpoint = OutPoint::new(commit.compute_txid(), u32::try_from(vout)?);
+    let psbt_input = psbt::Input {
+        witness_utxo: Some(commit.output[vout].clone()),
+        non_witness_utxo: Some(commit.clone()),
+        ..psbt::Input::default()
+    };
+
+    let mut tx_builder = wallet.build_tx();
+    tx_builder.add_foreign_utxo(outpoint, psbt_input, envelope.satisfaction_weight())?;
+    tx_builder
+        .manually_selected_only()
+        .add_data(&marker)
+        .drain_to(change.script_pubkey())
+        .fee_rate(fee_rate);
+    let mut psbt = tx_builder.finish()?;
+
+    let index = psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .position(|input| input.previous_output == outpoint)
+        .expect("reveal spends the envelope");
+    envelope.sign_reveal(&mut psbt, index)?;
+    let reveal = psbt.extract_tx()?;
+    envelope.verify_reveal(&commit.output[vout], &reveal)?;
+
+    Ok((commit, reveal))
+}
+
+/// Pulls the enveloped payload out of a reveal transaction.
+///
+/// Returns `None` unless the transaction carries our marker and the witness data matches the
+/// hash committed to in it.
+pub fn extract_envelope(tx: &Transaction) -> Option<Vec<u8>> {
+    let marker = tx.output.iter().find_map(|output| {
+        let script = &output.script_pubkey;
+        if !script.is_op_return() {
+            return None;
+        }
+        match script.instructions().nth(1) {
+            Some(Ok(Instruction::PushBytes(bytes)))
+                if bytes.as_bytes().starts_with(PROTOCOL_TAG) =>
+            {
+                Some(bytes.as_bytes().to_vec())
+            }
+            _ => None,
+        }
+    })?;
+
+    tx.input
+        .iter()
+        .filter_map(|input| input.witness.tapscript())
+        .filter_map(parse_envelope)
+        .find(|data| reveal_marker(data).as_bytes() == marker.as_slice())
+}
+
+fn parse_envelope(script: &Script) -> Option<Vec<u8>> {
+    let instructions = script.instructions().collect::<Result<Vec<_>, _>>().ok()?;
+    let start = instructions.windows(3).position(|window| {
+        matches!(
+            window,
+            [Instruction::PushBytes(flag), Instruction::Op(OP_IF), Instruction::PushBytes(tag)]
+                if flag.is_empty() && tag.as_bytes() == PROTOCOL_TAG
+        )
+    })?;
+
+    let mut data = Vec::new();
+    for instruction in &instructions[start + 3..] {
+        match instruction {
+            Instruction::PushBytes(bytes) => data.extend_from_slice(bytes.as_bytes()),
+            Instruction::Op(OP_ENDIF) => return Some(data),
+            Instruction::Op(_) => return None,
+        }
+    }
+    None
+}
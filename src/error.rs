@@ -1,3 +1,4 @@
+use crate::backend::BroadcastError;
 use crate::error;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
@@ -17,6 +18,12 @@ impl fmt::Debug for Report {
     }
 }
 
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 impl<E> From<E> for Report
 where
     E: Into<color_eyre::Report>,
@@ -26,6 +33,20 @@ where
     }
 }
 
+impl Report {
+    /// Whether retrying can't help: the node refused the transaction or the request itself
+    /// is invalid. Backend, fee estimation and database errors may pass.
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self.0.downcast_ref::<BroadcastError>(),
+            Some(BroadcastError::Rejected(_))
+        ) || matches!(
+            self.0.downcast_ref::<Graffiti>(),
+            Some(Graffiti::BadRequest(_))
+        )
+    }
+}
+
 // Tell axum how to convert `Report` into a response.
 impl IntoResponse for Report {
     fn into_response(self) -> Response {
@@ -51,6 +72,16 @@ impl IntoResponse for Report {
 pub enum Graffiti {
     #[error("An error occurred: {0}")]
     Anyhow(#[from] anyhow::Error),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Unfunded: {0}")]
+    Unfunded(String),
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
 }
 
 impl Graffiti {
@@ -60,6 +91,12 @@ impl Graffiti {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("error getting wallet name {e}"),
             ),
+            Self::NotFound(what) => (StatusCode::NOT_FOUND, format!("{what} not found")),
+            Self::BadRequest(reason) => (StatusCode::BAD_REQUEST, reason.clone()),
+            Self::Unauthorized(reason) => (StatusCode::UNAUTHORIZED, reason.clone()),
+            Self::Unfunded(reason) | Self::Unavailable(reason) => {
+                (StatusCode::SERVICE_UNAVAILABLE, reason.clone())
+            }
         };
         (status, Json(json!({ "error": err_msg }))).into_response()
     }
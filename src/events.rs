@@ -0,0 +1,183 @@
+use anyhow::bail;
+use bdk_wallet::bitcoin::Txid;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::fmt;
+use std::str::FromStr;
+use tokio::sync::broadcast;
+use tracing::info;
+
+use crate::db;
+
+/// How many events a slow subscriber may fall behind before it starts missing them.
+const EVENT_BUFFER: usize = 256;
+
+/// Something that happened to a write after it was created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Broadcast,
+    /// The first confirmation.
+    Confirmed,
+    /// The write reached one of the configured confirmation thresholds.
+    Confirmations,
+    /// A conflicting wallet transaction spent the inputs of the write.
+    Replaced,
+    /// The block the write was confirmed in is no longer on the best chain.
+    Reorged,
+    /// The write fell out of the mempool and was sent again.
+    Rebroadcast,
+    /// The write fell out of the mempool and the server rejected it when it was sent again.
+    Failed,
+    /// The wallet pays for fewer writes than one of the runway thresholds.
+    LowBalance,
+    /// The wallet can't pay for a single write, writes are refused until it is funded.
+    Unfunded,
+    /// The wallet is back above every runway threshold.
+    Funded,
+}
+
+impl EventKind {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Broadcast => "broadcast",
+            Self::Confirmed => "confirmed",
+            Self::Confirmations => "confirmations",
+            Self::Replaced => "replaced",
+            Self::Reorged => "reorged",
+            Self::Rebroadcast => "rebroadcast",
+            Self::Failed => "failed",
+            Self::LowBalance => "low_balance",
+            Self::Unfunded => "unfunded",
+            Self::Funded => "funded",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "broadcast" => Self::Broadcast,
+            "confirmed" => Self::Confirmed,
+            "confirmations" => Self::Confirmations,
+            "replaced" => Self::Replaced,
+            "reorged" => Self::Reorged,
+            "rebroadcast" => Self::Rebroadcast,
+            "failed" => Self::Failed,
+            "low_balance" => Self::LowBalance,
+            "unfunded" => Self::Unfunded,
+            "funded" => Self::Funded,
+            _ => bail!("unknown event kind {s}"),
+        })
+    }
+}
+
+/// What an event says beyond its kind, the fields that don't apply are left out.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde_derive::Serialize)]
+pub struct EventDetail {
+    /// Confirmation height, for `confirmed`, `confirmations` and `reorged` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Height of the confirmation that was lost to a reorg.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_height: Option<u32>,
+    /// The threshold reached, for `confirmations` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirmations: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<Txid>,
+    /// Why the server rejected the write, for `failed` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Spendable sats, for funding alerts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<u64>,
+    /// Writes the balance pays for, for funding alerts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runway: Option<u64>,
+    /// The runway threshold crossed, for `low_balance` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde_derive::Serialize)]
+pub struct WriteEvent {
+    pub id: i64,
+    /// Left out for funding alerts, which are about the wallet rather than a write.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txid: Option<Txid>,
+    pub kind: EventKind,
+    #[serde(flatten)]
+    pub detail: EventDetail,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Persists write events and fans them out to whoever is listening in-process.
+#[derive(Clone, Debug)]
+pub struct Events {
+    db: PgPool,
+    sender: broadcast::Sender<WriteEvent>,
+}
+
+impl Events {
+    pub fn new(db: PgPool) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { db, sender }
+    }
+
+    /// Stores the event and queues its webhook deliveries.
+    ///
+    /// # Errors
+    ///
+    /// Will return errors if the event can't be stored
+    pub async fn emit(
+        &self,
+        txid: Txid,
+        kind: EventKind,
+        detail: EventDetail,
+    ) -> anyhow::Result<WriteEvent> {
+        let event = self.store(Some(txid), kind, detail).await?;
+        info!("write {} {}", txid, kind);
+        Ok(event)
+    }
+
+    /// Stores an event about the wallet itself and queues its webhook deliveries.
+    ///
+    /// # Errors
+    ///
+    /// Will return errors if the event can't be stored
+    pub async fn emit_wallet(
+        &self,
+        kind: EventKind,
+        detail: EventDetail,
+    ) -> anyhow::Result<WriteEvent> {
+        let event = self.store(None, kind, detail).await?;
+        info!("wallet {}", kind);
+        Ok(event)
+    }
+
+    async fn store(
+        &self,
+        txid: Option<Txid>,
+        kind: EventKind,
+        detail: EventDetail,
+    ) -> anyhow::Result<WriteEvent> {
+        let event = db::insert_event(&self.db, txid, kind, detail).await?;
+        db::queue_deliveries(&self.db, &event).await?;
+        // Nobody listening is fine, the event is in the database.
+        let _ = self.sender.send(event.clone());
+        Ok(event)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WriteEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,147 @@
+use bdk_wallet::bitcoin::{Amount, FeeRate, OutPoint, Transaction};
+use bdk_wallet::chain::ConfirmationTime;
+use bdk_wallet::{KeychainKind, LocalOutput, SignOptions, Wallet};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::config::FanOutConfig;
+use crate::routes::reserved_outpoints;
+use crate::util::{insert_broadcast, GrafittiState, FEE_TARGET};
+
+/// How often the pool is topped up.
+const FAN_OUT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Most coins split off in one transaction.
+const MAX_SPLIT_OUTPUTS: usize = 50;
+
+fn is_confirmed(utxo: &LocalOutput) -> bool {
+    matches!(utxo.confirmation_time, ConfirmationTime::Confirmed { .. })
+}
+
+/// The confirmed pool coin a write of `fee` should spend: the smallest one that pays for it
+/// and isn't held by a pending write. Spending a confirmed coin keeps the write out of any
+/// unconfirmed chain.
+pub fn pick_pool_coin(
+    wallet: &Wallet,
+    config: &FanOutConfig,
+    reserved: &[OutPoint],
+    fee: Amount,
+) -> Option<OutPoint> {
+    wallet
+        .list_unspent()
+        .filter(|utxo| {
+            is_confirmed(utxo)
+                && config.is_pool_coin(utxo.txout.value)
+                && utxo.txout.value > fee
+                && !reserved.contains(&utxo.outpoint)
+        })
+        .min_by_key(|utxo| utxo.txout.value)
+        .map(|utxo| utxo.outpoint)
+}
+
+/// Splits coins larger than the pool coins into as many as the pool is short of. Pool coins,
+/// unconfirmed coins and `reserved` ones are left alone, so the new coins don't join an
+/// unconfirmed chain.
+///
+/// Returns `None` when the pool is full or there is nothing to split. Unconfirmed pool coins
+/// count as well, so a split isn't repeated while the last one confirms.
+///
+/// # Errors
+///
+/// Will return errors if the larger coins can't pay for the split or it can't be signed
+pub fn build_split(
+    wallet: &mut Wallet,
+    config: &FanOutConfig,
+    reserved: Vec<OutPoint>,
+    fee_rate: FeeRate,
+    sign_options: SignOptions,
+) -> anyhow::Result<Option<Transaction>> {
+    let pool = wallet
+        .list_unspent()
+        .filter(|utxo| config.is_pool_coin(utxo.txout.value))
+        .count();
+    let (splittable, others): (Vec<_>, Vec<_>) = wallet
+        .list_unspent()
+        .filter(|utxo| !reserved.contains(&utxo.outpoint))
+        .partition(|utxo| is_confirmed(utxo) && utxo.txout.value > config.coin * 2);
+    let splittable: Amount = splittable.iter().map(|utxo| utxo.txout.value).sum();
+    // One coin's worth is left for the fee and the change.
+    let affordable = (splittable.to_sat() / config.coin.to_sat()).saturating_sub(1);
+    let missing = config
+        .size
+        .saturating_sub(pool)
+        .min(MAX_SPLIT_OUTPUTS)
+        .min(usize::try_from(affordable)?);
+    if missing == 0 {
+        return Ok(None);
+    }
+    let unspendable: Vec<OutPoint> = others
+        .into_iter()
+        .map(|utxo| utxo.outpoint)
+        .chain(reserved)
+        .collect();
+    let scripts: Vec<_> = (0..missing)
+        .map(|_| {
+            wallet
+                .reveal_next_address(KeychainKind::Internal)
+                .script_pubkey()
+        })
+        .collect();
+
+    info!(
+        "splitting {} coins of {} off for the pool of {}",
+        missing, config.coin, config.size
+    );
+    let mut tx_builder = wallet.build_tx();
+    for script in scripts {
+        tx_builder.add_recipient(script, config.coin);
+    }
+    tx_builder.unspendable(unspendable).fee_rate(fee_rate);
+    let mut psbt = tx_builder.finish()?;
+    anyhow::ensure!(
+        wallet.sign(&mut psbt, sign_options)?,
+        "wallet could not finalize the split"
+    );
+    Ok(Some(psbt.extract_tx()?))
+}
+
+/// Keeps `GRAFFITI_POOL_SIZE` confirmed coins of `GRAFFITI_POOL_COIN` around, so a burst of
+/// writes spends separate coins instead of chaining off each other's change.
+pub struct FanOut {
+    gs: GrafittiState,
+    config: FanOutConfig,
+}
+
+impl FanOut {
+    pub const fn new(gs: GrafittiState, config: FanOutConfig) -> Self {
+        Self { gs, config }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.top_up().await {
+                    warn!("could not top up the coin pool, retrying: {e:?}");
+                }
+                tokio::time::sleep(FAN_OUT_INTERVAL).await;
+            }
+        })
+    }
+
+    async fn top_up(&self) -> anyhow::Result<()> {
+        let fee_rate = self.gs.blockchain.estimate_fee_rate(FEE_TARGET).await?;
+        let mut wallet = self.gs.wallet.lock().await;
+        let reserved = reserved_outpoints(&self.gs, &wallet).await?;
+        let sign_options = self.gs.config.descriptor_kind().sign_options();
+        let Some(tx) = build_split(&mut wallet, &self.config, reserved, fee_rate, sign_options)?
+        else {
+            debug!("coin pool is full");
+            return Ok(());
+        };
+
+        self.gs.blockchain.broadcast(&tx).await?;
+        insert_broadcast(&mut wallet, &[&tx]);
+        info!("broadcast pool split {}", tx.compute_txid());
+        Ok(())
+    }
+}
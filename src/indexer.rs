@@ -0,0 +1,235 @@
+use bdk_wallet::bitcoin::hex::DisplayHex;
+use bdk_wallet::bitcoin::{Block, Transaction, Txid};
+use bitcoincore_rpc::{Client as RpcClient, RpcApi};
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::RpcConfig;
+use crate::db;
+use crate::stream::Feed;
+use crate::util::op_return_payload;
+
+/// How long to wait for a new block once the index has caught up with the tip.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Mempool transactions fetched per pass, the rest wait for the next one.
+const MEMPOOL_BATCH: usize = 500;
+
+/// An `OP_RETURN` output found anywhere on chain or in the node's mempool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedOpReturn {
+    pub txid: Txid,
+    pub vout: u32,
+    /// `None` while the output is only in the mempool.
+    pub height: Option<u32>,
+    /// The first push after `OP_RETURN`, or everything after it when the script isn't made
+    /// of pushes only.
+    pub payload: Vec<u8>,
+    pub script: Vec<u8>,
+}
+
+impl IndexedOpReturn {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "txid": self.txid,
+            "vout": self.vout,
+            "height": self.height,
+            "payload": self.payload.to_lower_hex_string(),
+            "text": std::str::from_utf8(&self.payload).ok(),
+        })
+    }
+}
+
+/// Every `OP_RETURN` output of `tx`.
+pub fn tx_op_returns(tx: &Transaction, height: Option<u32>) -> Vec<IndexedOpReturn> {
+    let txid = tx.compute_txid();
+    tx.output
+        .iter()
+        .zip(0..)
+        .filter(|(output, _)| output.script_pubkey.is_op_return())
+        .map(|(output, vout)| {
+            let script = &output.script_pubkey;
+            IndexedOpReturn {
+                txid,
+                vout,
+                height,
+                payload: op_return_payload(script)
+                    .unwrap_or_else(|| script.as_bytes()[1..].to_vec()),
+                script: script.to_bytes(),
+            }
+        })
+        .collect()
+}
+
+/// Every `OP_RETURN` output in `block`.
+pub fn extract_op_returns(block: &Block, height: u32) -> Vec<IndexedOpReturn> {
+    block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx_op_returns(tx, Some(height)))
+        .collect()
+}
+
+/// Walks the chain from a start height through Bitcoin Core RPC and records every `OP_RETURN`
+/// output in Postgres, following the tip and undoing blocks that get reorged out. Once caught
+/// up it also watches the node's mempool, and every change goes out on the [`Feed`].
+pub struct Indexer {
+    db: PgPool,
+    rpc: Arc<RpcClient>,
+    start_height: u32,
+    feed: Feed,
+    /// Mempool transactions already looked at, `None` until the first pass.
+    mempool: Option<HashSet<Txid>>,
+}
+
+impl Indexer {
+    /// # Errors
+    ///
+    /// Will return an error if the RPC client can't be created
+    pub fn new(db: PgPool, rpc: &RpcConfig, start_height: u32, feed: Feed) -> anyhow::Result<Self> {
+        Ok(Self {
+            db,
+            rpc: Arc::new(rpc.client()?),
+            start_height,
+            feed,
+            mempool: None,
+        })
+    }
+
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            info!(
+                "indexing OP_RETURN outputs from height {}",
+                self.start_height
+            );
+            loop {
+                if let Err(e) = self.sync().await {
+                    warn!("OP_RETURN indexer failed, retrying: {e:?}");
+                } else if let Err(e) = self.scan_mempool().await {
+                    warn!("could not scan the mempool for OP_RETURN outputs: {e:?}");
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        })
+    }
+
+    /// Runs a blocking RPC call off the async runtime.
+    async fn rpc<T, F>(&self, call: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&RpcClient) -> bitcoincore_rpc::Result<T> + Send + 'static,
+    {
+        let rpc = self.rpc.clone();
+        Ok(tokio::task::spawn_blocking(move || call(&rpc)).await??)
+    }
+
+    /// Indexes blocks until the tip is reached.
+    async fn sync(&self) -> anyhow::Result<()> {
+        let tip = self.rpc(|rpc| rpc.get_block_count()).await?;
+        let mut height = self.rewind(tip).await?;
+
+        while u64::from(height) <= tip {
+            self.index_block(height).await?;
+            height += 1;
+        }
+        Ok(())
+    }
+
+    /// Drops indexed blocks that are no longer on the best chain and returns the next height
+    /// to index.
+    async fn rewind(&self, tip: u64) -> anyhow::Result<u32> {
+        while let Some((height, hash)) = db::last_indexed_block(&self.db).await? {
+            if u64::from(height) <= tip {
+                let best = self
+                    .rpc(move |rpc| rpc.get_block_hash(height.into()))
+                    .await?;
+                if best == hash {
+                    return Ok(height + 1);
+                }
+            }
+            warn!("block {} at height {} was reorged out", hash, height);
+            self.feed
+                .publish(db::remove_indexed_blocks_from(&self.db, height).await?);
+        }
+        Ok(self.start_height)
+    }
+
+    async fn index_block(&self, height: u32) -> anyhow::Result<()> {
+        let block = self
+            .rpc(move |rpc| {
+                let hash = rpc.get_block_hash(height.into())?;
+                rpc.get_block(&hash)
+            })
+            .await?;
+
+        // A reorg while catching up, the next sync rewinds past it.
+        if let Some(parent) = height.checked_sub(1) {
+            if let Some(indexed) = db::indexed_block_hash(&self.db, parent).await? {
+                anyhow::ensure!(
+                    block.header.prev_blockhash == indexed,
+                    "block at height {height} does not build on the indexed chain"
+                );
+            }
+        }
+
+        let op_returns = extract_op_returns(&block, height);
+        let records =
+            db::insert_indexed_block(&self.db, height, block.block_hash(), &op_returns).await?;
+        self.feed.publish(records);
+        if !op_returns.is_empty() {
+            info!(
+                "indexed {} OP_RETURN outputs at height {}",
+                op_returns.len(),
+                height
+            );
+        }
+        Ok(())
+    }
+
+    /// Records the `OP_RETURN` outputs of transactions that entered the mempool since the last
+    /// pass. The first pass only takes note of what is there.
+    async fn scan_mempool(&mut self) -> anyhow::Result<()> {
+        let listed: HashSet<Txid> = self
+            .rpc(|rpc| rpc.get_raw_mempool())
+            .await?
+            .into_iter()
+            .collect();
+        let Some(seen) = self.mempool.as_mut() else {
+            self.mempool = Some(listed);
+            return Ok(());
+        };
+        seen.retain(|txid| listed.contains(txid));
+        let new: Vec<Txid> = listed
+            .into_iter()
+            .filter(|txid| !seen.contains(txid))
+            .take(MEMPOOL_BATCH)
+            .collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        let fetch = new.clone();
+        let txs = self
+            .rpc(move |rpc| {
+                // Transactions that left the mempool since the listing are skipped.
+                Ok(fetch
+                    .iter()
+                    .filter_map(|txid| rpc.get_raw_transaction(txid, None).ok())
+                    .collect::<Vec<_>>())
+            })
+            .await?;
+        let op_returns: Vec<_> = txs.iter().flat_map(|tx| tx_op_returns(tx, None)).collect();
+        if !op_returns.is_empty() {
+            self.feed
+                .publish(db::insert_mempool_events(&self.db, op_returns).await?);
+        }
+        if let Some(seen) = &mut self.mempool {
+            seen.extend(new);
+        }
+        Ok(())
+    }
+}
@@ -9,11 +9,33 @@
 //! It leverages the Bitcoin Development Kit (BDK) to offer a simple and efficient way
 //! to create and manage `OP_RETURN` transactions on the Bitcoin network.
 
+mod admin;
+mod attestation;
+mod backend;
+mod batch;
+mod config;
+mod db;
+mod electrum;
+mod encryption;
+mod envelope;
 mod error;
+mod events;
+mod fanout;
+mod indexer;
+mod monitor;
+mod multisig;
+mod queue;
 mod routes;
+mod sandbox;
+mod schedule;
+mod stream;
 mod testenv;
 mod tests;
+mod tracker;
+mod treasury;
 mod util;
+mod watcher;
+mod webhooks;
 use crate::util::{setup_better_panic, setup_server, setup_tracer};
 use axum::serve;
 use tracing::info;
@@ -27,7 +49,8 @@ async fn main() -> anyhow::Result<()> {
 
     setup_tracer();
 
-    let (app, listener) = setup_server().await?;
+    let sandbox = std::env::args().any(|arg| arg == "--sandbox");
+    let (app, listener) = setup_server(sandbox).await?;
 
     info!("Server running on {:?}", listener);
 
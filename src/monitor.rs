@@ -0,0 +1,256 @@
+use bdk_wallet::bitcoin::{Amount, FeeRate, Weight};
+use bdk_wallet::Wallet;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::error::Graffiti;
+use crate::events::{EventDetail, EventKind};
+use crate::util::{affordable_writes, GrafittiState, FEE_TARGET};
+
+/// How often the balance and the fee rate are sampled.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Fee rate samples the runway is averaged over, an hour of them.
+const FEE_SAMPLES: usize = 60;
+/// Payload size the runway is counted in.
+pub const RUNWAY_WRITE_SIZE: usize = 80;
+
+/// How well funded the wallet is, measured against the runway thresholds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FundingLevel {
+    Funded,
+    /// Below this threshold, but still able to pay for a write.
+    Low(u64),
+    Unfunded,
+}
+
+impl FundingLevel {
+    /// The level of a wallet that pays for `runway` writes, `thresholds` are highest first.
+    pub fn of(runway: u64, thresholds: &[u64]) -> Self {
+        if runway == 0 {
+            return Self::Unfunded;
+        }
+        thresholds
+            .iter()
+            .rev()
+            .find(|threshold| runway < **threshold)
+            .map_or(Self::Funded, |threshold| Self::Low(*threshold))
+    }
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Funded => "funded",
+            Self::Low(_) => "low",
+            Self::Unfunded => "unfunded",
+        }
+    }
+
+    /// The alert for moving from `previous` to this level. Only dropping below a threshold and
+    /// recovering above all of them are announced, not every step back up.
+    pub fn alert(self, previous: Option<Self>) -> Option<EventKind> {
+        match (previous, self) {
+            (Some(previous), current) if previous == current => None,
+            (_, Self::Unfunded) => Some(EventKind::Unfunded),
+            (None | Some(Self::Funded), Self::Low(_)) => Some(EventKind::LowBalance),
+            (Some(Self::Low(previous)), Self::Low(current)) if current < previous => {
+                Some(EventKind::LowBalance)
+            }
+            (Some(Self::Low(_) | Self::Unfunded), Self::Funded) => Some(EventKind::Funded),
+            _ => None,
+        }
+    }
+}
+
+/// The last check of the balance monitor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunwayStatus {
+    pub spendable: Amount,
+    /// Average of the recent fee rate samples.
+    pub fee_rate: FeeRate,
+    /// Writes of [`RUNWAY_WRITE_SIZE`] bytes the spendable balance pays for.
+    pub writes: u64,
+    pub level: FundingLevel,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// Fee rate samples and the latest status, shared between the monitor and the handlers.
+#[derive(Debug, Default)]
+pub struct Runway {
+    fee_rates: VecDeque<FeeRate>,
+    pub status: Option<RunwayStatus>,
+}
+
+pub type SharedRunway = Arc<Mutex<Runway>>;
+
+impl Runway {
+    /// Adds a sample and returns the rolling average.
+    pub fn sample(&mut self, fee_rate: FeeRate) -> FeeRate {
+        if self.fee_rates.len() == FEE_SAMPLES {
+            self.fee_rates.pop_front();
+        }
+        self.fee_rates.push_back(fee_rate);
+        let total: u64 = self
+            .fee_rates
+            .iter()
+            .map(|rate| rate.to_sat_per_kwu())
+            .sum();
+        FeeRate::from_sat_per_kwu(total / self.fee_rates.len() as u64)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let Some(status) = self.status else {
+            return json!({ "level": null });
+        };
+        json!({
+            "level": status.level.as_str(),
+            "spendable": status.spendable.to_sat(),
+            "fee_rate": status.fee_rate.to_sat_per_vb_ceil(),
+            "runway": status.writes,
+            "write_size": RUNWAY_WRITE_SIZE,
+            "checked_at": status.checked_at,
+        })
+    }
+
+    /// The status in the Prometheus text format, empty before the first check.
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        let Some(status) = self.status else {
+            return out;
+        };
+        let gauges = [
+            (
+                "graffiti_wallet_spendable_sats",
+                "Spendable balance of the funding wallet.",
+                status.spendable.to_sat(),
+            ),
+            (
+                "graffiti_fee_rate_sat_per_kwu",
+                "Rolling average fee rate the runway is counted at.",
+                status.fee_rate.to_sat_per_kwu(),
+            ),
+            (
+                "graffiti_write_runway",
+                "Writes the spendable balance pays for.",
+                status.writes,
+            ),
+            (
+                "graffiti_wallet_unfunded",
+                "1 while the wallet can't pay for a write.",
+                u64::from(status.level == FundingLevel::Unfunded),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {value}");
+        }
+        out
+    }
+}
+
+/// Refuses a write of `weight`, see [`crate::util::estimate_write_weight`] and
+/// [`crate::envelope::estimate_envelope_weight`], up front when the wallet can't pay for it,
+/// instead of failing in coin selection.
+///
+/// # Errors
+///
+/// Will return [`Graffiti::Unfunded`] if the spendable balance is short of the fee
+pub fn ensure_funded(wallet: &Wallet, weight: Weight, fee_rate: FeeRate) -> Result<(), Graffiti> {
+    let spendable = wallet.balance().trusted_spendable();
+    let fee = fee_rate.fee_wu(weight).unwrap_or(Amount::MAX_MONEY);
+    if spendable >= fee {
+        return Ok(());
+    }
+    Err(Graffiti::Unfunded(format!(
+        "the wallet is unfunded, {spendable} spendable can't pay the {fee} fee of this write"
+    )))
+}
+
+/// Samples the balance and the fee rate, keeps the runway up to date and raises an alert
+/// through the logs and webhooks whenever it drops below a threshold.
+pub struct BalanceMonitor {
+    gs: GrafittiState,
+}
+
+impl BalanceMonitor {
+    pub const fn new(gs: GrafittiState) -> Self {
+        Self { gs }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.check().await {
+                    warn!("balance monitor failed, retrying: {e:?}");
+                }
+                tokio::time::sleep(CHECK_INTERVAL).await;
+            }
+        })
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let fee_rate = self.gs.blockchain.estimate_fee_rate(FEE_TARGET).await?;
+        // Kept in sync by the watcher.
+        let spendable = self.gs.wallet.lock().await.balance().trusted_spendable();
+
+        let (status, previous) = {
+            let mut runway = self.gs.runway.lock().await;
+            let fee_rate = runway.sample(fee_rate);
+            let writes = affordable_writes(
+                self.gs.config.descriptor_kind(),
+                RUNWAY_WRITE_SIZE,
+                spendable,
+                fee_rate,
+            );
+            let status = RunwayStatus {
+                spendable,
+                fee_rate,
+                writes,
+                level: FundingLevel::of(writes, &self.gs.config.runway_thresholds),
+                checked_at: Utc::now(),
+            };
+            (
+                status,
+                runway.status.replace(status).map(|status| status.level),
+            )
+        };
+
+        let Some(kind) = status.level.alert(previous) else {
+            return Ok(());
+        };
+        let threshold = match status.level {
+            FundingLevel::Low(threshold) => {
+                warn!(
+                    "wallet pays for {} more writes, below the threshold of {}",
+                    status.writes, threshold
+                );
+                Some(threshold)
+            }
+            FundingLevel::Unfunded => {
+                warn!("wallet can't pay for another write, refusing writes until it is funded");
+                None
+            }
+            FundingLevel::Funded => {
+                info!(
+                    "wallet is funded again, it pays for {} writes",
+                    status.writes
+                );
+                None
+            }
+        };
+        let detail = EventDetail {
+            balance: Some(status.spendable.to_sat()),
+            runway: Some(status.writes),
+            threshold,
+            ..EventDetail::default()
+        };
+        self.gs.events.emit_wallet(kind, detail).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use bdk_wallet::bitcoin::{OutPoint, Psbt, Transaction, Txid};
+use bdk_wallet::{SignOptions, Wallet};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::util::op_return_payload;
+
+/// Writes from a multisig wallet that are waiting for cosigner signatures, keyed by id.
+pub type PendingWrites = Arc<Mutex<HashMap<Uuid, PendingWrite>>>;
+
+/// A write whose PSBT has been signed by the local keys but is still short of the threshold.
+#[derive(Clone, Debug)]
+pub struct PendingWrite {
+    pub id: Uuid,
+    pub data: String,
+    pub psbt: Psbt,
+    pub threshold: usize,
+    pub created_at: DateTime<Utc>,
+}
+
+impl PendingWrite {
+    pub fn new(data: String, psbt: Psbt, threshold: usize) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            data,
+            psbt,
+            threshold,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Signatures collected so far, counted on the input that has the fewest.
+    pub fn signatures(&self) -> usize {
+        self.psbt
+            .inputs
+            .iter()
+            .map(|input| input.partial_sigs.len() + input.tap_script_sigs.len())
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Id of the transaction once signed, segwit signatures don't change it.
+    pub fn txid(&self) -> Txid {
+        self.psbt.unsigned_tx.compute_txid()
+    }
+
+    /// The payload of the write's data carrier output.
+    pub fn payload(&self) -> Option<Vec<u8>> {
+        self.psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .find_map(|output| op_return_payload(&output.script_pubkey))
+    }
+
+    /// Coins this write spends, which other writes must leave alone until it is resolved.
+    pub fn outpoints(&self) -> impl Iterator<Item = OutPoint> + '_ {
+        self.psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+    }
+
+    /// Merges the signatures from a cosigner's copy of the PSBT into ours.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the PSBT is for a different transaction
+    pub fn combine(&mut self, other: Psbt) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            other.unsigned_tx == self.psbt.unsigned_tx,
+            "PSBT does not belong to write {}",
+            self.id
+        );
+        self.psbt.combine(other)?;
+        Ok(())
+    }
+
+    /// Finalizes a copy of the PSBT, returning the transaction once enough signatures are in.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the wallet fails to finalize or extract the transaction
+    pub fn try_finalize(
+        &self,
+        wallet: &Wallet,
+        sign_options: SignOptions,
+    ) -> anyhow::Result<Option<Transaction>> {
+        if self.signatures() < self.threshold {
+            return Ok(None);
+        }
+        let mut psbt = self.psbt.clone();
+        if !wallet.finalize_psbt(&mut psbt, sign_options)? {
+            return Ok(None);
+        }
+        Ok(Some(psbt.extract_tx()?))
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "status": "pending",
+            "data": self.data,
+            "signatures": self.signatures(),
+            "threshold": self.threshold,
+            "created_at": self.created_at,
+            "psbt": self.psbt.to_string(),
+        })
+    }
+}
@@ -0,0 +1,360 @@
+use bdk_wallet::bitcoin::{OutPoint, Txid};
+use bdk_wallet::Wallet;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::attestation::Attestation;
+use crate::db;
+use crate::routes::{send_write, WriteOutcome};
+use crate::util::GrafittiState;
+
+/// Bitcoin Core's default `-limitancestorcount`, counting the transaction itself.
+pub const MAX_ANCESTORS: usize = 25;
+/// Bitcoin Core's default `-limitdescendantcount`, counting the transaction itself.
+pub const MAX_DESCENDANTS: usize = 25;
+/// How often queued writes are retried.
+const QUEUE_INTERVAL: Duration = Duration::from_secs(15);
+/// Finished writes kept around for callers to look up, older ones are read from the database.
+const MAX_FINISHED: usize = 1_000;
+/// Writes queued at once, new writes are refused beyond it.
+pub const MAX_QUEUED_WRITES: usize = 1_000;
+
+/// The unconfirmed part of the wallet's transaction graph.
+#[derive(Debug, Default)]
+pub struct Mempool {
+    parents: HashMap<Txid, HashSet<Txid>>,
+    children: HashMap<Txid, HashSet<Txid>>,
+}
+
+impl Mempool {
+    pub fn of(wallet: &Wallet) -> Self {
+        let unconfirmed: HashSet<Txid> = wallet
+            .transactions()
+            .filter(|tx| !tx.chain_position.is_confirmed())
+            .map(|tx| tx.tx_node.txid)
+            .collect();
+        let mut mempool = Self::default();
+        for tx in wallet.transactions() {
+            let txid = tx.tx_node.txid;
+            if !unconfirmed.contains(&txid) {
+                continue;
+            }
+            let parents: HashSet<Txid> = tx
+                .tx_node
+                .tx
+                .input
+                .iter()
+                .map(|input| input.previous_output.txid)
+                .filter(|parent| unconfirmed.contains(parent))
+                .collect();
+            for parent in &parents {
+                mempool.children.entry(*parent).or_default().insert(txid);
+            }
+            mempool.children.entry(txid).or_default();
+            mempool.parents.insert(txid, parents);
+        }
+        mempool
+    }
+
+    /// Every transaction reachable from `txid` through `edges`, not counting `txid` itself.
+    fn reach(edges: &HashMap<Txid, HashSet<Txid>>, txid: Txid) -> HashSet<Txid> {
+        let mut seen = HashSet::new();
+        let mut queue = vec![txid];
+        while let Some(next) = queue.pop() {
+            for other in edges.get(&next).into_iter().flatten() {
+                if seen.insert(*other) {
+                    queue.push(*other);
+                }
+            }
+        }
+        seen
+    }
+
+    pub fn ancestors(&self, txid: Txid) -> HashSet<Txid> {
+        Self::reach(&self.parents, txid)
+    }
+
+    pub fn descendants(&self, txid: Txid) -> HashSet<Txid> {
+        Self::reach(&self.children, txid)
+    }
+
+    /// Why a new transaction spending an output of `parent` would be rejected by the mempool,
+    /// `None` if it fits within the ancestor and descendant limits or `parent` is confirmed.
+    pub fn violation(&self, parent: Txid) -> Option<String> {
+        if !self.parents.contains_key(&parent) {
+            return None;
+        }
+        let mut ancestors = self.ancestors(parent);
+        ancestors.insert(parent);
+        if ancestors.len() + 1 > MAX_ANCESTORS {
+            return Some(format!(
+                "spending {parent} would have {} unconfirmed ancestors, the limit is {}",
+                ancestors.len(),
+                MAX_ANCESTORS - 1
+            ));
+        }
+        ancestors.into_iter().find_map(|ancestor| {
+            let descendants = self.descendants(ancestor).len() + 1;
+            (descendants + 1 > MAX_DESCENDANTS).then(|| {
+                format!(
+                    "unconfirmed {ancestor} already has {descendants} descendants, the limit is {MAX_DESCENDANTS}"
+                )
+            })
+        })
+    }
+}
+
+/// Unspent coins a write can't spend until their unconfirmed parents confirm, with the reason.
+pub fn blocked_coins(wallet: &Wallet) -> Vec<(OutPoint, String)> {
+    let mempool = Mempool::of(wallet);
+    wallet
+        .list_unspent()
+        .filter_map(|utxo| {
+            mempool
+                .violation(utxo.outpoint.txid)
+                .map(|reason| (utxo.outpoint, reason))
+        })
+        .collect()
+}
+
+/// A validated write, ready to be built and broadcast.
+#[derive(Clone, Debug)]
+pub struct PreparedWrite {
+    pub data: String,
+    pub payload: Vec<u8>,
+    pub attestation: Option<Attestation>,
+    pub on_chain: bool,
+    /// Confirmation target the fee rate is estimated for.
+    pub fee_target: usize,
+    /// Signal replaceability, so the write can be replaced while unconfirmed.
+    pub replaceable: bool,
+    /// Written through a commit/reveal pair, see [`crate::envelope`], not an `OP_RETURN`.
+    pub envelope: bool,
+}
+
+/// A write held back because every coin it could spend is at the mempool limits.
+#[derive(Clone, Debug)]
+pub struct QueuedWrite {
+    pub id: Uuid,
+    pub write: PreparedWrite,
+    pub reason: String,
+    pub queued_at: DateTime<Utc>,
+}
+
+impl QueuedWrite {
+    pub fn new(write: PreparedWrite, reason: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            write,
+            reason,
+            queued_at: Utc::now(),
+        }
+    }
+}
+
+/// What became of a write once it left the queue.
+#[derive(Clone, Debug)]
+pub struct FinishedWrite {
+    pub status: String,
+    pub result: serde_json::Value,
+    pub finished_at: DateTime<Utc>,
+}
+
+impl FinishedWrite {
+    pub fn to_json(&self, id: Uuid) -> serde_json::Value {
+        json!({
+            "id": id,
+            "status": self.status,
+            "result": self.result,
+            "finished_at": self.finished_at,
+        })
+    }
+}
+
+/// Writes waiting on unconfirmed parents, sent in the order they came in.
+pub type WriteQueue = Arc<Mutex<QueuedWrites>>;
+
+#[derive(Debug, Default)]
+pub struct QueuedWrites {
+    queue: VecDeque<QueuedWrite>,
+    finished: HashMap<Uuid, FinishedWrite>,
+    finished_order: VecDeque<Uuid>,
+}
+
+impl QueuedWrites {
+    /// The writes a previous run left queued, in the order they came in.
+    pub fn restore(writes: Vec<QueuedWrite>) -> Self {
+        Self {
+            queue: writes.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= MAX_QUEUED_WRITES
+    }
+
+    /// Queues `write`, returning its 1-based position. Callers check [`Self::is_full`] and
+    /// store the write first.
+    pub fn push(&mut self, write: QueuedWrite) -> usize {
+        self.queue.push_back(write);
+        self.queue.len()
+    }
+
+    pub fn front(&self) -> Option<QueuedWrite> {
+        self.queue.front().cloned()
+    }
+
+    /// Records why the write `id` is still held back.
+    pub fn block(&mut self, id: Uuid, reason: String) {
+        if let Some(write) = self.queue.iter_mut().find(|write| write.id == id) {
+            write.reason = reason;
+        }
+    }
+
+    /// Takes `id` off the queue, remembering how it went.
+    pub fn finish(&mut self, id: Uuid, status: &str, result: serde_json::Value) {
+        self.queue.retain(|write| write.id != id);
+        self.finished.insert(
+            id,
+            FinishedWrite {
+                status: status.to_string(),
+                result,
+                finished_at: Utc::now(),
+            },
+        );
+        self.finished_order.push_back(id);
+        while self.finished_order.len() > MAX_FINISHED {
+            if let Some(old) = self.finished_order.pop_front() {
+                self.finished.remove(&old);
+            }
+        }
+    }
+
+    fn queued_json(position: usize, write: &QueuedWrite) -> serde_json::Value {
+        json!({
+            "id": write.id,
+            "status": "queued",
+            "position": position + 1,
+            "reason": write.reason,
+            "data": write.write.data,
+            "queued_at": write.queued_at,
+        })
+    }
+
+    pub fn to_json(&self) -> Vec<serde_json::Value> {
+        self.queue
+            .iter()
+            .enumerate()
+            .map(|(position, write)| Self::queued_json(position, write))
+            .collect()
+    }
+
+    /// The queued `OP_RETURN` write of `payload`, marked as a duplicate. When an attestation
+    /// is asked for, only an attested write counts.
+    pub fn find_payload(&self, payload: &[u8], attest: bool) -> Option<serde_json::Value> {
+        self.queue
+            .iter()
+            .enumerate()
+            .find(|(_, queued)| {
+                !queued.write.envelope
+                    && queued.write.payload == payload
+                    && (queued.write.attestation.is_some() || !attest)
+            })
+            .map(|(position, queued)| {
+                let mut j = Self::queued_json(position, queued);
+                j["duplicate"] = json!(true);
+                j
+            })
+    }
+
+    /// The queued or recently finished write `id`.
+    pub fn get(&self, id: Uuid) -> Option<serde_json::Value> {
+        if let Some((position, write)) = self
+            .queue
+            .iter()
+            .enumerate()
+            .find(|(_, write)| write.id == id)
+        {
+            return Some(Self::queued_json(position, write));
+        }
+        self.finished.get(&id).map(|finished| finished.to_json(id))
+    }
+}
+
+/// Sends queued writes as their parents confirm, stopping at the first one still blocked so
+/// writes go out in order.
+pub struct QueueWorker {
+    gs: GrafittiState,
+}
+
+impl QueueWorker {
+    pub const fn new(gs: GrafittiState) -> Self {
+        Self { gs }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                self.drain().await;
+                tokio::time::sleep(QUEUE_INTERVAL).await;
+            }
+        })
+    }
+
+    async fn drain(&self) {
+        loop {
+            let Some(next) = self.gs.queue.lock().await.front() else {
+                return;
+            };
+            let outcome = send_write(&self.gs, next.write.clone()).await;
+            let mut queue = self.gs.queue.lock().await;
+            let (status, result) = match outcome {
+                Ok(WriteOutcome::Blocked(reason)) => {
+                    if let Err(e) = db::block_queued_write(&self.gs.db, next.id, &reason).await {
+                        warn!("could not store why write {} is queued: {e:?}", next.id);
+                    }
+                    queue.block(next.id, reason);
+                    return;
+                }
+                Ok(WriteOutcome::Sent(_, j)) => {
+                    info!("sent queued write {}", next.id);
+                    ("broadcast", j)
+                }
+                Ok(WriteOutcome::Pending(_, j)) => {
+                    info!("queued write {} is waiting for cosigners", next.id);
+                    ("pending_signatures", j)
+                }
+                Err(e) if e.is_rejection() => {
+                    warn!("queued write {} was rejected: {e:?}", next.id);
+                    ("failed", json!({ "error": e.to_string() }))
+                }
+                // The backend or database may be back by the next interval, keep the order.
+                Err(e) => {
+                    warn!("queued write {} will be retried: {e:?}", next.id);
+                    let reason = format!("retrying after an error: {e}");
+                    if let Err(e) = db::block_queued_write(&self.gs.db, next.id, &reason).await {
+                        warn!("could not store why write {} is queued: {e:?}", next.id);
+                    }
+                    queue.block(next.id, reason);
+                    return;
+                }
+            };
+            // Stored, so a restart doesn't send it again.
+            if let Err(e) = db::finish_queued_write(&self.gs.db, next.id, status, &result).await {
+                warn!(
+                    "could not store the result of queued write {}: {e:?}",
+                    next.id
+                );
+            }
+            queue.finish(next.id, status, result);
+        }
+    }
+}
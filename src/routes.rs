@@ -1,28 +1,99 @@
 // External crate imports
+use axum::body::Bytes;
+use axum::extract::Query;
 use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::{extract::Path, response::IntoResponse, Json};
-use bdk_wallet::{KeychainKind, SignOptions, Wallet};
+use bdk_wallet::bitcoin::hex::{DisplayHex, FromHex};
+use bdk_wallet::bitcoin::secp256k1::{PublicKey, SecretKey};
+use bdk_wallet::bitcoin::{Address, Amount, FeeRate, OutPoint, Psbt, Transaction, Txid};
+use bdk_wallet::chain::ConfirmationTime;
+use bdk_wallet::{KeychainKind, Wallet};
+use qrcode::render::svg;
+use qrcode::QrCode;
 // use bdk_wallet::bitcoin::script::PushBytesBuf;
+use serde::Deserialize;
 use serde_json::json;
-use tracing::info;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tracing::{info, warn};
+use uuid::Uuid;
 
 // Local crate imports
+use crate::admin::Admin;
+use crate::attestation::Attestation;
+use crate::batch::{anchor_coins, receipt_json, EconomyWrite, Tier};
+use crate::config::{Config, DescriptorKind};
+use crate::db::{self, OpReturnFilter};
+use crate::encryption::{decrypt_tx, encrypt, ENCRYPTION_OVERHEAD, MAX_ENCRYPTED_OP_RETURN};
+use crate::envelope::{
+    build_commit_reveal, estimate_envelope_weight, extract_envelope, MAX_ENVELOPE_SIZE,
+};
 use crate::error::{Graffiti, Report};
+use crate::events::{EventDetail, EventKind};
+use crate::fanout::pick_pool_coin;
+use crate::indexer::IndexedOpReturn;
+use crate::monitor::ensure_funded;
+use crate::multisig::PendingWrite;
+use crate::queue::{blocked_coins, PreparedWrite, QueuedWrite, QueuedWrites, MAX_QUEUED_WRITES};
+use crate::sandbox::{Sandbox, ELECTRS_TIMEOUT, FUNDING_AMOUNT};
+use crate::schedule::{ScheduleStatus, ScheduledWrite};
+use crate::stream::GraffitiRecord;
+use crate::tracker::WriteKind;
+use crate::treasury::{
+    build_consolidation, build_excess_sweep, build_full_sweep, TreasuryTx,
+    DEFAULT_CONSOLIDATION_BELOW,
+};
 use crate::util::GrafittiState;
+use crate::webhooks::{resolve_public, Delivery, Webhook};
 use crate::{
     error,
-    util::{get_electrum_client, get_tx_details, sync_electrum, NETWORK},
-    EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR,
+    util::{
+        affordable_writes, bip21_uri, build_sweep, estimate_write_weight, funded_by_wallet,
+        get_tx_details, insert_broadcast, op_return_payload, FEE_TARGET, NETWORK,
+    },
 };
 
+/// Status of the chain backend and the tip of the wallet. Answers 503 when no Electrum server
+/// is healthy, requests then go to degraded ones.
+pub async fn health(State(gs): State<GrafittiState>) -> error::Result<impl IntoResponse> {
+    // Only cached state, so health checks answer while a scan holds the wallet.
+    let mut j = gs.blockchain.status();
+    let healthy = j["healthy"].as_bool().unwrap_or(false);
+    j["wallet_tip"] = json!(gs.wallet_tip.load(Ordering::Relaxed));
+    j["funding"] = gs.runway.lock().await.to_json();
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok((status, Json(j)))
+}
+
+/// Funding metrics of the balance monitor in the Prometheus text format.
+pub async fn metrics(State(gs): State<GrafittiState>) -> error::Result<impl IntoResponse> {
+    let body = gs.runway.lock().await.metrics();
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        body,
+    ))
+}
+
 pub async fn get_op_return(State(gs): State<GrafittiState>) -> error::Result<impl IntoResponse> {
     info!("Received READ request for op return transactions");
-    let mut wallet = Wallet::new(EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR, NETWORK)?;
-    let client = gs.blockchain.lock().await;
-
-    sync_electrum(client, &mut wallet)
-        .await
-        .map_err(|e| Report::from(Graffiti::Anyhow(e)))?;
+    let wallet = gs.wallet.lock().await;
 
     let transactions = get_tx_details(&wallet).unwrap();
 
@@ -31,43 +102,1707 @@ pub async fn get_op_return(State(gs): State<GrafittiState>) -> error::Result<imp
     Ok(Json(j))
 }
 
+/// Options shared by the write endpoints, passed as query parameters.
+#[derive(Deserialize, Default)]
+pub struct WriteOptions {
+    /// Hex encoded public key to encrypt the payload to.
+    encrypt_to: Option<String>,
+    /// Sign the payload with the service attestation key.
+    #[serde(default)]
+    attest: bool,
+    #[serde(default)]
+    tier: Tier,
+    /// Unix time by which an economy write goes out, whatever the fee rate.
+    deadline: Option<i64>,
+    /// Block height the write is held back until.
+    not_before_height: Option<u32>,
+    /// Unix time the write is held back until.
+    not_before_time: Option<i64>,
+    /// Overrides `GRAFFITI_DEDUP`, `false` forces a new write of a payload already written.
+    /// Only plaintext priority `OP_RETURN` writes are deduplicated. Encrypted, envelope,
+    /// economy and scheduled writes ignore it.
+    dedup: Option<bool>,
+}
+
+impl WriteOptions {
+    /// The bytes to put on chain, encrypted if a recipient was given.
+    fn payload(&self, data: &[u8]) -> error::Result<Vec<u8>> {
+        let Some(recipient) = &self.encrypt_to else {
+            return Ok(data.to_vec());
+        };
+        let recipient = PublicKey::from_str(recipient)
+            .map_err(|e| Graffiti::BadRequest(format!("invalid recipient public key: {e}")))?;
+        Ok(encrypt(&recipient, data)?)
+    }
+
+    /// Whether to look for an earlier write of the payload, `default` unless the request says.
+    /// Every encryption uses a fresh key, so an encrypted payload never matches and isn't
+    /// looked up.
+    pub(crate) fn dedup(&self, default: bool) -> bool {
+        self.encrypt_to.is_none() && self.dedup.unwrap_or(default)
+    }
+
+    fn attestation(&self, config: &Config, payload: &[u8]) -> error::Result<Option<Attestation>> {
+        if !self.attest {
+            return Ok(None);
+        }
+        let keypair = config
+            .attestation_key
+            .as_ref()
+            .ok_or_else(|| Graffiti::BadRequest("no attestation key is configured".to_string()))?;
+        Ok(Some(Attestation::sign(keypair, payload)))
+    }
+}
+
+/// Refuses a plaintext that would outgrow the data carrier limit once encrypted, before any
+/// fee is spent on it.
+///
+/// # Errors
+///
+/// Will return a bad request if `plaintext_len` bytes don't fit in an `OP_RETURN` once
+/// encrypted
+pub(crate) fn check_encrypted_size(plaintext_len: usize) -> error::Result<()> {
+    if plaintext_len > MAX_ENCRYPTED_OP_RETURN {
+        return Err(Report::from(Graffiti::BadRequest(format!(
+            "encryption adds {ENCRYPTION_OVERHEAD} bytes, so at most {MAX_ENCRYPTED_OP_RETURN} \
+             bytes fit in an OP_RETURN, use /write_envelope for {plaintext_len}"
+        ))));
+    }
+    Ok(())
+}
+
+async fn save_attestation(
+    gs: &GrafittiState,
+    txid: Txid,
+    payload: &[u8],
+    attestation: Option<&Attestation>,
+    on_chain: bool,
+) -> error::Result<()> {
+    if let Some(attestation) = attestation {
+        db::save_attestation(&gs.db, txid, payload, attestation, on_chain).await?;
+    }
+    Ok(())
+}
+
+/// Adds a broadcast write to the history so the tracker follows it.
+pub(crate) async fn record_write(
+    gs: &GrafittiState,
+    tx: &Transaction,
+    kind: WriteKind,
+    payload: &[u8],
+    fee: Amount,
+) -> error::Result<()> {
+    db::insert_write(&gs.db, tx, kind, payload, fee).await?;
+    gs.events
+        .emit(
+            tx.compute_txid(),
+            EventKind::Broadcast,
+            EventDetail::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// What became of a write handed to [`send_write`].
+#[derive(Debug)]
+pub enum WriteOutcome {
+    Sent(Txid, serde_json::Value),
+    /// Waiting for cosigner signatures in the pending writes.
+    Pending(Txid, serde_json::Value),
+    /// Every coin that could pay for the write is at the mempool ancestor or descendant
+    /// limits.
+    Blocked(String),
+}
+
 pub async fn write_op_return(
     State(gs): State<GrafittiState>,
     Path(data): Path<String>,
+    Query(options): Query<WriteOptions>,
 ) -> error::Result<impl IntoResponse> {
     info!("Received WRITE request with data: {}", &data);
-    let client = gs.blockchain.lock().await;
-    // let db = ss.bdk_pool.lock().await.clone();
+    // Economy writes only put the batch root on chain, any size goes for them.
+    if options.encrypt_to.is_some() && options.tier != Tier::Economy {
+        check_encrypted_size(data.len())?;
+    }
+    let payload = options.payload(data.as_bytes())?;
+    if options.not_before_height.is_some() || options.not_before_time.is_some() {
+        return schedule_write(&gs, data, payload, &options).await;
+    }
+    if options.tier == Tier::Economy {
+        return queue_economy_write(&gs, data, payload, &options).await;
+    }
+    if options.dedup(gs.config.dedup) {
+        if let Some((status, j)) = find_duplicate(&gs, &payload, options.attest).await? {
+            return Ok((status, Json(j)));
+        }
+    }
+    let attestation = options.attestation(&gs.config, &payload)?;
+    let on_chain = attestation.is_some() && Attestation::fits_on_chain(&payload);
+    let write = PreparedWrite {
+        data,
+        payload,
+        attestation,
+        on_chain,
+        fee_target: FEE_TARGET,
+        replaceable: false,
+        envelope: false,
+    };
+    send_or_queue(&gs, write).await
+}
+
+/// Sends `write` right away, or queues it behind the writes already queued or when the
+/// mempool limits hold it back.
+async fn send_or_queue(
+    gs: &GrafittiState,
+    write: PreparedWrite,
+) -> error::Result<(StatusCode, Json<serde_json::Value>)> {
+    // Writes already queued go first, so this one can't overtake them.
+    let mut queue = gs.queue.lock().await;
+    if let Some(front) = queue.front() {
+        return enqueue(gs, &mut queue, write, front.reason).await;
+    }
+    drop(queue);
 
-    let mut wallet = Wallet::new(EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR, NETWORK)?;
-    sync_electrum(client, &mut wallet)
+    match send_write(gs, write.clone()).await? {
+        WriteOutcome::Sent(_, j) => Ok((StatusCode::OK, Json(j))),
+        WriteOutcome::Pending(_, j) => Ok((StatusCode::ACCEPTED, Json(j))),
+        WriteOutcome::Blocked(reason) => {
+            info!("queueing write: {reason}");
+            let mut queue = gs.queue.lock().await;
+            enqueue(gs, &mut queue, write, reason).await
+        }
+    }
+}
+
+/// Stores `write` and puts it at the end of the queue, refusing it when the queue is full.
+async fn enqueue(
+    gs: &GrafittiState,
+    queue: &mut QueuedWrites,
+    write: PreparedWrite,
+    reason: String,
+) -> error::Result<(StatusCode, Json<serde_json::Value>)> {
+    if queue.is_full() {
+        return Err(Report::from(Graffiti::Unavailable(format!(
+            "{MAX_QUEUED_WRITES} writes are queued already, try again once they went out"
+        ))));
+    }
+    let queued = QueuedWrite::new(write, reason);
+    db::insert_queued_write(&gs.db, &queued).await?;
+    let (id, reason) = (queued.id, queued.reason.clone());
+    let position = queue.push(queued);
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(queued_json(id, position, &reason)),
+    ))
+}
+
+/// An earlier write of `payload` to answer with instead of writing it again. When an
+/// attestation is asked for, only an attested write counts.
+async fn find_duplicate(
+    gs: &GrafittiState,
+    payload: &[u8],
+    attest: bool,
+) -> error::Result<Option<(StatusCode, serde_json::Value)>> {
+    // Writes not broadcast yet count too, a repeated request would send the payload again.
+    if let Some(j) = gs.queue.lock().await.find_payload(payload, attest) {
+        info!("payload is already queued in {}", j["id"]);
+        return Ok(Some((StatusCode::ACCEPTED, j)));
+    }
+    let pending: Vec<(Txid, serde_json::Value)> = gs
+        .pending
+        .lock()
         .await
-        .map_err(|e| Report::from(Graffiti::Anyhow(e)))?;
+        .values()
+        .filter(|write| write.payload().as_deref() == Some(payload))
+        .map(|write| (write.txid(), write.to_json()))
+        .collect();
+    for (txid, mut j) in pending {
+        if db::get_attestation(&gs.db, txid).await?.is_some() || !attest {
+            info!("payload is already waiting for cosigners in {}", j["id"]);
+            j["duplicate"] = json!(true);
+            return Ok(Some((StatusCode::ACCEPTED, j)));
+        }
+    }
 
-    let address = wallet.next_unused_address(KeychainKind::External);
+    let Some(txid) = db::find_duplicate_write(&gs.db, payload).await? else {
+        return Ok(None);
+    };
+    let attested = db::get_attestation(&gs.db, txid).await?.is_some();
+    let answer = duplicate_json(txid, attested, attest);
+    if answer.is_some() {
+        info!("payload was already written in {}", txid);
+    }
+    Ok(answer.map(|j| (StatusCode::OK, j)))
+}
 
-    info!(
-        "Deposit sats to this address in case the wallet is dry: {}",
-        address
+/// The answer to a payload already written in `txid`, `None` when `attest` asks for an
+/// attestation that `txid` doesn't have.
+pub(crate) fn duplicate_json(
+    txid: Txid,
+    attested: bool,
+    attest: bool,
+) -> Option<serde_json::Value> {
+    (attested || !attest).then(|| {
+        json!({
+            "txid": txid,
+            "attested": attested,
+            "duplicate": true,
+        })
+    })
+}
+
+/// Stores a write to be sent once the chain reaches `not_before_height` and the clock
+/// `not_before_time`.
+async fn schedule_write(
+    gs: &GrafittiState,
+    data: String,
+    payload: Vec<u8>,
+    options: &WriteOptions,
+) -> error::Result<(StatusCode, Json<serde_json::Value>)> {
+    if options.tier == Tier::Economy {
+        return Err(Report::from(Graffiti::BadRequest(
+            "economy writes can't be scheduled".to_string(),
+        )));
+    }
+    let not_before_time = options
+        .not_before_time
+        .map(|timestamp| {
+            chrono::DateTime::from_timestamp(timestamp, 0)
+                .ok_or_else(|| Graffiti::BadRequest(format!("invalid not_before_time {timestamp}")))
+        })
+        .transpose()?;
+    // Checks the attestation key now rather than when the write is due.
+    let attest = options.attestation(&gs.config, &payload)?.is_some();
+
+    let write = ScheduledWrite::new(
+        data,
+        payload,
+        attest,
+        options.not_before_height,
+        not_before_time,
     );
+    db::insert_scheduled_write(&gs.db, &write).await?;
+    info!("scheduled write {}", write.id);
+    Ok((StatusCode::ACCEPTED, Json(write.to_json())))
+}
+
+#[derive(Deserialize)]
+pub struct ScheduledQuery {
+    /// `scheduled`, `sent`, `failed` or `cancelled`.
+    status: Option<String>,
+    limit: Option<u32>,
+}
+
+/// Scheduled writes, newest first.
+pub async fn list_scheduled_writes(
+    State(gs): State<GrafittiState>,
+    Query(query): Query<ScheduledQuery>,
+) -> error::Result<impl IntoResponse> {
+    let status = query
+        .status
+        .as_deref()
+        .map(ScheduleStatus::from_str)
+        .transpose()
+        .map_err(|e| Graffiti::BadRequest(e.to_string()))?;
+    let limit = query.limit.unwrap_or(100).min(MAX_INDEX_LIMIT);
+    let writes = db::list_scheduled_writes(&gs.db, status, limit).await?;
+    let writes: Vec<_> = writes.iter().map(ScheduledWrite::to_json).collect();
+    Ok(Json(json!({ "scheduled_writes": writes })))
+}
+
+pub async fn get_scheduled_write(
+    State(gs): State<GrafittiState>,
+    Path(id): Path<Uuid>,
+) -> error::Result<impl IntoResponse> {
+    let write = db::get_scheduled_write(&gs.db, id)
+        .await?
+        .ok_or_else(|| Graffiti::NotFound(format!("scheduled write {id}")))?;
+    Ok(Json(write.to_json()))
+}
+
+/// Cancels a scheduled write that hasn't been sent yet.
+pub async fn cancel_scheduled_write(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Path(id): Path<Uuid>,
+) -> error::Result<impl IntoResponse> {
+    let cancelled = db::cancel_scheduled_write(&gs.db, id).await?;
+    let write = db::get_scheduled_write(&gs.db, id)
+        .await?
+        .ok_or_else(|| Graffiti::NotFound(format!("scheduled write {id}")))?;
+    if !cancelled {
+        return Err(Report::from(Graffiti::BadRequest(format!(
+            "scheduled write {id} is {}, only scheduled writes can be cancelled",
+            write.status
+        ))));
+    }
+    info!("cancelled scheduled write {}", id);
+    Ok(Json(write.to_json()))
+}
+
+/// Queues an economy write for the next batch, with a deadline no later than
+/// `GRAFFITI_ECONOMY_MAX_DELAY` from now.
+async fn queue_economy_write(
+    gs: &GrafittiState,
+    data: String,
+    payload: Vec<u8>,
+    options: &WriteOptions,
+) -> error::Result<(StatusCode, Json<serde_json::Value>)> {
+    if options.attest {
+        return Err(Report::from(Graffiti::BadRequest(
+            "economy writes can't be attested".to_string(),
+        )));
+    }
+    let economy = gs.config.economy;
+    let latest = chrono::Utc::now() + chrono::Duration::from_std(economy.max_delay)?;
+    let deadline = match options.deadline {
+        Some(timestamp) => chrono::DateTime::from_timestamp(timestamp, 0)
+            .filter(|deadline| *deadline <= latest)
+            .ok_or_else(|| {
+                Graffiti::BadRequest(format!(
+                    "deadline must be a unix time no later than {}",
+                    latest.timestamp()
+                ))
+            })?,
+        None => latest,
+    };
+
+    let write = EconomyWrite::new(data, payload, deadline);
+    db::insert_economy_write(&gs.db, &write).await?;
+    info!("queued economy write {}", write.id);
+    gs.economy.trigger.notify_one();
+    Ok((StatusCode::ACCEPTED, Json(economy_json(gs, &write).await?)))
+}
 
-    let mut tx_builder = wallet.build_tx();
+/// An economy write with its position and dispatch conditions while queued, and its receipt
+/// once anchored.
+async fn economy_json(
+    gs: &GrafittiState,
+    write: &EconomyWrite,
+) -> error::Result<serde_json::Value> {
+    if let Some((txid, _)) = write.batch {
+        let batch = db::batch_writes(&gs.db, txid).await?;
+        return Ok(receipt_json(write, &batch));
+    }
+    let mut j = write.to_json();
+    j["position"] = json!(db::economy_position(&gs.db, write).await?);
+    j["dispatch"] = gs
+        .economy
+        .status
+        .lock()
+        .await
+        .dispatch_json(&gs.config.economy, write);
+    Ok(j)
+}
 
-    let push_bytes = bdk_wallet::bitcoin::script::PushBytesBuf::try_from(data.into_bytes())?;
-    tx_builder.add_data(&push_bytes);
+/// Most queued economy writes listed at once.
+const MAX_LISTED_ECONOMY_WRITES: u32 = 1_000;
 
-    let mut psbt = tx_builder.finish()?;
-    let finalized = wallet.sign(&mut psbt, SignOptions::default())?;
+pub async fn list_economy_writes(
+    State(gs): State<GrafittiState>,
+) -> error::Result<impl IntoResponse> {
+    let writes = db::queued_economy_writes(&gs.db, MAX_LISTED_ECONOMY_WRITES).await?;
+    let status = gs.economy.status.lock().await.clone();
+    let writes: Vec<_> = writes
+        .iter()
+        .enumerate()
+        .map(|(position, write)| {
+            let mut j = write.to_json();
+            j["position"] = json!(position + 1);
+            j["dispatch"] = status.dispatch_json(&gs.config.economy, write);
+            j
+        })
+        .collect();
+    Ok(Json(json!({ "economy_writes": writes })))
+}
 
-    assert!(finalized);
+pub async fn get_economy_write(
+    State(gs): State<GrafittiState>,
+    Path(id): Path<Uuid>,
+) -> error::Result<impl IntoResponse> {
+    let write = db::get_economy_write(&gs.db, id)
+        .await?
+        .ok_or_else(|| Graffiti::NotFound(format!("economy write {id}")))?;
+    Ok(Json(economy_json(&gs, &write).await?))
+}
+
+fn queued_json(id: Uuid, position: usize, reason: &str) -> serde_json::Value {
+    json!({
+        "id": id,
+        "status": "queued",
+        "position": position,
+        "reason": reason,
+    })
+}
+
+/// Sends a write through an envelope or an `OP_RETURN`, whichever it was prepared for.
+///
+/// # Errors
+///
+/// Will return errors if the write can't be built, signed or broadcast
+pub(crate) async fn send_write(
+    gs: &GrafittiState,
+    write: PreparedWrite,
+) -> error::Result<WriteOutcome> {
+    if write.envelope {
+        send_envelope(gs, write).await
+    } else {
+        send_op_return(gs, write).await
+    }
+}
+
+/// Builds, signs and broadcasts an `OP_RETURN` write, unless the wallet's coins are all too
+/// deep in unconfirmed chains for the mempool to accept it.
+///
+/// # Errors
+///
+/// Will return errors if the wallet is unfunded or the transaction can't be built, signed or
+/// broadcast
+pub(crate) async fn send_op_return(
+    gs: &GrafittiState,
+    write: PreparedWrite,
+) -> error::Result<WriteOutcome> {
+    let PreparedWrite {
+        data,
+        payload,
+        attestation,
+        on_chain,
+        fee_target,
+        replaceable,
+        ..
+    } = write;
+    let fee_rate = gs.blockchain.estimate_fee_rate(fee_target).await?;
+
+    let mut wallet = gs.wallet.lock().await;
+    let kind = gs.config.descriptor_kind();
+    ensure_funded(
+        &wallet,
+        estimate_write_weight(kind, payload.len()),
+        fee_rate,
+    )?;
+
+    // Coins already committed to writes that are waiting for cosigners or to an anchor.
+    let mut reserved = reserved_outpoints(gs, &wallet).await?;
+
+    // Coins whose unconfirmed ancestry is at the mempool limits would get the write rejected.
+    let blocked = blocked_coins(&wallet);
+    if let Some((_, reason)) = blocked.first() {
+        let reason = reason.clone();
+        reserved.extend(blocked.into_iter().map(|(outpoint, _)| outpoint));
+        let usable: Amount = wallet
+            .list_unspent()
+            .filter(|utxo| !reserved.contains(&utxo.outpoint))
+            .map(|utxo| utxo.txout.value)
+            .sum();
+        if affordable_writes(kind, payload.len(), usable, fee_rate) == 0 {
+            return Ok(WriteOutcome::Blocked(reason));
+        }
+    }
+
+    // Spending a coin of the pool lets concurrent writes go out in parallel.
+    let pool_coin = gs.config.fan_out.as_ref().and_then(|fan_out| {
+        let fee = fee_rate
+            .fee_wu(estimate_write_weight(kind, payload.len()))
+            .unwrap_or(Amount::MAX_MONEY);
+        pick_pool_coin(&wallet, fan_out, &reserved, fee)
+    });
+
+    let push_bytes = bdk_wallet::bitcoin::script::PushBytesBuf::try_from(payload.clone())?;
+    let attestation_script = match attestation.as_ref().filter(|_| on_chain) {
+        Some(attestation) => Some(attestation.script(&payload)?),
+        None => None,
+    };
+    let build = |wallet: &mut Wallet, pool_coin: Option<OutPoint>| -> error::Result<Psbt> {
+        let mut tx_builder = wallet.build_tx();
+        if let Some(outpoint) = pool_coin {
+            // The pool coin alone, a second coin would tie the write to its ancestry.
+            tx_builder.add_utxo(outpoint)?.manually_selected_only();
+        }
+        match &attestation_script {
+            Some(script) => {
+                tx_builder.add_recipient(script.clone(), Amount::ZERO);
+            }
+            None => {
+                tx_builder.add_data(&push_bytes);
+            }
+        }
+        tx_builder.fee_rate(fee_rate).unspendable(reserved.clone());
+        if replaceable {
+            tx_builder.enable_rbf();
+        }
+        Ok(tx_builder.finish()?)
+    };
+
+    let mut psbt = match pool_coin {
+        Some(outpoint) => match build(&mut wallet, Some(outpoint)) {
+            Ok(psbt) => psbt,
+            // The estimate picked a coin that can't pay the actual fee, e.g. of an attestation.
+            Err(e) => {
+                info!("pool coin {outpoint} can't fund the write, selecting coins instead: {e}");
+                build(&mut wallet, None)?
+            }
+        },
+        None => build(&mut wallet, None)?,
+    };
+    let finalized = wallet.sign(&mut psbt, kind.sign_options())?;
+
+    if !finalized {
+        let Some(threshold) = kind.threshold() else {
+            return Err(Report::from(Graffiti::Anyhow(anyhow::anyhow!(
+                "wallet could not finalize the transaction"
+            ))));
+        };
+        let txid = psbt.unsigned_tx.compute_txid();
+        save_attestation(gs, txid, &payload, attestation.as_ref(), on_chain).await?;
+        let pending = PendingWrite::new(data, psbt, threshold);
+        info!(
+            "write {} is waiting for cosigners, {}/{} signatures",
+            pending.id,
+            pending.signatures(),
+            threshold
+        );
+        let j = pending.to_json();
+        db::save_pending_write(&gs.db, &pending).await?;
+        gs.pending.lock().await.insert(pending.id, pending);
+        return Ok(WriteOutcome::Pending(txid, j));
+    }
 
     let tx = psbt.extract_tx()?;
+    let fee = wallet.calculate_fee(&tx)?;
+
+    gs.blockchain.broadcast(&tx).await?;
+    insert_broadcast(&mut wallet, &[&tx]);
+    drop(wallet);
+
+    let txid = tx.compute_txid();
+    record_write(gs, &tx, WriteKind::OpReturn, &payload, fee).await?;
+    save_attestation(gs, txid, &payload, attestation.as_ref(), on_chain).await?;
+    Ok(WriteOutcome::Sent(
+        txid,
+        json!({
+            "txid": txid,
+            "attested": attestation.is_some(),
+            "attestation_on_chain": on_chain,
+            "duplicate": false,
+        }),
+    ))
+}
+
+/// Writes the request body through a taproot commit/reveal pair, for payloads too large for
+/// `OP_RETURN`.
+pub async fn write_envelope(
+    State(gs): State<GrafittiState>,
+    Query(options): Query<WriteOptions>,
+    body: Bytes,
+) -> error::Result<impl IntoResponse> {
+    info!("Received ENVELOPE WRITE request with {} bytes", body.len());
+    let payload = options.payload(&body)?;
+    let attestation = options.attestation(&gs.config, &payload)?;
+    if body.is_empty() || payload.len() > MAX_ENVELOPE_SIZE {
+        return Err(Report::from(Graffiti::BadRequest(format!(
+            "envelope payloads must be between 1 and {MAX_ENVELOPE_SIZE} bytes"
+        ))));
+    }
+    if gs.config.descriptor_kind().threshold().is_some() {
+        return Err(Report::from(Graffiti::BadRequest(
+            "envelope writes are not available for multisig wallets".to_string(),
+        )));
+    }
+    let write = PreparedWrite {
+        data: format!("envelope of {} bytes", payload.len()),
+        payload,
+        attestation,
+        on_chain: false,
+        fee_target: FEE_TARGET,
+        replaceable: false,
+        envelope: true,
+    };
+    send_or_queue(&gs, write).await
+}
+
+/// Builds, signs and broadcasts the commit and reveal of an envelope write, unless the
+/// wallet's coins are all too deep in unconfirmed chains for the mempool to accept it.
+async fn send_envelope(gs: &GrafittiState, write: PreparedWrite) -> error::Result<WriteOutcome> {
+    let PreparedWrite {
+        payload,
+        attestation,
+        fee_target,
+        ..
+    } = write;
+    let kind = gs.config.descriptor_kind();
+    let fee_rate = gs.blockchain.estimate_fee_rate(fee_target).await?;
+
+    let mut wallet = gs.wallet.lock().await;
+    let weight = estimate_envelope_weight(kind, payload.len());
+    ensure_funded(&wallet, weight, fee_rate)?;
+
+    // Coins whose unconfirmed ancestry is at the mempool limits would get the commit rejected.
+    let mut reserved = reserved_outpoints(gs, &wallet).await?;
+    let blocked = blocked_coins(&wallet);
+    reserved.extend(blocked.iter().map(|(outpoint, _)| *outpoint));
+    if let Some((_, reason)) = blocked.first() {
+        let usable: Amount = wallet
+            .list_unspent()
+            .filter(|utxo| !reserved.contains(&utxo.outpoint))
+            .map(|utxo| utxo.txout.value)
+            .sum();
+        if fee_rate.fee_wu(weight).map_or(true, |fee| usable < fee) {
+            return Ok(WriteOutcome::Blocked(reason.clone()));
+        }
+    }
+
+    let (commit, reveal) = build_commit_reveal(
+        &mut wallet,
+        &payload,
+        fee_rate,
+        &reserved,
+        kind.sign_options(),
+    )?;
+    let commit_fee = wallet.calculate_fee(&commit)?;
+    let envelope_value = commit.output[reveal.input[0].previous_output.vout as usize].value;
+    let reveal_fee = envelope_value
+        - reveal
+            .output
+            .iter()
+            .map(|output| output.value)
+            .sum::<Amount>();
+
+    // Both halves are stored before anything goes out. Should the reveal not make it after
+    // its commit did, the tracker rebroadcasts it like any write that left the mempool.
+    db::insert_write(&gs.db, &commit, WriteKind::Commit, &[], commit_fee).await?;
+    db::insert_write(&gs.db, &reveal, WriteKind::Reveal, &payload, reveal_fee).await?;
+    if let Err(e) = gs.blockchain.broadcast(&commit).await {
+        for tx in [&commit, &reveal] {
+            db::set_write_status(&gs.db, tx.compute_txid(), "failed").await?;
+        }
+        return Err(Report::from(e));
+    }
+    let revealed = gs.blockchain.broadcast(&reveal).await;
+    insert_broadcast(&mut wallet, &[&commit, &reveal]);
+    drop(wallet);
+
+    let mut broadcast = vec![commit.compute_txid()];
+    match revealed {
+        Ok(()) => broadcast.push(reveal.compute_txid()),
+        Err(e) => warn!(
+            "reveal {} was not broadcast, leaving it to the tracker: {e}",
+            reveal.compute_txid()
+        ),
+    }
+    for txid in broadcast {
+        gs.events
+            .emit(txid, EventKind::Broadcast, EventDetail::default())
+            .await?;
+    }
+    save_attestation(
+        gs,
+        reveal.compute_txid(),
+        &payload,
+        attestation.as_ref(),
+        false,
+    )
+    .await?;
+
+    let j = json!({
+        "commit_txid": commit.compute_txid(),
+        "reveal_txid": reveal.compute_txid(),
+        "size": payload.len(),
+    });
+    Ok(WriteOutcome::Sent(reveal.compute_txid(), j))
+}
+
+#[derive(Deserialize)]
+pub struct DecryptRequest {
+    txid: Txid,
+    /// Hex encoded secret key of the recipient.
+    secret_key: String,
+}
+
+/// Recovers an encrypted payload. The secret key is only used for this request and never
+/// stored; clients that can should call [`crate::encryption::decrypt_tx`] locally instead.
+pub async fn decrypt_payload(
+    State(gs): State<GrafittiState>,
+    Json(body): Json<DecryptRequest>,
+) -> error::Result<impl IntoResponse> {
+    info!("Received DECRYPT request for {}", body.txid);
+    let secret = SecretKey::from_str(&body.secret_key)
+        .map_err(|e| Graffiti::BadRequest(format!("invalid secret key: {e}")))?;
+
+    let tx = gs
+        .blockchain
+        .get_tx(body.txid)
+        .await?
+        .ok_or_else(|| Graffiti::NotFound(format!("transaction {}", body.txid)))?;
+
+    let plaintext = decrypt_tx(&secret, &tx).map_err(|e| Graffiti::BadRequest(e.to_string()))?;
+
+    let j = json!({
+        "txid": body.txid,
+        "data": String::from_utf8(plaintext.clone()).ok(),
+        "hex": plaintext.to_lower_hex_string(),
+    });
+
+    Ok(Json(j))
+}
+
+/// Checks that a transaction was written by this deployment: its inputs must be funded by our
+/// wallet and it must carry a valid attestation by our service key.
+pub async fn verify(
+    State(gs): State<GrafittiState>,
+    Path(txid): Path<Txid>,
+) -> error::Result<impl IntoResponse> {
+    info!("Received VERIFY request for {}", txid);
+    let pubkey = gs
+        .config
+        .attestation_key
+        .as_ref()
+        .ok_or_else(|| Graffiti::BadRequest("no attestation key is configured".to_string()))?
+        .x_only_public_key()
+        .0;
+
+    let tx = gs
+        .blockchain
+        .get_tx(txid)
+        .await?
+        .ok_or_else(|| Graffiti::NotFound(format!("transaction {txid}")))?;
+
+    let funded = funded_by_wallet(&*gs.wallet.lock().await, &tx);
+
+    let (source, found) = match Attestation::from_tx(&tx, pubkey) {
+        Some(found) => ("on_chain", Some(found)),
+        None => ("off_chain", db::get_attestation(&gs.db, txid).await?),
+    };
+    let attestation = found.map(|(payload, attestation)| {
+        // An off-chain attestation only counts if it signs what is actually in the transaction.
+        let in_tx = tx
+            .output
+            .iter()
+            .filter_map(|output| op_return_payload(&output.script_pubkey))
+            .chain(extract_envelope(&tx))
+            .any(|written| written == payload);
+        let valid = in_tx && attestation.pubkey == pubkey && attestation.verify(&payload);
+        let mut j = attestation.to_json();
+        j["source"] = json!(source);
+        j["valid"] = json!(valid);
+        j
+    });
+    let attested = attestation
+        .as_ref()
+        .is_some_and(|attestation| attestation["valid"] == json!(true));
+
+    let j = json!({
+        "txid": txid,
+        "verified": attested && funded,
+        "funded_by_wallet": funded,
+        "attestation": attestation,
+    });
+
+    Ok(Json(j))
+}
+
+/// Largest page the index endpoints return.
+const MAX_INDEX_LIMIT: u32 = 1_000;
+
+#[derive(Deserialize)]
+pub struct IndexQuery {
+    /// Hex encoded payload prefix.
+    prefix: Option<String>,
+    from_height: Option<u32>,
+    to_height: Option<u32>,
+    limit: Option<u32>,
+}
+
+/// Searches every `OP_RETURN` on chain by payload prefix and height range.
+pub async fn search_index(
+    State(gs): State<GrafittiState>,
+    Query(query): Query<IndexQuery>,
+) -> error::Result<impl IntoResponse> {
+    let prefix = query
+        .prefix
+        .map(|prefix| Vec::<u8>::from_hex(&prefix))
+        .transpose()
+        .map_err(|e| Graffiti::BadRequest(format!("prefix must be hex: {e}")))?;
+    let filter = OpReturnFilter {
+        prefix,
+        from_height: query.from_height,
+        to_height: query.to_height,
+        txid: None,
+        limit: query.limit.unwrap_or(100).min(MAX_INDEX_LIMIT),
+    };
+
+    let op_returns = db::find_op_returns(&gs.db, &filter).await?;
+    let op_returns: Vec<_> = op_returns.iter().map(IndexedOpReturn::to_json).collect();
+    let j = json!({ "op_returns": op_returns });
+
+    Ok(Json(j))
+}
+
+pub async fn get_indexed_tx(
+    State(gs): State<GrafittiState>,
+    Path(txid): Path<Txid>,
+) -> error::Result<impl IntoResponse> {
+    let filter = OpReturnFilter {
+        txid: Some(txid),
+        limit: MAX_INDEX_LIMIT,
+        ..OpReturnFilter::default()
+    };
+    let op_returns = db::find_op_returns(&gs.db, &filter).await?;
+    if op_returns.is_empty() {
+        return Err(Report::from(Graffiti::NotFound(format!(
+            "indexed transaction {txid}"
+        ))));
+    }
+    let op_returns: Vec<_> = op_returns.iter().map(IndexedOpReturn::to_json).collect();
+    let j = json!({ "txid": txid, "op_returns": op_returns });
+
+    Ok(Json(j))
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    limit: Option<u32>,
+}
+
+/// Write history, newest first.
+pub async fn list_writes(
+    State(gs): State<GrafittiState>,
+    Query(query): Query<HistoryQuery>,
+) -> error::Result<impl IntoResponse> {
+    let limit = query.limit.unwrap_or(100).min(MAX_INDEX_LIMIT);
+    let writes = db::list_writes(&gs.db, limit).await?;
+    let writes: Vec<_> = writes.iter().map(|write| write.to_json(&[])).collect();
+    let j = json!({ "writes": writes });
+
+    Ok(Json(j))
+}
+
+/// One write with its confirmations, including ones lost to reorgs, and its events.
+pub async fn get_write(
+    State(gs): State<GrafittiState>,
+    Path(txid): Path<Txid>,
+) -> error::Result<impl IntoResponse> {
+    let write = db::get_write(&gs.db, txid)
+        .await?
+        .ok_or_else(|| Graffiti::NotFound(format!("write {txid}")))?;
+    let events = db::write_events(&gs.db, txid).await?;
+
+    Ok(Json(write.to_json(&events)))
+}
+
+pub async fn list_pending_writes(
+    State(gs): State<GrafittiState>,
+) -> error::Result<impl IntoResponse> {
+    let pending = gs.pending.lock().await;
+    let mut writes: Vec<&PendingWrite> = pending.values().collect();
+    writes.sort_by_key(|write| write.created_at);
+
+    let writes: Vec<_> = writes.into_iter().map(PendingWrite::to_json).collect();
+    let j = json!({ "pending_writes": writes });
+
+    Ok(Json(j))
+}
+
+pub async fn list_queued_writes(
+    State(gs): State<GrafittiState>,
+) -> error::Result<impl IntoResponse> {
+    let writes = gs.queue.lock().await.to_json();
+    Ok(Json(json!({ "queued_writes": writes })))
+}
+
+pub async fn get_queued_write(
+    State(gs): State<GrafittiState>,
+    Path(id): Path<Uuid>,
+) -> error::Result<impl IntoResponse> {
+    if let Some(write) = gs.queue.lock().await.get(id) {
+        return Ok(Json(write));
+    }
+    let finished = db::finished_queued_write(&gs.db, id)
+        .await?
+        .ok_or_else(|| Graffiti::NotFound(format!("queued write {id}")))?;
+    Ok(Json(finished.to_json(id)))
+}
+
+pub async fn get_pending_write(
+    State(gs): State<GrafittiState>,
+    Path(id): Path<Uuid>,
+) -> error::Result<impl IntoResponse> {
+    let pending = gs.pending.lock().await;
+    let write = pending
+        .get(&id)
+        .ok_or_else(|| Graffiti::NotFound(format!("pending write {id}")))?;
+
+    Ok(Json(write.to_json()))
+}
+
+#[derive(Deserialize)]
+pub struct SignedPsbt {
+    psbt: String,
+}
+
+/// Takes a PSBT signed by an external cosigner and broadcasts the write once the threshold is
+/// met.
+pub async fn submit_signatures(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Path(id): Path<Uuid>,
+    Json(body): Json<SignedPsbt>,
+) -> error::Result<impl IntoResponse> {
+    info!("Received signatures for pending write {}", id);
+    let psbt = Psbt::from_str(&body.psbt)
+        .map_err(|e| Graffiti::BadRequest(format!("invalid PSBT: {e}")))?;
+
+    let mut wallet = gs.wallet.lock().await;
+
+    let mut pending = gs.pending.lock().await;
+    let write = pending
+        .get_mut(&id)
+        .ok_or_else(|| Graffiti::NotFound(format!("pending write {id}")))?;
+    write
+        .combine(psbt)
+        .map_err(|e| Graffiti::BadRequest(e.to_string()))?;
+    db::save_pending_write(&gs.db, write).await?;
+
+    let sign_options = gs.config.descriptor_kind().sign_options();
+    let Some(tx) = write.try_finalize(&wallet, sign_options)? else {
+        info!(
+            "write {} has {}/{} signatures",
+            id,
+            write.signatures(),
+            write.threshold
+        );
+        return Ok(Json(write.to_json()));
+    };
+
+    gs.blockchain.broadcast(&tx).await?;
+    insert_broadcast(&mut wallet, &[&tx]);
+    pending.remove(&id);
+    drop(pending);
+    db::delete_pending_write(&gs.db, id).await?;
+
+    let payload = tx
+        .output
+        .iter()
+        .find_map(|output| op_return_payload(&output.script_pubkey))
+        .unwrap_or_default();
+    let fee = wallet.calculate_fee(&tx)?;
+    record_write(&gs, &tx, WriteKind::OpReturn, &payload, fee).await?;
 
-    let client = get_electrum_client().map_err(|e| Report::from(Graffiti::Anyhow(e)))?;
-    client.transaction_broadcast(&tx)?;
+    let txid = tx.compute_txid();
+    info!("write {} reached its threshold, broadcast {}", id, txid);
+    let j = json!({ "id": id, "status": "broadcast", "txid": txid });
+
+    Ok(Json(j))
+}
+
+pub async fn estimate_fee(
+    State(gs): State<GrafittiState>,
+    Path(data): Path<String>,
+) -> error::Result<impl IntoResponse> {
+    info!("Received FEE ESTIMATE request for {} bytes", data.len());
+    let fee_rate = gs.blockchain.estimate_fee_rate(FEE_TARGET).await?;
+
+    let kind = gs.config.descriptor_kind();
+    let weight = estimate_write_weight(kind, data.len());
+    let fee = fee_rate.fee_wu(weight).unwrap_or(Amount::MAX_MONEY);
+
+    let j = json!({
+        "descriptor": kind,
+        "vsize": weight.to_vbytes_ceil(),
+        "fee_rate": fee_rate.to_sat_per_vb_ceil(),
+        "fee": fee.to_sat(),
+    });
+
+    Ok(Json(j))
+}
+
+/// Payload size assumed when counting affordable writes.
+const DEFAULT_WRITE_SIZE: usize = 80;
+
+#[derive(Deserialize)]
+pub struct BalanceQuery {
+    /// Payload size of the writes to count, 80 bytes when left out.
+    size: Option<usize>,
+}
+
+/// Balance of the funding wallet and how many writes it pays for at the current fee rate.
+pub async fn wallet_balance(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Query(query): Query<BalanceQuery>,
+) -> error::Result<impl IntoResponse> {
+    let size = query.size.unwrap_or(DEFAULT_WRITE_SIZE);
+    let fee_rate = gs.blockchain.estimate_fee_rate(FEE_TARGET).await?;
+    let balance = gs.wallet.lock().await.balance();
+    let spendable = balance.trusted_spendable();
+    let writes = affordable_writes(gs.config.descriptor_kind(), size, spendable, fee_rate);
+
+    let j = json!({
+        "confirmed": balance.confirmed.to_sat(),
+        "unconfirmed": (balance.trusted_pending + balance.untrusted_pending).to_sat(),
+        "trusted_pending": balance.trusted_pending.to_sat(),
+        "untrusted_pending": balance.untrusted_pending.to_sat(),
+        "immature": balance.immature.to_sat(),
+        "total": balance.total().to_sat(),
+        "spendable": spendable.to_sat(),
+        "fee_rate": fee_rate.to_sat_per_vb_ceil(),
+        "write_size": size,
+        "affordable_writes": writes,
+    });
+
+    Ok(Json(j))
+}
+
+#[derive(Deserialize)]
+pub struct AddressQuery {
+    /// Sats to ask for in the payment URI.
+    amount: Option<u64>,
+    label: Option<String>,
+}
+
+/// Reveals a receive address that was never handed out, with a BIP21 URI and its QR code.
+pub async fn wallet_address(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Query(query): Query<AddressQuery>,
+) -> error::Result<impl IntoResponse> {
+    // The watcher subscribes to revealed addresses on its next poll.
+    let info = gs
+        .wallet
+        .lock()
+        .await
+        .reveal_next_address(KeychainKind::External);
+    let uri = bip21_uri(
+        &info.address,
+        query.amount.map(Amount::from_sat),
+        query.label.as_deref(),
+    );
+    let qr = QrCode::new(uri.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    info!(
+        "revealed deposit address {} at index {}",
+        info.address, info.index
+    );
+
+    let j = json!({
+        "address": info.address.to_string(),
+        "index": info.index,
+        "uri": uri,
+        "qr_svg": qr,
+    });
+
+    Ok(Json(j))
+}
+
+/// Unspent coins of the funding wallet. Coins held by writes waiting for cosigners are
+/// marked `reserved`.
+pub async fn wallet_utxos(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+) -> error::Result<impl IntoResponse> {
+    let wallet = gs.wallet.lock().await;
+    let reserved: HashSet<OutPoint> = gs
+        .pending
+        .lock()
+        .await
+        .values()
+        .flat_map(PendingWrite::outpoints)
+        .collect();
+    let tip = wallet.latest_checkpoint().height();
+
+    let utxos: Vec<_> = wallet
+        .list_unspent()
+        .map(|utxo| {
+            let (height, confirmations) = match utxo.confirmation_time {
+                ConfirmationTime::Confirmed { height, .. } => {
+                    (Some(height), (tip + 1).saturating_sub(height))
+                }
+                ConfirmationTime::Unconfirmed { .. } => (None, 0),
+            };
+            json!({
+                "outpoint": utxo.outpoint.to_string(),
+                "value": utxo.txout.value.to_sat(),
+                "keychain": utxo.keychain,
+                "derivation_index": utxo.derivation_index,
+                "height": height,
+                "confirmations": confirmations,
+                "reserved": reserved.contains(&utxo.outpoint),
+                "pool": gs
+                    .config
+                    .fan_out
+                    .is_some_and(|fan_out| fan_out.is_pool_coin(utxo.txout.value)),
+            })
+        })
+        .collect();
+    let j = json!({ "tip": tip, "utxos": utxos });
+
+    Ok(Json(j))
+}
+
+/// Coins held by writes that are waiting for cosigners, and the change of unconfirmed economy
+/// anchors.
+///
+/// # Errors
+///
+/// Will return errors if the open economy batches can't be read
+pub(crate) async fn reserved_outpoints(
+    gs: &GrafittiState,
+    wallet: &Wallet,
+) -> anyhow::Result<Vec<OutPoint>> {
+    let mut reserved: Vec<OutPoint> = gs
+        .pending
+        .lock()
+        .await
+        .values()
+        .flat_map(PendingWrite::outpoints)
+        .collect();
+    let anchors = db::open_batches(&gs.db).await?;
+    reserved.extend(anchor_coins(wallet, &anchors));
+    Ok(reserved)
+}
+
+/// Treasury transactions are signed on the spot, cosigners can't be waited for.
+fn single_signer(gs: &GrafittiState) -> error::Result<DescriptorKind> {
+    let kind = gs.config.descriptor_kind();
+    if kind.threshold().is_some() {
+        return Err(Report::from(Graffiti::BadRequest(
+            "treasury operations are not available for multisig wallets".to_string(),
+        )));
+    }
+    Ok(kind)
+}
+
+async fn broadcast_treasury(
+    gs: &GrafittiState,
+    wallet: &mut Wallet,
+    treasury: Option<TreasuryTx>,
+) -> error::Result<serde_json::Value> {
+    let Some(treasury) = treasury else {
+        return Ok(json!({ "txid": null }));
+    };
+    gs.blockchain.broadcast(&treasury.tx).await?;
+    insert_broadcast(wallet, &[&treasury.tx]);
+    info!(
+        "broadcast treasury transaction {}",
+        treasury.tx.compute_txid()
+    );
+    Ok(treasury.to_json())
+}
+
+#[derive(Deserialize)]
+pub struct ConsolidateQuery {
+    /// Coins below this many sats are merged, 50,000 when left out.
+    below: Option<u64>,
+    /// Highest fee rate in sat/vb to consolidate at, `GRAFFITI_CONSOLIDATION_MAX_FEE_RATE`
+    /// when left out.
+    max_fee_rate: Option<u64>,
+}
+
+/// Merges small coins into one while fees are low.
+pub async fn consolidate(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Query(query): Query<ConsolidateQuery>,
+) -> error::Result<impl IntoResponse> {
+    let kind = single_signer(&gs)?;
+    let below = query
+        .below
+        .map_or(DEFAULT_CONSOLIDATION_BELOW, Amount::from_sat);
+    let max_fee_rate = query
+        .max_fee_rate
+        .and_then(FeeRate::from_sat_per_vb)
+        .unwrap_or(gs.config.consolidation_max_fee_rate);
+    let fee_rate = gs.blockchain.estimate_fee_rate(FEE_TARGET).await?;
+    if fee_rate > max_fee_rate {
+        return Err(Report::from(Graffiti::BadRequest(format!(
+            "the fee rate of {} sat/vb is above {} sat/vb, consolidate when fees are lower",
+            fee_rate.to_sat_per_vb_ceil(),
+            max_fee_rate.to_sat_per_vb_ceil()
+        ))));
+    }
+
+    let mut wallet = gs.wallet.lock().await;
+    let mut reserved = reserved_outpoints(&gs, &wallet).await?;
+    // Pool coins are small on purpose.
+    if let Some(fan_out) = &gs.config.fan_out {
+        reserved.extend(
+            wallet
+                .list_unspent()
+                .filter(|utxo| fan_out.is_pool_coin(utxo.txout.value))
+                .map(|utxo| utxo.outpoint),
+        );
+    }
+    let treasury =
+        build_consolidation(&mut wallet, below, &reserved, fee_rate, kind.sign_options())?;
+    let j = broadcast_treasury(&gs, &mut wallet, treasury).await?;
+
+    Ok(Json(j))
+}
+
+/// Sends the balance above `GRAFFITI_HOT_CEILING` to `GRAFFITI_COLD_ADDRESS`.
+pub async fn sweep_excess(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+) -> error::Result<impl IntoResponse> {
+    let kind = single_signer(&gs)?;
+    let (Some(cold_address), Some(ceiling)) = (&gs.config.cold_address, gs.config.hot_ceiling)
+    else {
+        return Err(Report::from(Graffiti::BadRequest(
+            "set GRAFFITI_COLD_ADDRESS and GRAFFITI_HOT_CEILING to sweep excess funds".to_string(),
+        )));
+    };
+    let fee_rate = gs.blockchain.estimate_fee_rate(FEE_TARGET).await?;
+
+    let mut wallet = gs.wallet.lock().await;
+    let reserved = reserved_outpoints(&gs, &wallet).await?;
+    let treasury = build_excess_sweep(
+        &mut wallet,
+        cold_address,
+        ceiling,
+        reserved,
+        fee_rate,
+        kind.sign_options(),
+    )?;
+    let j = broadcast_treasury(&gs, &mut wallet, treasury).await?;
+
+    Ok(Json(j))
+}
+
+#[derive(Deserialize)]
+pub struct SweepAllQuery {
+    /// Where the funds go, `GRAFFITI_COLD_ADDRESS` when left out.
+    address: Option<String>,
+    /// Must be `true`, the wallet can't write anymore afterwards.
+    #[serde(default)]
+    confirm: bool,
+}
+
+/// Sends every coin of the wallet away, for decommissioning the service.
+pub async fn sweep_all(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Query(query): Query<SweepAllQuery>,
+) -> error::Result<impl IntoResponse> {
+    let kind = single_signer(&gs)?;
+    if !query.confirm {
+        return Err(Report::from(Graffiti::BadRequest(
+            "sweeping the whole wallet needs confirm=true".to_string(),
+        )));
+    }
+    let destination = match query.address {
+        Some(address) => Address::from_str(&address)
+            .and_then(|address| address.require_network(NETWORK))
+            .map_err(|e| Graffiti::BadRequest(format!("invalid address: {e}")))?,
+        None => gs.config.cold_address.clone().ok_or_else(|| {
+            Graffiti::BadRequest("pass an address or set GRAFFITI_COLD_ADDRESS".to_string())
+        })?,
+    };
+    let fee_rate = gs.blockchain.estimate_fee_rate(FEE_TARGET).await?;
+
+    let mut wallet = gs.wallet.lock().await;
+    let treasury = build_full_sweep(&mut wallet, &destination, fee_rate, kind.sign_options())?;
+    let j = broadcast_treasury(&gs, &mut wallet, treasury).await?;
+
+    Ok(Json(j))
+}
+
+pub async fn migrate_wallet(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+) -> error::Result<impl IntoResponse> {
+    info!("Received MIGRATE request");
+    let Some(mut legacy) = gs.config.legacy_wallet()? else {
+        return Err(Report::from(Graffiti::Anyhow(anyhow::anyhow!(
+            "no legacy descriptors configured"
+        ))));
+    };
+
+    let fee_rate = gs.blockchain.estimate_fee_rate(FEE_TARGET).await?;
+    // The legacy wallet isn't watched, it only needs a scan when it is swept.
+    gs.blockchain
+        .full_scan(&mut legacy)
+        .await
+        .map_err(|e| Report::from(Graffiti::Anyhow(e)))?;
+    let mut wallet = gs.wallet.lock().await;
+
+    let Some(tx) = build_sweep(&mut legacy, &mut wallet, fee_rate)? else {
+        return Ok(Json(json!({ "txid": null, "swept": 0 })));
+    };
+    let swept: Amount = tx.output.iter().map(|output| output.value).sum();
+
+    gs.blockchain.broadcast(&tx).await?;
+    insert_broadcast(&mut wallet, &[&tx]);
 
     let txid = tx.compute_txid();
-    let j = json!({ "txid": txid });
+    info!("swept {} from legacy wallet in {}", swept, txid);
+    let j = json!({ "txid": txid, "swept": swept.to_sat() });
+
+    Ok(Json(j))
+}
+
+#[derive(Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    secret: String,
+    /// Event kinds to deliver, all of them when left out.
+    #[serde(default)]
+    events: Vec<EventKind>,
+}
+
+pub async fn create_webhook(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Json(body): Json<NewWebhook>,
+) -> error::Result<impl IntoResponse> {
+    let url = reqwest::Url::parse(&body.url)
+        .map_err(|e| Graffiti::BadRequest(format!("invalid webhook url: {e}")))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Report::from(Graffiti::BadRequest(
+            "webhook urls must be http or https".to_string(),
+        )));
+    }
+    if body.secret.is_empty() {
+        return Err(Report::from(Graffiti::BadRequest(
+            "webhooks need a secret to sign deliveries with".to_string(),
+        )));
+    }
+    // Checked again on every delivery, the name may resolve differently by then.
+    resolve_public(&url)
+        .await
+        .map_err(|e| Graffiti::BadRequest(format!("invalid webhook url: {e}")))?;
+
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        url: url.to_string(),
+        secret: body.secret,
+        events: body.events,
+        created_at: chrono::Utc::now(),
+    };
+    db::create_webhook(&gs.db, &webhook).await?;
+    info!("registered webhook {} for {}", webhook.id, webhook.url);
+
+    Ok((StatusCode::CREATED, Json(webhook.to_json())))
+}
+
+pub async fn list_webhooks(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+) -> error::Result<impl IntoResponse> {
+    let webhooks = db::list_webhooks(&gs.db).await?;
+    let webhooks: Vec<_> = webhooks.iter().map(Webhook::to_json).collect();
+    let j = json!({ "webhooks": webhooks });
+
+    Ok(Json(j))
+}
+
+pub async fn delete_webhook(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Path(id): Path<Uuid>,
+) -> error::Result<impl IntoResponse> {
+    if !db::delete_webhook(&gs.db, id).await? {
+        return Err(Report::from(Graffiti::NotFound(format!("webhook {id}"))));
+    }
+    info!("removed webhook {}", id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct DeliveryQuery {
+    /// `pending`, `delivered` or `failed`.
+    status: Option<String>,
+    limit: Option<u32>,
+}
+
+/// The delivery log of a webhook, newest first.
+pub async fn list_deliveries(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<DeliveryQuery>,
+) -> error::Result<impl IntoResponse> {
+    let limit = query.limit.unwrap_or(100).min(MAX_INDEX_LIMIT);
+    let deliveries = db::list_deliveries(&gs.db, id, query.status.as_deref(), limit).await?;
+    let deliveries: Vec<_> = deliveries.iter().map(Delivery::to_json).collect();
+    let j = json!({ "webhook_id": id, "deliveries": deliveries });
+
+    Ok(Json(j))
+}
+
+/// Records replayed per query when a stream resumes from a cursor.
+const RESUME_PAGE: u32 = 500;
+/// Events a stream holds for a slow client before it stops reading the feed.
+const STREAM_BUFFER: usize = 64;
+
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    /// Hex encoded payload prefix.
+    prefix: Option<String>,
+    /// Event id to resume after, `Last-Event-ID` works too.
+    cursor: Option<i64>,
+}
+
+/// Server-Sent Events stream of `OP_RETURN` outputs as they enter the mempool, confirm and get
+/// reorged out, from the chain-wide index. Each event's id is its cursor, so reconnecting
+/// clients get what they missed. A client that falls too far behind gets a `lagged` event and
+/// the stream ends.
+pub async fn stream_graffiti(
+    State(gs): State<GrafittiState>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+) -> error::Result<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    if gs.config.index_start_height.is_none() {
+        return Err(Report::from(Graffiti::NotFound(
+            "OP_RETURN index".to_string(),
+        )));
+    }
+    let prefix = query
+        .prefix
+        .as_deref()
+        .map(Vec::from_hex)
+        .transpose()
+        .map_err(|e| Graffiti::BadRequest(format!("invalid prefix: {e}")))?
+        .unwrap_or_default();
+    let cursor = match (query.cursor, headers.get("last-event-id")) {
+        (Some(cursor), _) => Some(cursor),
+        (None, Some(id)) => Some(
+            id.to_str()
+                .ok()
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| Graffiti::BadRequest("invalid Last-Event-ID".to_string()))?,
+        ),
+        (None, None) => None,
+    };
+
+    // Subscribe before replaying so nothing falls between the replay and the live records.
+    let mut live = gs.feed.subscribe();
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+    tokio::spawn(async move {
+        let mut last = cursor;
+        if let Some(mut after) = cursor {
+            loop {
+                let page =
+                    match db::graffiti_records_after(&gs.db, after, &prefix, RESUME_PAGE).await {
+                        Ok(page) => page,
+                        Err(e) => {
+                            warn!("could not replay the stream after {after}: {e:?}");
+                            return;
+                        }
+                    };
+                let done = page.len() < RESUME_PAGE as usize;
+                for record in page {
+                    after = record.cursor;
+                    last = Some(record.cursor);
+                    if sender.send(Ok(stream_event(&record))).await.is_err() {
+                        return;
+                    }
+                }
+                if done {
+                    break;
+                }
+            }
+        }
+
+        loop {
+            let received = tokio::select! {
+                received = live.recv() => received,
+                () = sender.closed() => return,
+            };
+            match received {
+                Ok(record) => {
+                    if last.is_some_and(|last| record.cursor <= last)
+                        || !record.op_return.payload.starts_with(&prefix)
+                    {
+                        continue;
+                    }
+                    last = Some(record.cursor);
+                    if sender.send(Ok(stream_event(&record))).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    // Records are gone from the feed, the client resumes from its last cursor.
+                    let data = json!({ "missed": missed, "cursor": last });
+                    let mut lagged = Event::default().event("lagged").data(data.to_string());
+                    if let Some(last) = last {
+                        lagged = lagged.id(last.to_string());
+                    }
+                    let _ = sender.send(Ok(lagged)).await;
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(receiver)).keep_alive(KeepAlive::default()))
+}
+
+fn stream_event(record: &GraffitiRecord) -> Event {
+    Event::default()
+        .id(record.cursor.to_string())
+        .event(record.event.as_str())
+        .data(record.to_json().to_string())
+}
+
+/// Blocks one sandbox call may mine or reorg.
+const MAX_SANDBOX_BLOCKS: usize = 1_000;
+/// Outputs one faucet call may create.
+const MAX_FAUCET_OUTPUTS: u32 = 100;
+/// Longest a client may wait for electrs.
+const MAX_SANDBOX_WAIT: Duration = Duration::from_secs(300);
+
+/// The regtest nodes, for the sandbox routes. They are only routed with `--sandbox`.
+fn sandbox(gs: &GrafittiState) -> error::Result<Arc<Sandbox>> {
+    gs.sandbox
+        .clone()
+        .ok_or_else(|| Report::from(Graffiti::NotFound("sandbox".to_string())))
+}
+
+fn sandbox_blocks(count: usize) -> error::Result<usize> {
+    if !(1..=MAX_SANDBOX_BLOCKS).contains(&count) {
+        return Err(Report::from(Graffiti::BadRequest(format!(
+            "block count must be between 1 and {MAX_SANDBOX_BLOCKS}"
+        ))));
+    }
+    Ok(count)
+}
+
+#[derive(Deserialize)]
+pub struct FundQuery {
+    /// Sats per output, 0.2 BTC when left out.
+    amount: Option<u64>,
+    outputs: Option<u32>,
+    /// Mine a block so the coins confirm, on by default.
+    confirm: Option<bool>,
+}
+
+/// Pays new receive addresses of the service wallet from the regtest node.
+pub async fn sandbox_fund(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Query(query): Query<FundQuery>,
+) -> error::Result<impl IntoResponse> {
+    let sandbox = sandbox(&gs)?;
+    let amount = query.amount.map_or(FUNDING_AMOUNT, Amount::from_sat);
+    let outputs = query.outputs.unwrap_or(1);
+    if !(1..=MAX_FAUCET_OUTPUTS).contains(&outputs) || amount == Amount::ZERO {
+        return Err(Report::from(Graffiti::BadRequest(format!(
+            "fund between 1 and {MAX_FAUCET_OUTPUTS} outputs with a positive amount"
+        ))));
+    }
+
+    // Revealed addresses are picked up by the watcher.
+    let addresses: Vec<_> = {
+        let mut wallet = gs.wallet.lock().await;
+        (0..outputs)
+            .map(|_| wallet.reveal_next_address(KeychainKind::External).address)
+            .collect()
+    };
+    let txids = sandbox.send(addresses.clone(), amount).await?;
+    let blocks = if query.confirm.unwrap_or(true) {
+        sandbox.mine(1).await?
+    } else {
+        Vec::new()
+    };
+    info!(
+        "sandbox funded the wallet with {} coins of {}",
+        outputs, amount
+    );
+    let addresses: Vec<_> = addresses.iter().map(ToString::to_string).collect();
+    let j = json!({
+        "txids": txids,
+        "addresses": addresses,
+        "amount": amount.to_sat(),
+        "blocks": blocks,
+    });
+
+    Ok(Json(j))
+}
+
+#[derive(Deserialize)]
+pub struct MineQuery {
+    blocks: Option<usize>,
+}
+
+/// Mines blocks on the regtest node, answering once electrs has them.
+pub async fn sandbox_mine(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Query(query): Query<MineQuery>,
+) -> error::Result<impl IntoResponse> {
+    let sandbox = sandbox(&gs)?;
+    let count = sandbox_blocks(query.blocks.unwrap_or(1))?;
+    let blocks = sandbox.mine(count).await?;
+    info!("sandbox mined {} blocks", count);
+    let j = json!({ "blocks": blocks });
+
+    Ok(Json(j))
+}
+
+/// Mines a block that leaves the mempool alone, so writes stay unconfirmed.
+pub async fn sandbox_mine_empty(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+) -> error::Result<impl IntoResponse> {
+    let sandbox = sandbox(&gs)?;
+    let (height, hash) = sandbox.mine_empty().await?;
+    info!("sandbox mined empty block {} at {}", hash, height);
+    let j = json!({ "height": height, "block_hash": hash });
+
+    Ok(Json(j))
+}
+
+#[derive(Deserialize)]
+pub struct ReorgQuery {
+    depth: Option<usize>,
+    /// Leave the reorged transactions in the mempool instead of confirming them again.
+    #[serde(default)]
+    empty: bool,
+}
+
+/// Replaces the last `depth` blocks of the regtest chain, keeping its height.
+pub async fn sandbox_reorg(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Query(query): Query<ReorgQuery>,
+) -> error::Result<impl IntoResponse> {
+    let sandbox = sandbox(&gs)?;
+    let depth = sandbox_blocks(query.depth.unwrap_or(1))?;
+    let tip = gs.wallet.lock().await.latest_checkpoint().height();
+    if depth >= tip as usize {
+        return Err(Report::from(Graffiti::BadRequest(format!(
+            "can't reorg {depth} blocks of a chain at height {tip}"
+        ))));
+    }
+    let blocks = sandbox.reorg(depth, query.empty).await?;
+    info!("sandbox reorged {} blocks", depth);
+    let j = json!({ "depth": depth, "empty": query.empty, "blocks": blocks });
+
+    Ok(Json(j))
+}
+
+#[derive(Deserialize)]
+pub struct WaitQuery {
+    /// Seconds to wait, 30 when left out.
+    timeout: Option<u64>,
+}
+
+/// Answers once electrs has the tip of the regtest node.
+pub async fn sandbox_wait(
+    _admin: Admin,
+    State(gs): State<GrafittiState>,
+    Query(query): Query<WaitQuery>,
+) -> error::Result<impl IntoResponse> {
+    let sandbox = sandbox(&gs)?;
+    let timeout = query
+        .timeout
+        .map_or(ELECTRS_TIMEOUT, Duration::from_secs)
+        .min(MAX_SANDBOX_WAIT);
+    let height = sandbox.wait_for_electrs(timeout).await?;
+    let j = json!({ "height": height });
 
     Ok(Json(j))
 }
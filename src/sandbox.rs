@@ -0,0 +1,245 @@
+use anyhow::bail;
+use bdk_wallet::bitcoin::{Address, Amount, BlockHash, Network, Txid};
+use bdk_wallet::{KeychainKind, Wallet};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::{BackendConfig, ElectrumServerConfig};
+use crate::testenv::bitcoincore_rpc::RpcApi;
+use crate::testenv::electrsd::electrum_client::ElectrumApi;
+use crate::testenv::TestEnv;
+use crate::util::NETWORK;
+
+/// Blocks mined on startup, so the coinbase of the first one can be spent.
+const MATURITY_BLOCKS: usize = 101;
+/// Coins the service wallet starts with, several so writes don't wait on each other's change.
+pub const FUNDING_OUTPUTS: u32 = 5;
+pub const FUNDING_AMOUNT: Amount = Amount::from_sat(20_000_000);
+/// Seconds between blocks of the auto-miner unless `GRAFFITI_SANDBOX_BLOCK_INTERVAL` is set.
+const DEFAULT_BLOCK_INTERVAL: u64 = 10;
+/// How long mining and reorgs wait for electrs to catch up with bitcoind.
+pub const ELECTRS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A local regtest chain, `bitcoind` and `electrs`, that the service runs against with
+/// `--sandbox`. The nodes stop when it is dropped.
+pub struct Sandbox {
+    /// Calls are serialized, waiting for electrs to see a block must not race another call
+    /// reading the same notification.
+    env: Mutex<TestEnv>,
+}
+
+impl Sandbox {
+    /// Starts the nodes and mines until coinbase outputs are spendable.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the build isn't for regtest or the nodes can't be started
+    pub async fn start() -> anyhow::Result<Arc<Self>> {
+        if NETWORK != Network::Regtest {
+            bail!("the sandbox runs on regtest, build with `--features regtest`");
+        }
+        let env = tokio::task::spawn_blocking(|| -> anyhow::Result<TestEnv> {
+            let env = TestEnv::new()?;
+            env.mine_blocks(MATURITY_BLOCKS, None)?;
+            wait_for_electrs(&env, ELECTRS_TIMEOUT)?;
+            Ok(env)
+        })
+        .await??;
+        let sandbox = Arc::new(Self {
+            env: Mutex::new(env),
+        });
+        info!("sandbox is up, electrum at {}", sandbox.electrum_url());
+        Ok(sandbox)
+    }
+
+    fn env(&self) -> std::sync::MutexGuard<'_, TestEnv> {
+        self.env
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    pub fn electrum_url(&self) -> String {
+        format!("tcp://{}", self.env().electrsd.electrum_url)
+    }
+
+    /// The Electrum backend of the local `electrs`.
+    pub fn backend_config(&self) -> BackendConfig {
+        BackendConfig::Electrum {
+            servers: vec![ElectrumServerConfig::new(&self.electrum_url())],
+        }
+    }
+
+    /// Runs a blocking call on the nodes off the async runtime.
+    async fn run<T, F>(self: &Arc<Self>, call: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&TestEnv) -> anyhow::Result<T> + Send + 'static,
+    {
+        let sandbox = self.clone();
+        tokio::task::spawn_blocking(move || call(&sandbox.env())).await?
+    }
+
+    /// Mines `count` blocks and waits until electrs has them.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the nodes can't be reached
+    pub async fn mine(self: &Arc<Self>, count: usize) -> anyhow::Result<Vec<BlockHash>> {
+        self.run(move |env| {
+            let hashes = env.mine_blocks(count, None)?;
+            wait_for_electrs(env, ELECTRS_TIMEOUT)?;
+            Ok(hashes)
+        })
+        .await
+    }
+
+    /// Mines a block without any of the mempool transactions and waits until electrs has it.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the nodes can't be reached
+    pub async fn mine_empty(self: &Arc<Self>) -> anyhow::Result<(usize, BlockHash)> {
+        self.run(|env| {
+            let block = env.mine_empty_block()?;
+            wait_for_electrs(env, ELECTRS_TIMEOUT)?;
+            Ok(block)
+        })
+        .await
+    }
+
+    /// Replaces the last `depth` blocks with as many new ones, so the height stays the same.
+    /// With `empty` the new blocks leave the reorged transactions in the mempool, otherwise
+    /// they confirm them again.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the chain is shorter than `depth` or the nodes can't be reached
+    pub async fn reorg(
+        self: &Arc<Self>,
+        depth: usize,
+        empty: bool,
+    ) -> anyhow::Result<Vec<BlockHash>> {
+        self.run(move |env| {
+            let height = env.rpc_client().get_block_count()?;
+            if depth as u64 >= height {
+                bail!("can't reorg {depth} blocks of a chain at height {height}");
+            }
+            let hashes = if empty {
+                env.reorg_empty_blocks(depth)?
+                    .into_iter()
+                    .map(|(_, hash)| hash)
+                    .collect()
+            } else {
+                env.reorg(depth)?
+            };
+            wait_for_electrs(env, ELECTRS_TIMEOUT)?;
+            Ok(hashes)
+        })
+        .await
+    }
+
+    /// Waits until electrs has the tip of bitcoind, returning its height.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if electrs is still behind after `timeout`
+    pub async fn wait_for_electrs(self: &Arc<Self>, timeout: Duration) -> anyhow::Result<usize> {
+        self.run(move |env| wait_for_electrs(env, timeout)).await
+    }
+
+    /// Pays `amount` to each of `addresses` from the bitcoind wallet.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the bitcoind wallet can't pay
+    pub async fn send(
+        self: &Arc<Self>,
+        addresses: Vec<Address>,
+        amount: Amount,
+    ) -> anyhow::Result<Vec<Txid>> {
+        self.run(move |env| {
+            addresses
+                .iter()
+                .map(|address| env.send(address, amount))
+                .collect()
+        })
+        .await
+    }
+
+    /// Funds the first receive addresses of `wallet` and confirms the coins, before the
+    /// service scans it.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the nodes can't be reached
+    pub async fn fund_wallet(self: &Arc<Self>, wallet: &Wallet) -> anyhow::Result<()> {
+        let addresses = (0..FUNDING_OUTPUTS)
+            .map(|index| wallet.peek_address(KeychainKind::External, index).address)
+            .collect();
+        self.send(addresses, FUNDING_AMOUNT).await?;
+        self.mine(1).await?;
+        info!(
+            "funded the service wallet with {} coins of {}",
+            FUNDING_OUTPUTS, FUNDING_AMOUNT
+        );
+        Ok(())
+    }
+
+    /// Mines a block every `GRAFFITI_SANDBOX_BLOCK_INTERVAL` seconds, so writes confirm as they
+    /// would on a real chain. An interval of 0 leaves mining to the caller.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the interval isn't a number
+    pub fn spawn_miner(self: &Arc<Self>) -> anyhow::Result<Option<JoinHandle<()>>> {
+        let seconds = match env::var("GRAFFITI_SANDBOX_BLOCK_INTERVAL") {
+            Ok(seconds) => seconds
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid GRAFFITI_SANDBOX_BLOCK_INTERVAL: {e}"))?,
+            Err(_) => DEFAULT_BLOCK_INTERVAL,
+        };
+        if seconds == 0 {
+            return Ok(None);
+        }
+        info!("mining a block every {} seconds", seconds);
+        let sandbox = self.clone();
+        Ok(Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(seconds)).await;
+                if let Err(e) = sandbox.mine(1).await {
+                    warn!("sandbox miner failed: {e:?}");
+                }
+            }
+        })))
+    }
+}
+
+/// Polls electrs until its tip is the one of bitcoind. Comparing hashes rather than waiting for
+/// a header notification also works when electrs got there first, and after a reorg that
+/// keeps the height.
+fn wait_for_electrs(env: &TestEnv, timeout: Duration) -> anyhow::Result<usize> {
+    let client = env.electrum_client();
+    let start = Instant::now();
+    loop {
+        env.electrsd.trigger()?;
+        let best = env.rpc_client().get_best_block_hash()?;
+        let tip = client.block_headers_subscribe()?;
+        // Drop the notifications that subscribing queues, nobody reads them.
+        while client.block_headers_pop()?.is_some() {}
+        if tip.header.block_hash() == best {
+            return Ok(tip.height);
+        }
+        if start.elapsed() > timeout {
+            bail!(
+                "electrs is still at height {} after {:?}, bitcoind is at {}",
+                tip.height,
+                timeout,
+                best
+            );
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
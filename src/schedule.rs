@@ -0,0 +1,227 @@
+use anyhow::bail;
+use bdk_wallet::bitcoin::Txid;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::attestation::Attestation;
+use crate::db;
+use crate::error;
+use crate::queue::PreparedWrite;
+use crate::routes::{send_op_return, WriteOutcome};
+use crate::util::{GrafittiState, FEE_TARGET};
+
+/// How often scheduled writes are checked against the chain tip and the clock.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where a scheduled write is in its life.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleStatus {
+    Scheduled,
+    /// Claimed by the [`Scheduler`] while it is built and broadcast.
+    Sending,
+    Sent,
+    Failed,
+    Cancelled,
+}
+
+impl ScheduleStatus {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Scheduled => "scheduled",
+            Self::Sending => "sending",
+            Self::Sent => "sent",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl fmt::Display for ScheduleStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ScheduleStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "scheduled" => Self::Scheduled,
+            "sending" => Self::Sending,
+            "sent" => Self::Sent,
+            "failed" => Self::Failed,
+            "cancelled" => Self::Cancelled,
+            _ => bail!("unknown schedule status {s}"),
+        })
+    }
+}
+
+/// A write held back until a block height or a point in time, whichever are set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledWrite {
+    pub id: Uuid,
+    pub data: String,
+    /// The bytes to put on chain, already encrypted if a recipient was given.
+    pub payload: Vec<u8>,
+    /// Attest the payload with the service key when it is sent.
+    pub attest: bool,
+    pub not_before_height: Option<u32>,
+    pub not_before_time: Option<DateTime<Utc>>,
+    pub status: ScheduleStatus,
+    pub txid: Option<Txid>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+impl ScheduledWrite {
+    pub fn new(
+        data: String,
+        payload: Vec<u8>,
+        attest: bool,
+        not_before_height: Option<u32>,
+        not_before_time: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            data,
+            payload,
+            attest,
+            not_before_height,
+            not_before_time,
+            status: ScheduleStatus::Scheduled,
+            txid: None,
+            error: None,
+            created_at: Utc::now(),
+            sent_at: None,
+        }
+    }
+
+    /// Whether the write may go out with the chain at `height` and the clock at `now`.
+    pub fn is_due(&self, height: u32, now: DateTime<Utc>) -> bool {
+        self.not_before_height.map_or(true, |min| height >= min)
+            && self.not_before_time.map_or(true, |min| now >= min)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "status": self.status,
+            "data": self.data,
+            "attest": self.attest,
+            "not_before_height": self.not_before_height,
+            "not_before_time": self.not_before_time,
+            "txid": self.txid,
+            "error": self.error,
+            "created_at": self.created_at,
+            "sent_at": self.sent_at,
+        })
+    }
+}
+
+/// Sends scheduled writes once the chain and the clock reach them.
+pub struct Scheduler {
+    gs: GrafittiState,
+}
+
+impl Scheduler {
+    pub const fn new(gs: GrafittiState) -> Self {
+        Self { gs }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            // A write claimed before a restart may or may not have gone out, better to say so
+            // than to send it twice.
+            if let Err(e) = db::fail_interrupted_schedules(&self.gs.db).await {
+                warn!("could not resolve interrupted scheduled writes: {e:?}");
+            }
+            loop {
+                if let Err(e) = self.send_due().await {
+                    warn!("could not send scheduled writes, retrying: {e:?}");
+                }
+                tokio::time::sleep(SCHEDULE_INTERVAL).await;
+            }
+        })
+    }
+
+    async fn send_due(&self) -> error::Result<()> {
+        let height = self.gs.wallet.lock().await.latest_checkpoint().height();
+        let now = Utc::now();
+        let waiting = db::waiting_scheduled_writes(&self.gs.db).await?;
+        for write in waiting
+            .into_iter()
+            .filter(|write| write.is_due(height, now))
+        {
+            // A write cancelled since the query stays unsent.
+            if !db::claim_scheduled_write(&self.gs.db, write.id).await? {
+                continue;
+            }
+            // Stored per write, one failing to store doesn't hold back the rest.
+            let id = write.id;
+            if let Err(e) = self.send(write).await {
+                warn!("could not store the result of scheduled write {id}: {e:?}");
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&self, write: ScheduledWrite) -> error::Result<()> {
+        let attestation = match (write.attest, &self.gs.config.attestation_key) {
+            (false, _) => None,
+            (true, Some(keypair)) => Some(Attestation::sign(keypair, &write.payload)),
+            (true, None) => {
+                let reason = "no attestation key is configured".to_string();
+                db::finish_scheduled_write(
+                    &self.gs.db,
+                    write.id,
+                    ScheduleStatus::Failed,
+                    None,
+                    Some(&reason),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+        let on_chain = attestation.is_some() && Attestation::fits_on_chain(&write.payload);
+        let prepared = PreparedWrite {
+            data: write.data,
+            payload: write.payload,
+            attestation,
+            on_chain,
+            fee_target: FEE_TARGET,
+            replaceable: false,
+            envelope: false,
+        };
+
+        let (status, txid, reason) = match send_op_return(&self.gs, prepared).await {
+            Ok(WriteOutcome::Sent(txid, _) | WriteOutcome::Pending(txid, _)) => {
+                info!("sent scheduled write {} in {}", write.id, txid);
+                (ScheduleStatus::Sent, Some(txid), None)
+            }
+            Ok(WriteOutcome::Blocked(reason)) => {
+                info!("scheduled write {} waits: {reason}", write.id);
+                (ScheduleStatus::Scheduled, None, Some(reason))
+            }
+            Err(e) if e.is_rejection() => {
+                warn!("scheduled write {} was rejected: {e:?}", write.id);
+                (ScheduleStatus::Failed, None, Some(e.to_string()))
+            }
+            // The backend may be back by the next round, the write stays due until then.
+            Err(e) => {
+                warn!("scheduled write {} will be retried: {e:?}", write.id);
+                (ScheduleStatus::Scheduled, None, Some(e.to_string()))
+            }
+        };
+        db::finish_scheduled_write(&self.gs.db, write.id, status, txid, reason.as_deref()).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::bail;
+use serde_json::json;
+use std::fmt;
+use std::str::FromStr;
+use tokio::sync::broadcast;
+
+use crate::indexer::IndexedOpReturn;
+
+/// How many records a slow stream may fall behind before it is told it lagged.
+const FEED_BUFFER: usize = 256;
+
+/// Where an `OP_RETURN` output moved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// Seen in the node's mempool.
+    Mempool,
+    Confirmed,
+    /// Its block left the best chain.
+    Reorged,
+}
+
+impl StreamEvent {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Mempool => "mempool",
+            Self::Confirmed => "confirmed",
+            Self::Reorged => "reorged",
+        }
+    }
+}
+
+impl fmt::Display for StreamEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StreamEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "mempool" => Self::Mempool,
+            "confirmed" => Self::Confirmed,
+            "reorged" => Self::Reorged,
+            _ => bail!("unknown stream event {s}"),
+        })
+    }
+}
+
+/// An `OP_RETURN` output as pushed to the live stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GraffitiRecord {
+    /// Id of the stream event, clients resume after it.
+    pub cursor: i64,
+    pub event: StreamEvent,
+    pub op_return: IndexedOpReturn,
+}
+
+impl GraffitiRecord {
+    pub fn to_json(&self) -> serde_json::Value {
+        let chain_position = match (self.event, self.op_return.height) {
+            (StreamEvent::Confirmed, Some(height)) => {
+                json!({ "type": "Confirmed", "anchor": { "height": height } })
+            }
+            (StreamEvent::Reorged, _) => json!({ "type": "Reorged" }),
+            _ => json!({ "type": "Unconfirmed" }),
+        };
+        let mut j = self.op_return.to_json();
+        j["cursor"] = json!(self.cursor);
+        j["event"] = json!(self.event.as_str());
+        j["chain_position"] = chain_position;
+        j
+    }
+}
+
+/// Hands the indexer's records to every connected stream.
+#[derive(Clone, Debug)]
+pub struct Feed {
+    sender: broadcast::Sender<GraffitiRecord>,
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(FEED_BUFFER);
+        Self { sender }
+    }
+}
+
+impl Feed {
+    pub fn subscribe(&self) -> broadcast::Receiver<GraffitiRecord> {
+        self.sender.subscribe()
+    }
+
+    /// Pushes records that are already stored, so streams can replay them after a reconnect.
+    pub fn publish(&self, records: Vec<GraffitiRecord>) {
+        for record in records {
+            // No stream is connected.
+            let _ = self.sender.send(record);
+        }
+    }
+}
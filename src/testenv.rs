@@ -279,8 +279,17 @@ impl TestEnv {
 #[cfg(test)]
 mod test {
     use super::TestEnv;
+    use crate::backend::{BroadcastError, ChainBackend};
+    use crate::config::{BackendConfig, ElectrumServerConfig, RpcConfig};
+    use crate::electrum::{Client, ElectrumPool};
     use crate::testenv::TestEnv;
+    use crate::{EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR};
+    use bdk_chain::bitcoin::Amount;
+    use bdk_chain::bitcoin::Network;
+    use bdk_wallet::{KeychainKind, Wallet};
+    use bitcoincore_rpc::Auth;
     use electrsd::bitcoind::{anyhow::Result, bitcoincore_rpc::RpcApi};
+    use tokio::sync::Mutex;
 
     /// This checks that reorgs initiated by `bitcoind` is detected by our `electrsd` instance.
     #[test]
@@ -315,4 +324,169 @@ mod test {
 
         Ok(())
     }
+
+    /// The Esplora backend reads blocks and transactions from the `electrsd` HTTP API.
+    #[test]
+    fn test_esplora_backend() -> Result<()> {
+        let env = TestEnv::new()?;
+        env.mine_blocks(101, None)?;
+        let address = env
+            .bitcoind
+            .client
+            .get_new_address(None, None)?
+            .assume_checked();
+        let txid = env.send(&address, Amount::from_sat(10_000))?;
+        env.mine_blocks(1, None)?;
+        env.wait_until_electrum_sees_block()?;
+
+        let url = format!(
+            "http://{}",
+            env.electrsd.esplora_url.as_ref().expect("http is enabled")
+        );
+        let backend = ChainBackend::new(&BackendConfig::Esplora { url })?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let height = env.bitcoind.client.get_block_count()?;
+            assert_eq!(
+                backend.block_hash(height as u32).await?,
+                env.bitcoind.client.get_block_hash(height)?
+            );
+            let tx = backend
+                .get_tx(txid)
+                .await?
+                .expect("the transaction was mined");
+            assert_eq!(tx.compute_txid(), txid);
+            assert!(
+                backend.estimate_fee_rate(6).await? >= bdk_chain::bitcoin::FeeRate::BROADCAST_MIN
+            );
+            Ok(())
+        })
+    }
+
+    /// The RPC backend syncs a wallet from the blocks and mempool of `bitcoind` and reports
+    /// why a transaction was rejected.
+    #[test]
+    fn test_rpc_backend() -> Result<()> {
+        let env = TestEnv::new()?;
+        env.mine_blocks(101, None)?;
+
+        let rpc = RpcConfig {
+            url: env.bitcoind.rpc_url(),
+            auth: Auth::CookieFile(env.bitcoind.params.cookie_file.clone()),
+        };
+        let backend = ChainBackend::new(&BackendConfig::Rpc {
+            rpc,
+            start_height: 0,
+        })?;
+        let mut wallet = Wallet::new(EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR, Network::Regtest)?;
+        let address = wallet.next_unused_address(KeychainKind::External).address;
+
+        env.send(&address, Amount::from_sat(50_000))?;
+        env.mine_blocks(1, None)?;
+        let unconfirmed = env.send(&address, Amount::from_sat(20_000))?;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            backend.full_scan(&mut wallet).await?;
+            let balance = wallet.balance();
+            assert_eq!(balance.confirmed, Amount::from_sat(50_000));
+            assert_eq!(balance.untrusted_pending, Amount::from_sat(20_000));
+            assert_eq!(
+                wallet.latest_checkpoint().height() as u64,
+                env.bitcoind.client.get_block_count()?
+            );
+
+            // Already in the mempool, testmempoolaccept names the reason.
+            let tx = backend.get_tx(unconfirmed).await?.expect("in the mempool");
+            assert!(matches!(
+                backend.broadcast(&tx).await,
+                Err(BroadcastError::Rejected(reason)) if reason.contains("already")
+            ));
+
+            env.mine_blocks(1, None)?;
+            let wallet = Mutex::new(wallet);
+            backend.sync_blocks(&wallet).await?;
+            assert_eq!(
+                wallet.lock().await.balance().confirmed,
+                Amount::from_sat(70_000)
+            );
+            Ok(())
+        })
+    }
+
+    /// Requests fail over from a server that is down to the next one, and the one that is
+    /// down is counted against.
+    #[test]
+    fn test_electrum_failover() -> Result<()> {
+        let env = TestEnv::new()?;
+        env.mine_blocks(1, None)?;
+        env.wait_until_electrum_sees_block()?;
+
+        let pool = ElectrumPool::new(&[
+            ElectrumServerConfig::new("tcp://127.0.0.1:1"),
+            ElectrumServerConfig::new(&format!("tcp://{}", env.electrsd.electrum_url)),
+        ]);
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let header = pool
+                .call(|| |client: &Client| client.inner.block_header(1))
+                .await?;
+            assert_eq!(header.block_hash(), env.bitcoind.client.get_block_hash(1)?);
+
+            let status = pool.to_json();
+            assert_eq!(status["servers"][0]["failures"], 1);
+            assert_eq!(status["servers"][0]["state"], "degraded");
+            assert_eq!(status["servers"][1]["primary"], true);
+            Ok(())
+        })
+    }
+
+    /// The sandbox hands its `electrs` to the config, funds the service wallet and mines and
+    /// reorgs the chain the way its routes do.
+    #[cfg(feature = "regtest")]
+    #[test]
+    fn test_sandbox() -> Result<()> {
+        use crate::sandbox::{Sandbox, ELECTRS_TIMEOUT, FUNDING_AMOUNT, FUNDING_OUTPUTS};
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let sandbox = Sandbox::start().await?;
+            let config = sandbox.backend_config();
+            assert!(matches!(
+                &config,
+                BackendConfig::Electrum { servers } if servers[0].url == sandbox.electrum_url()
+            ));
+            let backend = ChainBackend::new(&config)?;
+
+            let mut wallet =
+                Wallet::new(EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR, Network::Regtest)?;
+            sandbox.fund_wallet(&wallet).await?;
+            backend.full_scan(&mut wallet).await?;
+            assert_eq!(
+                wallet.balance().confirmed,
+                FUNDING_AMOUNT * u64::from(FUNDING_OUTPUTS)
+            );
+
+            let blocks = sandbox.mine(2).await?;
+            assert_eq!(blocks.len(), 2);
+            let height = sandbox.wait_for_electrs(ELECTRS_TIMEOUT).await?;
+            assert_eq!(backend.block_hash(height as u32).await?, blocks[1]);
+
+            let (empty_height, empty) = sandbox.mine_empty().await?;
+            assert_eq!(empty_height, height + 1);
+
+            let reorged = sandbox.reorg(1, true).await?;
+            assert_eq!(reorged.len(), 1);
+            assert_ne!(reorged[0], empty);
+            assert_eq!(
+                sandbox.wait_for_electrs(ELECTRS_TIMEOUT).await?,
+                empty_height
+            );
+            assert_eq!(backend.block_hash(empty_height as u32).await?, reorged[0]);
+
+            // Deeper than the chain.
+            assert!(sandbox.reorg(empty_height + 1, false).await.is_err());
+            Ok(())
+        })
+    }
 }
@@ -1,7 +1,1061 @@
 #[cfg(test)]
 mod tests {
+    use crate::admin::token_matches;
+    use crate::attestation::MAX_OP_RETURN_DATA;
+    use crate::backend::BroadcastError;
+    use crate::batch::{
+        anchor_coins, anchor_payload, batch_due, build_replacement, leaf_hash, merkle_proof,
+        merkle_root, verify_proof, EconomyWrite, BATCH_MARKER,
+    };
+    use crate::config::{
+        parse_electrum_servers, parse_runway_thresholds, parse_thresholds, DescriptorKind,
+        ElectrumServerConfig, FanOutConfig,
+    };
+    use crate::electrum::{broadcast_outcome, ElectrumPool};
+    use crate::encryption::{decrypt, encrypt, ENCRYPTION_OVERHEAD, MAX_ENCRYPTED_OP_RETURN};
+    use crate::envelope::{
+        build_commit_reveal, envelope_keypair, estimate_envelope_weight, extract_envelope,
+        reveal_marker, Envelope,
+    };
+    use crate::error::{Graffiti, Report};
+    use crate::events::EventKind;
+    use crate::fanout::{build_split, pick_pool_coin};
+    use crate::indexer::{tx_op_returns, IndexedOpReturn};
+    use crate::monitor::{FundingLevel, Runway};
+    use crate::multisig::PendingWrite;
+    use crate::queue::{
+        blocked_coins, Mempool, PreparedWrite, QueuedWrite, QueuedWrites, MAX_ANCESTORS,
+        MAX_QUEUED_WRITES,
+    };
+    use crate::routes::{check_encrypted_size, duplicate_json, WriteOptions};
+    use crate::schedule::{ScheduleStatus, ScheduledWrite};
+    use crate::stream::{GraffitiRecord, StreamEvent};
+    use crate::tracker::{classify, TrackedWrite, Transition, WalletView};
+    use crate::treasury::{build_consolidation, build_full_sweep};
+    use crate::util::{
+        affordable_writes, bip21_uri, estimate_write_weight, insert_broadcast, op_return_pushes,
+        NETWORK,
+    };
+    use crate::webhooks::{backoff, is_public};
+    use crate::{EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR};
+    use axum::extract::Query;
+    use axum::http::{StatusCode, Uri};
+    use axum::response::IntoResponse;
+    use bdk_electrum::bdk_chain::Append;
+    use bdk_electrum::electrum_client::Error as ElectrumError;
+    use bdk_wallet::bitcoin::absolute::LockTime;
+    use bdk_wallet::bitcoin::block::{self, Header};
+    use bdk_wallet::bitcoin::hash_types::TxMerkleNode;
+    use bdk_wallet::bitcoin::hashes::Hash;
+    use bdk_wallet::bitcoin::key::Keypair;
+    use bdk_wallet::bitcoin::secp256k1::{rand, Message, Secp256k1};
+    use bdk_wallet::bitcoin::transaction::Version;
+    use bdk_wallet::bitcoin::{
+        ecdsa, Address, Amount, Block, BlockHash, CompactTarget, FeeRate, OutPoint, Psbt,
+        PublicKey, ScriptBuf, Transaction, TxIn, TxOut, Txid, Weight,
+    };
+    use bdk_wallet::{KeychainKind, Wallet};
+    use std::str::FromStr;
+    use std::time::Duration;
+
     #[test]
     fn test_two_plus_two() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_descriptor_kind() {
+        assert_eq!(
+            DescriptorKind::of(EXTERNAL_DESCRIPTOR).unwrap(),
+            DescriptorKind::Wpkh
+        );
+        let tr = EXTERNAL_DESCRIPTOR
+            .replace("wpkh(", "tr(")
+            .replace("/84'", "/86'");
+        assert_eq!(DescriptorKind::of(&tr).unwrap(), DescriptorKind::Tr);
+        let sh = EXTERNAL_DESCRIPTOR.replace("wpkh(", "pkh(");
+        assert!(DescriptorKind::of(&sh).is_err());
+    }
+
+    #[test]
+    fn test_write_weight() {
+        assert!(DescriptorKind::Tr.input_weight() < DescriptorKind::Wpkh.input_weight());
+        // a 40 byte write from a segwit v0 wallet is 161 vbytes with a 72 byte signature
+        let wpkh = estimate_write_weight(DescriptorKind::Wpkh, 40);
+        assert_eq!(wpkh.to_vbytes_ceil(), 161);
+        // the smaller taproot input is paid back by the larger change output
+        let tr = estimate_write_weight(DescriptorKind::Tr, 40);
+        assert_eq!(tr.to_vbytes_ceil(), 162);
+        // so taproot only saves once a write spends a second coin
+        let wpkh = wpkh + DescriptorKind::Wpkh.input_weight();
+        let tr = tr + DescriptorKind::Tr.input_weight();
+        assert_eq!(wpkh.to_vbytes_ceil(), 229);
+        assert_eq!(tr.to_vbytes_ceil(), 220);
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let data: Vec<u8> = (0..2_000_u32).map(|i| (i % 251) as u8).collect();
+        let keypair = Keypair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let envelope = Envelope::new(&data, keypair).unwrap();
+
+        let mut reveal = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                witness: envelope.witness(&[0; 64]),
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::new_op_return(reveal_marker(&data)),
+            }],
+        };
+        assert_eq!(extract_envelope(&reveal), Some(data));
+
+        // a marker for different data must not match
+        reveal.output[0].script_pubkey = ScriptBuf::new_op_return(reveal_marker(b"other"));
+        assert_eq!(extract_envelope(&reveal), None);
+    }
+
+    #[test]
+    fn test_envelope_weight() {
+        let (mut wallet, funding) = funded_wallet(&[500_000, 600_000]);
+        insert_broadcast(&mut wallet, &[&funding]);
+        let reserved = OutPoint::new(funding.compute_txid(), 1);
+        let data = vec![0x42; 2_000];
+        let (commit, reveal) = build_commit_reveal(
+            &mut wallet,
+            &data,
+            FeeRate::from_sat_per_vb_unchecked(2),
+            &[reserved],
+            DescriptorKind::Wpkh.sign_options(),
+        )
+        .unwrap();
+        assert_eq!(commit.input.len(), 1);
+        assert_ne!(commit.input[0].previous_output, reserved);
+
+        // Within a byte of each signature, which may be a byte shorter.
+        let actual = commit.weight() + reveal.weight();
+        let estimate = estimate_envelope_weight(DescriptorKind::Wpkh, data.len());
+        assert!(estimate >= actual);
+        assert!(estimate - actual <= Weight::from_wu(2));
+        // The payload is witness data, an OP_RETURN would weigh four times as much.
+        assert!(estimate < Weight::from_vb_unchecked(data.len() as u64));
+    }
+
+    #[test]
+    fn test_encryption_round_trip() {
+        let secp = Secp256k1::new();
+        let (secret, recipient) = secp.generate_keypair(&mut rand::thread_rng());
+        let (other_secret, _) = secp.generate_keypair(&mut rand::thread_rng());
+
+        let payload = encrypt(&recipient, b"for your eyes only").unwrap();
+        assert_eq!(payload.len(), 18 + ENCRYPTION_OVERHEAD);
+        assert_eq!(decrypt(&secret, &payload).unwrap(), b"for your eyes only");
+        assert!(decrypt(&other_secret, &payload).is_err());
+    }
+
+    #[test]
+    fn test_webhook_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(10));
+        assert_eq!(backoff(2), Duration::from_secs(20));
+        assert_eq!(backoff(5), Duration::from_secs(160));
+        assert_eq!(backoff(100), Duration::from_secs(6 * 60 * 60));
+    }
+
+    #[test]
+    fn test_parse_thresholds() {
+        assert_eq!(parse_thresholds("6, 3,6,").unwrap(), vec![3, 6]);
+        assert!(parse_thresholds("").unwrap().is_empty());
+        assert!(parse_thresholds("1").is_err());
+        assert!(parse_thresholds("101").is_err());
+        assert!(parse_thresholds("six").is_err());
+    }
+
+    #[test]
+    fn test_treasury_transactions() {
+        let (mut wallet, funding) = funded_wallet(&[10_000, 20_000, 30_000, 500_000]);
+        insert_broadcast(&mut wallet, &[&funding]);
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(1);
+        let sign_options = DescriptorKind::Wpkh.sign_options();
+
+        // The coin of a pending write stays out of the consolidation.
+        let reserved = [OutPoint::new(funding.compute_txid(), 2)];
+        let below = Amount::from_sat(50_000);
+        let consolidation = build_consolidation(
+            &mut wallet,
+            below,
+            &reserved,
+            fee_rate,
+            sign_options.clone(),
+        )
+        .unwrap()
+        .expect("two small coins");
+        assert_eq!(consolidation.tx.input.len(), 2);
+        assert_eq!(consolidation.tx.output.len(), 1);
+        assert_eq!(
+            consolidation.amount + consolidation.fee,
+            Amount::from_sat(30_000)
+        );
+        assert!(wallet.is_mine(&consolidation.destination.script_pubkey()));
+        let all_reserved = [
+            OutPoint::new(funding.compute_txid(), 0),
+            OutPoint::new(funding.compute_txid(), 1),
+        ];
+        assert!(build_consolidation(
+            &mut wallet,
+            below,
+            &all_reserved,
+            fee_rate,
+            sign_options.clone()
+        )
+        .unwrap()
+        .is_none());
+
+        let cold = Wallet::new(
+            &EXTERNAL_DESCRIPTOR.replace("/0/*", "/2/*"),
+            &INTERNAL_DESCRIPTOR.replace("/1/*", "/3/*"),
+            NETWORK,
+        )
+        .unwrap()
+        .peek_address(KeychainKind::External, 0)
+        .address;
+        let sweep = build_full_sweep(&mut wallet, &cold, fee_rate, sign_options)
+            .unwrap()
+            .expect("the wallet has coins");
+        assert_eq!(sweep.tx.input.len(), 4);
+        assert_eq!(sweep.amount + sweep.fee, Amount::from_sat(560_000));
+    }
+
+    /// A wallet and an unconfirmed transaction paying each of `values` to one of its receive
+    /// addresses. Insert or confirm the transaction to fund the wallet.
+    fn funded_wallet(values: &[u64]) -> (Wallet, Transaction) {
+        let mut wallet = Wallet::new(EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR, NETWORK).unwrap();
+        let output = values
+            .iter()
+            .map(|value| TxOut {
+                value: Amount::from_sat(*value),
+                script_pubkey: wallet
+                    .reveal_next_address(KeychainKind::External)
+                    .script_pubkey(),
+            })
+            .collect();
+        let funding = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                ..TxIn::default()
+            }],
+            output,
+        };
+        (wallet, funding)
+    }
+
+    /// Confirms `txdata` in a block on top of the wallet's tip.
+    fn confirm(wallet: &mut Wallet, txdata: Vec<Transaction>) {
+        let tip = wallet.latest_checkpoint();
+        let block = Block {
+            header: Header {
+                version: block::Version::TWO,
+                prev_blockhash: tip.hash(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207f_ffff),
+                nonce: 0,
+            },
+            txdata,
+        };
+        wallet
+            .apply_block_connected_to(&block, tip.height() + 1, tip.block_id())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let (mut wallet, funding) = funded_wallet(&[1_000_000, 15_000]);
+        let large = OutPoint::new(funding.compute_txid(), 0);
+        let small = OutPoint::new(funding.compute_txid(), 1);
+        confirm(&mut wallet, vec![funding]);
+
+        let config = FanOutConfig {
+            size: 4,
+            coin: Amount::from_sat(20_000),
+        };
+        assert!(config.is_pool_coin(Amount::from_sat(15_000)));
+        assert!(!config.is_pool_coin(Amount::from_sat(1_000_000)));
+        let fee = Amount::from_sat(500);
+        assert_eq!(pick_pool_coin(&wallet, &config, &[], fee), Some(small));
+        assert_eq!(pick_pool_coin(&wallet, &config, &[small], fee), None);
+
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(1);
+        let split = build_split(
+            &mut wallet,
+            &config,
+            Vec::new(),
+            fee_rate,
+            DescriptorKind::Wpkh.sign_options(),
+        )
+        .unwrap()
+        .expect("the pool is short of three coins");
+        assert_eq!(split.input.len(), 1);
+        assert_eq!(split.input[0].previous_output, large);
+        let coins = split
+            .output
+            .iter()
+            .filter(|output| output.value == config.coin)
+            .count();
+        assert_eq!(coins, 3);
+
+        // Unconfirmed pool coins count, the split isn't repeated.
+        insert_broadcast(&mut wallet, &[&split]);
+        assert!(build_split(
+            &mut wallet,
+            &config,
+            Vec::new(),
+            fee_rate,
+            DescriptorKind::Wpkh.sign_options(),
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn test_mempool_limits() {
+        let (mut wallet, funding) = funded_wallet(&[1_000_000]);
+        let script = wallet
+            .reveal_next_address(KeychainKind::External)
+            .script_pubkey();
+        let spend = |previous_output: OutPoint, value: u64| Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: script.clone(),
+            }],
+        };
+        let mut parent = OutPoint::new(funding.compute_txid(), 0);
+        confirm(&mut wallet, vec![funding]);
+
+        // A chain of unconfirmed writes, each spending the change of the one before.
+        let mut chain = Vec::new();
+        for fee in 1..=MAX_ANCESTORS as u64 {
+            let tx = spend(parent, 1_000_000 - 1_000 * fee);
+            parent = OutPoint::new(tx.compute_txid(), 0);
+            insert_broadcast(&mut wallet, &[&tx]);
+            chain.push(tx);
+            if chain.len() < MAX_ANCESTORS {
+                assert!(blocked_coins(&wallet).is_empty());
+            }
+        }
+        let mempool = Mempool::of(&wallet);
+        assert_eq!(mempool.ancestors(parent.txid).len(), MAX_ANCESTORS - 1);
+        assert_eq!(
+            mempool.descendants(chain[0].compute_txid()).len(),
+            MAX_ANCESTORS - 1
+        );
+        let blocked = blocked_coins(&wallet);
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0].0, parent);
+        assert!(blocked[0].1.contains("ancestors"));
+
+        // Confirming the start of the chain releases the coin.
+        confirm(&mut wallet, chain[..2].to_vec());
+        assert!(blocked_coins(&wallet).is_empty());
+
+        let write = PreparedWrite {
+            data: "hello".to_string(),
+            payload: b"hello".to_vec(),
+            attestation: None,
+            on_chain: false,
+            fee_target: 6,
+            replaceable: false,
+            envelope: false,
+        };
+        // A write left queued by the previous run stays first.
+        let mut queue =
+            QueuedWrites::restore(vec![QueuedWrite::new(write.clone(), "blocked".to_string())]);
+        let first = queue.front().unwrap().id;
+        let second = QueuedWrite::new(write.clone(), "blocked".to_string());
+        let second_id = second.id;
+        assert_eq!(queue.push(second), 2);
+        assert_eq!(queue.front().unwrap().id, first);
+        queue.finish(first, "broadcast", serde_json::json!({}));
+        assert_eq!(queue.get(first).unwrap()["status"], "broadcast");
+        assert_eq!(queue.get(second_id).unwrap()["position"], 1);
+        assert!(queue.get(uuid::Uuid::new_v4()).is_none());
+
+        // Past the cap new writes are refused rather than queued.
+        while !queue.is_full() {
+            queue.push(QueuedWrite::new(write.clone(), "blocked".to_string()));
+        }
+        assert_eq!(queue.to_json().len(), MAX_QUEUED_WRITES);
+    }
+
+    #[test]
+    fn test_economy_batches() {
+        for count in 1..=7u8 {
+            let payloads: Vec<Vec<u8>> = (0..count).map(|i| vec![i; 3]).collect();
+            let leaves: Vec<_> = payloads.iter().map(|payload| leaf_hash(payload)).collect();
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index);
+                assert!(verify_proof(*leaf, &proof, root));
+                assert!(!verify_proof(leaf_hash(b"other"), &proof, root));
+            }
+        }
+        let single = leaf_hash(b"graffiti");
+        assert_eq!(merkle_root(&[single]), single);
+        let anchor = anchor_payload(single);
+        assert_eq!(anchor.len(), 36);
+        assert!(anchor.starts_with(BATCH_MARKER));
+
+        let now = chrono::Utc::now();
+        let max = FeeRate::from_sat_per_vb_unchecked(2);
+        let high = FeeRate::from_sat_per_vb_unchecked(20);
+        let write = EconomyWrite::new(
+            "hello".to_string(),
+            b"hello".to_vec(),
+            now + chrono::Duration::hours(1),
+        );
+        assert!(!batch_due(&[], max, max, now));
+        assert!(batch_due(&[write.clone()], max, max, now));
+        assert!(!batch_due(&[write.clone()], high, max, now));
+        assert!(batch_due(
+            &[write],
+            high,
+            max,
+            now + chrono::Duration::hours(2)
+        ));
+    }
+
+    #[test]
+    fn test_anchor_replacement() {
+        let (mut wallet, funding) = funded_wallet(&[100_000]);
+        confirm(&mut wallet, vec![funding]);
+
+        let first = anchor_payload(merkle_root(&[leaf_hash(b"one")]));
+        let push = bdk_wallet::bitcoin::script::PushBytesBuf::try_from(first).unwrap();
+        let mut tx_builder = wallet.build_tx();
+        tx_builder
+            .add_data(&push)
+            .fee_rate(FeeRate::from_sat_per_vb_unchecked(1))
+            .enable_rbf();
+        let mut psbt = tx_builder.finish().unwrap();
+        assert!(wallet
+            .sign(&mut psbt, DescriptorKind::Wpkh.sign_options())
+            .unwrap());
+        let anchor = psbt.extract_tx().unwrap();
+        insert_broadcast(&mut wallet, &[&anchor]);
+
+        // The anchor's change stays out of other transactions while it can be replaced.
+        let change = anchor_coins(&wallet, &[anchor.compute_txid()]);
+        assert_eq!(change.len(), 1);
+        assert_eq!(change[0].txid, anchor.compute_txid());
+        assert!(anchor_coins(&wallet, &[]).is_empty());
+
+        // A write joining while fees are high pays only the least bump the mempool accepts.
+        let second = anchor_payload(merkle_root(&[leaf_hash(b"one"), leaf_hash(b"two")]));
+        let replacement = build_replacement(
+            &mut wallet,
+            anchor.compute_txid(),
+            second.clone(),
+            FeeRate::ZERO,
+            DescriptorKind::Wpkh.sign_options(),
+        )
+        .unwrap();
+        let bumped = wallet.calculate_fee_rate(&replacement).unwrap();
+        assert!(bumped >= FeeRate::from_sat_per_vb_unchecked(2));
+        assert!(bumped < FeeRate::from_sat_per_vb_unchecked(3));
+        assert_eq!(
+            replacement.input[0].previous_output,
+            anchor.input[0].previous_output
+        );
+        assert!(replacement
+            .output
+            .iter()
+            .any(|output| op_return_pushes(&output.script_pubkey) == Some(vec![second.clone()])));
+        assert!(
+            wallet.calculate_fee(&replacement).unwrap() > wallet.calculate_fee(&anchor).unwrap()
+        );
+
+        confirm(&mut wallet, vec![replacement.clone()]);
+        assert!(anchor_coins(&wallet, &[replacement.compute_txid()]).is_empty());
+    }
+
+    #[test]
+    fn test_scheduled_writes() {
+        let now = chrono::Utc::now();
+        let later = now + chrono::Duration::hours(1);
+        let write = |height, time| {
+            ScheduledWrite::new("hi".to_string(), b"hi".to_vec(), false, height, time)
+        };
+
+        assert!(write(None, None).is_due(0, now));
+        assert!(!write(Some(100), None).is_due(99, now));
+        assert!(write(Some(100), None).is_due(100, now));
+        assert!(!write(None, Some(later)).is_due(100, now));
+        assert!(write(None, Some(later)).is_due(100, later));
+        // Both have to be reached.
+        assert!(!write(Some(100), Some(later)).is_due(100, now));
+        assert!(!write(Some(100), Some(later)).is_due(99, later));
+        assert!(write(Some(100), Some(later)).is_due(100, later));
+
+        for status in [
+            ScheduleStatus::Scheduled,
+            ScheduleStatus::Sending,
+            ScheduleStatus::Sent,
+            ScheduleStatus::Failed,
+            ScheduleStatus::Cancelled,
+        ] {
+            assert_eq!(ScheduleStatus::from_str(status.as_str()).unwrap(), status);
+        }
+        assert!(ScheduleStatus::from_str("done").is_err());
+    }
+
+    #[test]
+    fn test_funding_levels() {
+        assert_eq!(
+            parse_runway_thresholds("10, 100,10").unwrap(),
+            vec![100, 10]
+        );
+        assert!(parse_runway_thresholds("0").is_err());
+
+        let thresholds = [100, 10];
+        assert_eq!(FundingLevel::of(500, &thresholds), FundingLevel::Funded);
+        assert_eq!(FundingLevel::of(100, &thresholds), FundingLevel::Funded);
+        assert_eq!(FundingLevel::of(50, &thresholds), FundingLevel::Low(100));
+        assert_eq!(FundingLevel::of(9, &thresholds), FundingLevel::Low(10));
+        assert_eq!(FundingLevel::of(0, &thresholds), FundingLevel::Unfunded);
+
+        // Only dropping below a threshold and full recovery are announced.
+        let low = FundingLevel::Low(100);
+        let lower = FundingLevel::Low(10);
+        assert_eq!(FundingLevel::Funded.alert(None), None);
+        assert_eq!(low.alert(None), Some(EventKind::LowBalance));
+        assert_eq!(low.alert(Some(low)), None);
+        assert_eq!(lower.alert(Some(low)), Some(EventKind::LowBalance));
+        assert_eq!(low.alert(Some(lower)), None);
+        assert_eq!(
+            FundingLevel::Unfunded.alert(Some(lower)),
+            Some(EventKind::Unfunded)
+        );
+        assert_eq!(lower.alert(Some(FundingLevel::Unfunded)), None);
+        assert_eq!(
+            FundingLevel::Funded.alert(Some(FundingLevel::Unfunded)),
+            Some(EventKind::Funded)
+        );
+
+        let mut runway = Runway::default();
+        assert_eq!(
+            runway.sample(FeeRate::from_sat_per_vb_unchecked(2)),
+            FeeRate::from_sat_per_vb_unchecked(2)
+        );
+        assert_eq!(
+            runway.sample(FeeRate::from_sat_per_vb_unchecked(4)),
+            FeeRate::from_sat_per_vb_unchecked(3)
+        );
+    }
+
+    #[test]
+    fn test_parse_electrum_servers() {
+        let servers =
+            parse_electrum_servers("ssl://a.example:50002, tcp://b.example:50001").unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[1].url, "tcp://b.example:50001");
+        assert!(servers[1].validate_domain);
+
+        let servers = parse_electrum_servers(
+            r#"[{"url": "ssl://node.onion:50002", "validate_domain": false, "socks5": "127.0.0.1:9050"}]"#,
+        )
+        .unwrap();
+        assert!(!servers[0].validate_domain);
+        assert_eq!(servers[0].socks5.as_deref(), Some("127.0.0.1:9050"));
+        assert_eq!(servers[0].timeout, Some(10));
+
+        assert!(parse_electrum_servers(" , ").is_err());
+        assert!(parse_electrum_servers("a.example:50002").is_err());
+    }
+
+    #[test]
+    fn test_bip21_uri() {
+        let address = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+            .unwrap()
+            .assume_checked();
+        assert_eq!(
+            bip21_uri(&address, None, None),
+            "bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+        );
+        assert_eq!(
+            bip21_uri(&address, Some(Amount::from_sat(100_000)), Some("top up #1")),
+            "bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx?amount=0.001&label=top%20up%20%231"
+        );
+    }
+
+    #[test]
+    fn test_affordable_writes() {
+        // 161 vbytes at 2 sat/vb
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(2);
+        let spendable = Amount::from_sat(322 * 10 + 100);
+        assert_eq!(
+            affordable_writes(DescriptorKind::Wpkh, 40, spendable, fee_rate),
+            10
+        );
+        assert_eq!(
+            affordable_writes(DescriptorKind::Wpkh, 40, Amount::ZERO, fee_rate),
+            0
+        );
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("0123456789abcdef", "0123456789abcdef"));
+        assert!(!token_matches("0123456789abcdef", "0123456789abcdeF"));
+        assert!(!token_matches("0123456789abcdef", "0123456789abcde"));
+        assert!(!token_matches("0123456789abcdef", "0123456789abcdef0"));
+        assert!(!token_matches("0123456789abcdef", ""));
+    }
+
+    /// Fills the `{k}` threshold and the `{a}` to `{d}` keys of `template`, each key from its
+    /// own account of the test master key.
+    fn multisig_descriptor(template: &str, threshold: usize) -> String {
+        let tprv = &EXTERNAL_DESCRIPTOR["wpkh(".len()..EXTERNAL_DESCRIPTOR.find('/').unwrap()];
+        let key = |account: u32| format!("{tprv}/48'/1'/{account}'/0/*");
+        template
+            .replace("{k}", &threshold.to_string())
+            .replace("{a}", &key(0))
+            .replace("{b}", &key(1))
+            .replace("{c}", &key(2))
+            .replace("{d}", &key(3))
+    }
+
+    #[test]
+    fn test_multisig_descriptor_kind() {
+        let wsh = multisig_descriptor("wsh(sortedmulti({k},{a},{b},{c}))", 2);
+        let kind = DescriptorKind::of(&wsh).unwrap();
+        assert_eq!(
+            kind,
+            DescriptorKind::WshMulti {
+                threshold: 2,
+                signers: 3
+            }
+        );
+        assert_eq!(kind.threshold(), Some(2));
+
+        let tr = multisig_descriptor("tr({a},multi_a({k},{b},{c}))", 1);
+        let kind = DescriptorKind::of(&tr).unwrap();
+        assert_eq!(
+            kind,
+            DescriptorKind::TrMulti {
+                threshold: 1,
+                signers: 2
+            }
+        );
+        assert_eq!(kind.threshold(), Some(1));
+        assert_eq!(DescriptorKind::Tr.threshold(), None);
+
+        // unsorted multi, a leaf that isn't multi_a and more than one leaf are refused
+        let unsorted = multisig_descriptor("wsh(multi({k},{a},{b},{c}))", 2);
+        assert!(DescriptorKind::of(&unsorted).is_err());
+        let pk_leaf = multisig_descriptor("tr({a},pk({b}))", 1);
+        assert!(DescriptorKind::of(&pk_leaf).is_err());
+        let two_leaves = multisig_descriptor("tr({a},{multi_a({k},{b},{c}),pk({d})})", 1);
+        assert!(DescriptorKind::of(&two_leaves).is_err());
+    }
+
+    #[test]
+    fn test_multisig_weight() {
+        // 2-of-3 wsh: empty dummy, two 73 byte signatures and a 105 byte witness script
+        let wsh = DescriptorKind::WshMulti {
+            threshold: 2,
+            signers: 3,
+        };
+        assert_eq!(wsh.input_weight().to_wu(), 164 + 254);
+        // 2-of-3 multi_a: two 65 byte signatures, one empty push, a 104 byte leaf script and
+        // the control block
+        let tr = DescriptorKind::TrMulti {
+            threshold: 2,
+            signers: 3,
+        };
+        assert_eq!(tr.input_weight().to_wu(), 164 + 271);
+        // both pay change to a 34 byte script
+        assert_eq!(wsh.output_weight(), DescriptorKind::Tr.output_weight());
+        assert_eq!(tr.output_weight(), DescriptorKind::Tr.output_weight());
+        assert!(estimate_write_weight(wsh, 40) < estimate_write_weight(tr, 40));
+    }
+
+    #[test]
+    fn test_pending_write_threshold() {
+        let secp = Secp256k1::new();
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::new_op_return([1, 2, 3]),
+            }],
+        };
+        let signed_by = |count: usize| {
+            let mut psbt = Psbt::from_unsigned_tx(tx.clone()).unwrap();
+            for _ in 0..count {
+                let (secret, public) = secp.generate_keypair(&mut rand::thread_rng());
+                let signature = secp.sign_ecdsa(&Message::from_digest([1; 32]), &secret);
+                psbt.inputs[0].partial_sigs.insert(
+                    PublicKey::new(public),
+                    ecdsa::Signature::sighash_all(signature),
+                );
+            }
+            psbt
+        };
+
+        let mut write = PendingWrite::new("hello".to_string(), signed_by(1), 2);
+        assert_eq!(write.signatures(), 1);
+        assert_eq!(write.txid(), tx.compute_txid());
+
+        // a PSBT for another transaction is refused and leaves the signatures alone
+        let mut other = signed_by(1);
+        other.unsigned_tx.lock_time = LockTime::from_consensus(1);
+        assert!(write.combine(other).is_err());
+        assert_eq!(write.signatures(), 1);
+
+        // a cosigner's signature brings the write to its threshold
+        write.combine(signed_by(1)).unwrap();
+        assert_eq!(write.signatures(), 2);
+        assert!(write.signatures() >= write.threshold);
+        assert_eq!(write.to_json()["signatures"], 2);
+    }
+
+    #[test]
+    fn test_commit_reveal() {
+        let (mut wallet, funding) = funded_wallet(&[100_000]);
+        confirm(&mut wallet, vec![funding]);
+        let data = vec![7; 1_000];
+        let (commit, reveal) = build_commit_reveal(
+            &mut wallet,
+            &data,
+            FeeRate::from_sat_per_vb_unchecked(2),
+            &[],
+            DescriptorKind::Wpkh.sign_options(),
+        )
+        .unwrap();
+        assert_eq!(reveal.input.len(), 1);
+        assert_eq!(reveal.input[0].previous_output.txid, commit.compute_txid());
+        assert_eq!(extract_envelope(&reveal), Some(data.clone()));
+
+        // the envelope key can be derived again from the wallet alone
+        let keypair = envelope_keypair(&wallet, &data).unwrap();
+        assert_ne!(envelope_keypair(&wallet, b"other").unwrap(), keypair);
+        let envelope = Envelope::new(&data, keypair).unwrap();
+        let prevout = &commit.output[reveal.input[0].previous_output.vout as usize];
+        assert_eq!(prevout.script_pubkey, envelope.script_pubkey());
+        envelope.verify_reveal(prevout, &reveal).unwrap();
+
+        // a reveal that spends more than the envelope holds is caught before the commit
+        let mut greedy = reveal.clone();
+        let change = greedy
+            .output
+            .iter_mut()
+            .find(|output| !output.script_pubkey.is_op_return())
+            .unwrap();
+        change.value = prevout.value + Amount::from_sat(1);
+        assert!(envelope.verify_reveal(prevout, &greedy).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_op_return_size() {
+        let secp = Secp256k1::new();
+        let (_, recipient) = secp.generate_keypair(&mut rand::thread_rng());
+        let largest = encrypt(&recipient, &[7; MAX_ENCRYPTED_OP_RETURN]).unwrap();
+        assert_eq!(largest.len(), MAX_OP_RETURN_DATA);
+
+        assert!(check_encrypted_size(MAX_ENCRYPTED_OP_RETURN).is_ok());
+        let response = check_encrypted_size(MAX_ENCRYPTED_OP_RETURN + 1)
+            .unwrap_err()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_webhook_addresses() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        assert!(public("1.1.1.1"));
+        assert!(public("2606:4700:4700::1111"));
+        for internal in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(internal), "{internal} is not public");
+        }
+    }
+
+    #[test]
+    fn test_stream_record() {
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::new_op_return(b"graffiti"),
+            }],
+        };
+        let op_return = tx_op_returns(&tx, None).pop().unwrap();
+        assert_eq!(op_return.payload, b"graffiti");
+
+        let mempool = GraffitiRecord {
+            cursor: 7,
+            event: StreamEvent::Mempool,
+            op_return: op_return.clone(),
+        };
+        let j = mempool.to_json();
+        assert_eq!(j["cursor"], 7);
+        assert_eq!(j["event"], "mempool");
+        assert_eq!(j["text"], "graffiti");
+        assert_eq!(j["chain_position"]["type"], "Unconfirmed");
+
+        let confirmed = GraffitiRecord {
+            cursor: 8,
+            event: StreamEvent::Confirmed,
+            op_return: IndexedOpReturn {
+                height: Some(100),
+                ..op_return
+            },
+        };
+        assert_eq!(
+            confirmed.to_json()["chain_position"]["anchor"]["height"],
+            100
+        );
+        for event in [
+            StreamEvent::Mempool,
+            StreamEvent::Confirmed,
+            StreamEvent::Reorged,
+        ] {
+            assert_eq!(StreamEvent::from_str(event.as_str()).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn test_wallet_checkpoint() {
+        let (mut wallet, tx) = funded_wallet(&[50_000]);
+        confirm(&mut wallet, vec![tx]);
+        let mut changeset = wallet.take_staged().unwrap();
+        // Saved in pieces, merged when the service starts.
+        let _ = wallet.next_unused_address(KeychainKind::External);
+        changeset.append(wallet.take_staged().unwrap());
+        let stored = serde_json::to_value(&changeset).unwrap();
+
+        let loaded = Wallet::new_or_load(
+            EXTERNAL_DESCRIPTOR,
+            INTERNAL_DESCRIPTOR,
+            Some(serde_json::from_value(stored).unwrap()),
+            NETWORK,
+        )
+        .unwrap();
+        assert_eq!(loaded.balance(), wallet.balance());
+        assert_eq!(
+            loaded.latest_checkpoint().block_id(),
+            wallet.latest_checkpoint().block_id()
+        );
+        assert_eq!(
+            loaded.derivation_index(KeychainKind::External),
+            wallet.derivation_index(KeychainKind::External)
+        );
+
+        // A wallet saved for other descriptors is not picked up.
+        assert!(Wallet::new_or_load(
+            INTERNAL_DESCRIPTOR,
+            EXTERNAL_DESCRIPTOR,
+            Some(changeset),
+            NETWORK
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_electrum_breaker() {
+        let pool = ElectrumPool::new(&[
+            ElectrumServerConfig::new("tcp://first:50001"),
+            ElectrumServerConfig::new("tcp://second:50001"),
+        ]);
+        let first = pool.primary();
+        assert_eq!(first.config.url, "tcp://first:50001");
+
+        // One failure only ranks the server after healthy ones, three open its breaker.
+        first.record_failure(&"timed out");
+        assert_eq!(pool.to_json()["servers"][0]["state"], "degraded");
+        first.record_failure(&"timed out");
+        first.record_failure(&"timed out");
+        assert_eq!(pool.to_json()["servers"][0]["state"], "open");
+        assert_eq!(pool.primary().config.url, "tcp://second:50001");
+        assert!(pool.is_healthy());
+
+        // A probe that goes through closes it again.
+        first.record_success(Some(100), None);
+        assert_eq!(pool.to_json()["servers"][0]["state"], "healthy");
+        assert_eq!(pool.primary().config.url, "tcp://first:50001");
+    }
+
+    #[test]
+    fn test_broadcast_outcome() {
+        let rejected = || -> Result<(), ElectrumError> {
+            Err(ElectrumError::Protocol(serde_json::json!(
+                "bad-txns-inputs-missingorspent"
+            )))
+        };
+        let down = || -> Result<(), ElectrumError> {
+            Err(ElectrumError::Message("connection refused".to_string()))
+        };
+        assert!(broadcast_outcome([down(), Ok(()), rejected()]).is_ok());
+        assert!(matches!(
+            broadcast_outcome([down(), rejected(), down()]),
+            Err(ElectrumError::Protocol(_))
+        ));
+        assert!(matches!(
+            broadcast_outcome([down(), down()]),
+            Err(ElectrumError::Message(_))
+        ));
+        assert!(broadcast_outcome([]).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_payloads() {
+        let options = |query: &str| {
+            let uri: Uri = format!("http://localhost/write_op_return/hi?{query}")
+                .parse()
+                .unwrap();
+            Query::<WriteOptions>::try_from_uri(&uri).unwrap().0
+        };
+        // The request overrides GRAFFITI_DEDUP either way.
+        assert!(options("").dedup(true));
+        assert!(!options("").dedup(false));
+        assert!(!options("dedup=false").dedup(true));
+        assert!(options("dedup=true&attest=true").dedup(false));
+        // A fresh key per encryption, an encrypted payload is never looked up.
+        assert!(!options("dedup=true&encrypt_to=02ab").dedup(true));
+
+        let txid = Txid::all_zeros();
+        let j = duplicate_json(txid, false, false).unwrap();
+        assert_eq!(j["txid"], txid.to_string());
+        assert_eq!(j["duplicate"], true);
+        assert_eq!(j["attested"], false);
+        // An attestation is asked for and the earlier write has none, so it is written again.
+        assert!(duplicate_json(txid, false, true).is_none());
+        assert_eq!(duplicate_json(txid, true, true).unwrap()["attested"], true);
+        assert_eq!(duplicate_json(txid, true, false).unwrap()["attested"], true);
+
+        // Queued writes count before they are broadcast, envelopes don't.
+        let write = |envelope: bool| PreparedWrite {
+            data: "hello".to_string(),
+            payload: b"hello".to_vec(),
+            attestation: None,
+            on_chain: false,
+            fee_target: 6,
+            replaceable: false,
+            envelope,
+        };
+        let mut queue = QueuedWrites::default();
+        queue.push(QueuedWrite::new(write(true), "limits".to_string()));
+        assert!(queue.find_payload(b"hello", false).is_none());
+        let queued = QueuedWrite::new(write(false), "limits".to_string());
+        let id = queued.id;
+        queue.push(queued);
+        let j = queue.find_payload(b"hello", false).unwrap();
+        assert_eq!(j["id"], id.to_string());
+        assert_eq!(j["position"], 2);
+        assert_eq!(j["duplicate"], true);
+        assert!(queue.find_payload(b"hello", true).is_none());
+        assert!(queue.find_payload(b"other", false).is_none());
+    }
+
+    #[test]
+    fn test_rejection_errors() {
+        assert!(Report::from(BroadcastError::Rejected("bad-txns".to_string())).is_rejection());
+        assert!(Report::from(Graffiti::BadRequest("too large".to_string())).is_rejection());
+        // Backend outages and an unfunded wallet may pass, the write is retried.
+        assert!(!Report::from(BroadcastError::Other(anyhow::anyhow!("timeout"))).is_rejection());
+        assert!(!Report::from(Graffiti::Unfunded("empty".to_string())).is_rejection());
+        assert!(!Report::from(Graffiti::Anyhow(anyhow::anyhow!("refused"))).is_rejection());
+    }
+
+    #[test]
+    fn test_tracker_transitions() {
+        let (_, tx) = funded_wallet(&[10_000]);
+        let write = |confirmation: Option<(u32, BlockHash)>| TrackedWrite {
+            txid: tx.compute_txid(),
+            tx: tx.clone(),
+            confirmation,
+            reorged_height: None,
+            notified_confirmations: 1,
+        };
+        let view = |height: Option<u32>, replacement: Option<Txid>| WalletView {
+            height,
+            replacement,
+        };
+        let confirmed = write(Some((100, BlockHash::all_zeros())));
+        let unconfirmed = write(None);
+        let other = Txid::from_byte_array([1; 32]);
+
+        assert_eq!(
+            classify(&confirmed, true, &view(Some(100), None), true),
+            Transition::Buried { height: 100 }
+        );
+        // Reorged out and mined again in another block.
+        let reconfirmed = classify(&confirmed, false, &view(Some(101), None), true);
+        assert_eq!(
+            reconfirmed,
+            Transition::Confirmed {
+                height: 101,
+                lost: Some(100)
+            }
+        );
+        assert_eq!(reconfirmed.lost(), Some(100));
+        // Reorged out and back in the mempool.
+        assert_eq!(
+            classify(&confirmed, false, &view(None, None), true),
+            Transition::Unconfirmed { lost: Some(100) }
+        );
+        // Reorged out and gone from the backend, so it is rebroadcast.
+        assert_eq!(
+            classify(&confirmed, false, &view(None, None), false),
+            Transition::Dropped { lost: Some(100) }
+        );
+        assert_eq!(
+            classify(&unconfirmed, false, &view(Some(100), None), false),
+            Transition::Confirmed {
+                height: 100,
+                lost: None
+            }
+        );
+        // A conflicting wallet transaction only counts once the backend dropped the write.
+        assert_eq!(
+            classify(&unconfirmed, false, &view(None, Some(other)), true),
+            Transition::Unconfirmed { lost: None }
+        );
+        assert_eq!(
+            classify(&unconfirmed, false, &view(None, Some(other)), false),
+            Transition::Replaced {
+                replacement: other,
+                lost: None
+            }
+        );
+        assert_eq!(
+            classify(&unconfirmed, false, &view(None, None), false).lost(),
+            None
+        );
+    }
 }
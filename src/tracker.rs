@@ -0,0 +1,452 @@
+use anyhow::bail;
+use bdk_electrum::bdk_chain::ChainPosition;
+use bdk_wallet::bitcoin::hex::DisplayHex;
+use bdk_wallet::bitcoin::{Amount, BlockHash, Transaction, Txid};
+use bdk_wallet::Wallet;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::backend::BroadcastError;
+use crate::db;
+use crate::events::{EventDetail, EventKind, WriteEvent};
+use crate::util::GrafittiState;
+
+/// How often writes are checked for confirmations, reorgs and drops from the mempool.
+const TRACK_INTERVAL: Duration = Duration::from_secs(30);
+/// Confirmations after which a write is no longer checked for reorgs.
+pub const FINALITY_DEPTH: u32 = 100;
+
+/// What a recorded transaction is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteKind {
+    OpReturn,
+    /// Funds the envelope output of a [`WriteKind::Reveal`].
+    Commit,
+    Reveal,
+}
+
+impl WriteKind {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::OpReturn => "op_return",
+            Self::Commit => "commit",
+            Self::Reveal => "reveal",
+        }
+    }
+}
+
+impl fmt::Display for WriteKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for WriteKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "op_return" => Self::OpReturn,
+            "commit" => Self::Commit,
+            "reveal" => Self::Reveal,
+            _ => bail!("unknown write kind {s}"),
+        })
+    }
+}
+
+/// A block a write was confirmed in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Confirmation {
+    pub height: u32,
+    pub block_hash: BlockHash,
+    pub confirmed_at: DateTime<Utc>,
+    /// Set once the block was reorged out.
+    pub reorged_at: Option<DateTime<Utc>>,
+}
+
+/// A write from the history, with every confirmation it ever had.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteRecord {
+    pub txid: Txid,
+    pub kind: WriteKind,
+    pub payload: Vec<u8>,
+    /// `unconfirmed`, `confirmed`, `replaced` or `failed`.
+    pub status: String,
+    /// Fee the transaction paid, unknown for writes recorded before fees were stored.
+    pub fee: Option<Amount>,
+    pub created_at: DateTime<Utc>,
+    pub confirmations: Vec<Confirmation>,
+}
+
+impl WriteRecord {
+    /// The confirmation that still stands, if any.
+    pub fn confirmation(&self) -> Option<&Confirmation> {
+        self.confirmations
+            .iter()
+            .find(|confirmation| confirmation.reorged_at.is_none())
+    }
+
+    pub fn to_json(&self, events: &[WriteEvent]) -> serde_json::Value {
+        let confirmations: Vec<_> = self
+            .confirmations
+            .iter()
+            .map(|confirmation| {
+                json!({
+                    "height": confirmation.height,
+                    "block_hash": confirmation.block_hash,
+                    "confirmed_at": confirmation.confirmed_at,
+                    "reorged_at": confirmation.reorged_at,
+                })
+            })
+            .collect();
+        json!({
+            "txid": self.txid,
+            "kind": self.kind,
+            "payload": self.payload.to_lower_hex_string(),
+            "text": std::str::from_utf8(&self.payload).ok(),
+            "status": self.status,
+            "fee": self.fee.map(Amount::to_sat),
+            "height": self.confirmation().map(|confirmation| confirmation.height),
+            "created_at": self.created_at,
+            "confirmations": confirmations,
+            "events": events,
+        })
+    }
+}
+
+/// A write that may still change state, with what the tracker needs to check it.
+#[derive(Clone, Debug)]
+pub struct TrackedWrite {
+    pub txid: Txid,
+    pub tx: Transaction,
+    /// Height and block of the confirmation that still stands.
+    pub confirmation: Option<(u32, BlockHash)>,
+    /// Height of the most recent confirmation lost to a reorg.
+    pub reorged_height: Option<u32>,
+    /// Highest confirmation threshold already announced for the standing confirmation.
+    pub notified_confirmations: u32,
+}
+
+/// What a tracker pass makes of a write. `lost` is the height of a confirmation whose block
+/// was reorged out since the last pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// The standing confirmation at `height` still holds.
+    Buried {
+        height: u32,
+    },
+    Confirmed {
+        height: u32,
+        lost: Option<u32>,
+    },
+    /// Still in the mempool of the chain backend.
+    Unconfirmed {
+        lost: Option<u32>,
+    },
+    /// Gone from the backend, another wallet transaction spends one of its inputs.
+    Replaced {
+        replacement: Txid,
+        lost: Option<u32>,
+    },
+    /// Gone from the backend, to be broadcast again.
+    Dropped {
+        lost: Option<u32>,
+    },
+}
+
+impl Transition {
+    pub const fn lost(self) -> Option<u32> {
+        match self {
+            Self::Buried { .. } => None,
+            Self::Confirmed { lost, .. }
+            | Self::Unconfirmed { lost }
+            | Self::Replaced { lost, .. }
+            | Self::Dropped { lost } => lost,
+        }
+    }
+}
+
+/// Classifies `write` from whether its recorded block is still in the best chain (`standing`),
+/// what the wallet sees and whether the chain backend still has the transaction (`known`).
+/// `known` only matters when the wallet doesn't see the write confirmed.
+pub fn classify(
+    write: &TrackedWrite,
+    standing: bool,
+    view: &WalletView,
+    known: bool,
+) -> Transition {
+    let lost = match write.confirmation {
+        Some((height, _)) if standing => return Transition::Buried { height },
+        Some((height, _)) => Some(height),
+        None => None,
+    };
+    // A write the wallet no longer considers canonical may have been replaced, one it shows
+    // as unconfirmed may still have been dropped.
+    match (view.height, view.replacement) {
+        (Some(height), _) => Transition::Confirmed { height, lost },
+        (None, _) if known => Transition::Unconfirmed { lost },
+        (None, Some(replacement)) => Transition::Replaced { replacement, lost },
+        (None, None) => Transition::Dropped { lost },
+    }
+}
+
+/// Follows broadcast writes until they are buried, recording confirmations, undoing them when
+/// their block is reorged out and rebroadcasting writes that dropped out of the mempool.
+/// Writes that were replaced or rejected are no longer followed.
+pub struct Tracker {
+    gs: GrafittiState,
+}
+
+impl Tracker {
+    pub const fn new(gs: GrafittiState) -> Self {
+        Self { gs }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(TRACK_INTERVAL).await;
+                if let Err(e) = self.sync().await {
+                    warn!("write tracker failed, retrying: {e:?}");
+                }
+            }
+        })
+    }
+
+    async fn sync(&self) -> anyhow::Result<()> {
+        // Kept in sync by the watcher. The wallet is only locked to read from it, never across
+        // database or chain backend calls.
+        let tip = self.gs.wallet.lock().await.latest_checkpoint().height();
+
+        let min_height = tip.saturating_sub(FINALITY_DEPTH);
+        let writes = db::tracked_writes(&self.gs.db, min_height).await?;
+        let views: Vec<WalletView> = {
+            let wallet = self.gs.wallet.lock().await;
+            writes
+                .iter()
+                .map(|write| WalletView::of(&wallet, write))
+                .collect()
+        };
+
+        let mut block_hashes = HashMap::new();
+        for (write, view) in writes.into_iter().zip(views) {
+            let txid = write.txid;
+            if let Err(e) = self.check(tip, write, view, &mut block_hashes).await {
+                warn!("could not check write {}: {e:?}", txid);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash of the best block at `height`, as the chain backend sees it.
+    async fn block_hash(
+        &self,
+        height: u32,
+        cache: &mut HashMap<u32, BlockHash>,
+    ) -> anyhow::Result<BlockHash> {
+        if let Some(hash) = cache.get(&height) {
+            return Ok(*hash);
+        }
+        let hash = self.gs.blockchain.block_hash(height).await?;
+        cache.insert(height, hash);
+        Ok(hash)
+    }
+
+    /// Whether the chain backend still has `txid`, in a block or in its mempool. The wallet
+    /// keeps showing a dropped transaction as unconfirmed, so only the server can tell.
+    async fn is_known(&self, txid: Txid) -> anyhow::Result<bool> {
+        Ok(self.gs.blockchain.get_tx(txid).await?.is_some())
+    }
+
+    /// Emits a `confirmations` event for every threshold the write passed since the last pass.
+    async fn notify_confirmations(
+        &self,
+        txid: Txid,
+        height: u32,
+        tip: u32,
+        notified: u32,
+    ) -> anyhow::Result<()> {
+        let confirmations = (tip + 1).saturating_sub(height);
+        let reached: Vec<u32> = self
+            .gs
+            .config
+            .confirmation_thresholds
+            .iter()
+            .copied()
+            .filter(|threshold| (notified + 1..=confirmations).contains(threshold))
+            .collect();
+        let Some(highest) = reached.last().copied() else {
+            return Ok(());
+        };
+        for threshold in reached {
+            let detail = EventDetail {
+                height: Some(height),
+                confirmations: Some(threshold),
+                ..EventDetail::default()
+            };
+            self.gs
+                .events
+                .emit(txid, EventKind::Confirmations, detail)
+                .await?;
+        }
+        db::set_notified_confirmations(&self.gs.db, txid, highest).await
+    }
+
+    async fn check(
+        &self,
+        tip: u32,
+        write: TrackedWrite,
+        view: WalletView,
+        block_hashes: &mut HashMap<u32, BlockHash>,
+    ) -> anyhow::Result<()> {
+        let txid = write.txid;
+        let standing = match write.confirmation {
+            Some((height, hash)) => self.block_hash(height, block_hashes).await? == hash,
+            None => false,
+        };
+        let known = standing || view.height.is_some() || self.is_known(txid).await?;
+        let transition = classify(&write, standing, &view, known);
+        if let Transition::Buried { height } = transition {
+            return self
+                .notify_confirmations(txid, height, tip, write.notified_confirmations)
+                .await;
+        }
+
+        let lost = transition.lost();
+        if let Some(old_height) = lost {
+            warn!(
+                "write {} lost its confirmation at height {}",
+                txid, old_height
+            );
+            db::unconfirm_write(&self.gs.db, txid).await?;
+            let detail = EventDetail {
+                height: view.height,
+                previous_height: Some(old_height),
+                ..EventDetail::default()
+            };
+            self.gs
+                .events
+                .emit(txid, EventKind::Reorged, detail)
+                .await?;
+        }
+        let previous_height = lost.or(write.reorged_height);
+
+        match transition {
+            Transition::Buried { .. } | Transition::Unconfirmed { .. } => {}
+            Transition::Confirmed { height, .. } => {
+                let hash = self.block_hash(height, block_hashes).await?;
+                db::confirm_write(&self.gs.db, txid, height, hash).await?;
+                let detail = EventDetail {
+                    height: Some(height),
+                    previous_height,
+                    ..EventDetail::default()
+                };
+                self.gs
+                    .events
+                    .emit(txid, EventKind::Confirmed, detail)
+                    .await?;
+                self.notify_confirmations(txid, height, tip, 1).await?;
+            }
+            Transition::Replaced { replacement, .. } => {
+                info!("write {} was replaced by {}", txid, replacement);
+                db::set_write_status(&self.gs.db, txid, "replaced").await?;
+                let detail = EventDetail {
+                    replaced_by: Some(replacement),
+                    ..EventDetail::default()
+                };
+                self.gs
+                    .events
+                    .emit(txid, EventKind::Replaced, detail)
+                    .await?;
+            }
+            Transition::Dropped { .. } => self.rebroadcast(&write, previous_height).await?,
+        }
+        Ok(())
+    }
+
+    async fn rebroadcast(
+        &self,
+        write: &TrackedWrite,
+        previous_height: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let txid = write.txid;
+        info!("write {} dropped out of the mempool, rebroadcasting", txid);
+        let result = self.gs.blockchain.broadcast(&write.tx).await;
+        let (kind, detail) = match result {
+            Ok(()) => (
+                EventKind::Rebroadcast,
+                EventDetail {
+                    previous_height,
+                    ..EventDetail::default()
+                },
+            ),
+            // The server answered and refused it, sending it again won't help.
+            Err(BroadcastError::Rejected(reason)) => {
+                warn!("write {} was rejected: {}", txid, reason);
+                db::set_write_status(&self.gs.db, txid, "failed").await?;
+                (
+                    EventKind::Failed,
+                    EventDetail {
+                        reason: Some(reason),
+                        ..EventDetail::default()
+                    },
+                )
+            }
+            Err(BroadcastError::Other(e)) => return Err(e),
+        };
+        self.gs.events.emit(txid, kind, detail).await?;
+        Ok(())
+    }
+}
+
+/// What the wallet knows about a tracked write, read under a short lock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WalletView {
+    /// Height of the block the wallet sees the write confirmed in.
+    pub height: Option<u32>,
+    /// Another wallet transaction spending one of the write's inputs, looked up for writes
+    /// that aren't confirmed.
+    pub replacement: Option<Txid>,
+}
+
+impl WalletView {
+    pub fn of(wallet: &Wallet, write: &TrackedWrite) -> Self {
+        let height = wallet
+            .get_tx(write.txid)
+            .and_then(|tx| match tx.chain_position {
+                ChainPosition::Confirmed(anchor) => Some(anchor.confirmation_height),
+                ChainPosition::Unconfirmed(_) => None,
+            });
+        let replacement = height
+            .is_none()
+            .then(|| find_replacement(wallet, &write.tx))
+            .flatten();
+        Self {
+            height,
+            replacement,
+        }
+    }
+}
+
+/// A wallet transaction that spends one of the inputs of `tx`.
+fn find_replacement(wallet: &Wallet, tx: &Transaction) -> Option<Txid> {
+    let txid = tx.compute_txid();
+    wallet
+        .transactions()
+        .find(|candidate| {
+            candidate.tx_node.txid != txid
+                && candidate.tx_node.tx.input.iter().any(|input| {
+                    tx.input
+                        .iter()
+                        .any(|spent| spent.previous_output == input.previous_output)
+                })
+        })
+        .map(|candidate| candidate.tx_node.txid)
+}
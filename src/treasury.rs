@@ -0,0 +1,165 @@
+use anyhow::ensure;
+use bdk_wallet::bitcoin::{Address, Amount, FeeRate, OutPoint, Psbt, Transaction};
+use bdk_wallet::{KeychainKind, SignOptions, Wallet};
+use serde_json::json;
+use tracing::info;
+
+/// Coins below this value are merged by a consolidation unless the request says otherwise.
+pub const DEFAULT_CONSOLIDATION_BELOW: Amount = Amount::from_sat(50_000);
+
+/// A treasury transaction, built and signed but not broadcast yet.
+#[derive(Clone, Debug)]
+pub struct TreasuryTx {
+    pub tx: Transaction,
+    /// Sent to the destination, the rest of the inputs pays the fee or returns as change.
+    pub amount: Amount,
+    pub fee: Amount,
+    pub destination: Address,
+}
+
+impl TreasuryTx {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "txid": self.tx.compute_txid(),
+            "inputs": self.tx.input.len(),
+            "amount": self.amount.to_sat(),
+            "fee": self.fee.to_sat(),
+            "destination": self.destination.to_string(),
+        })
+    }
+}
+
+fn sign(
+    wallet: &mut Wallet,
+    mut psbt: Psbt,
+    sign_options: SignOptions,
+    destination: Address,
+) -> anyhow::Result<TreasuryTx> {
+    let finalized = wallet.sign(&mut psbt, sign_options)?;
+    ensure!(
+        finalized,
+        "wallet could not finalize the transaction, treasury operations need every signer"
+    );
+    let tx = psbt.extract_tx()?;
+    let fee = wallet.calculate_fee(&tx)?;
+    let amount = tx
+        .output
+        .iter()
+        .filter(|output| output.script_pubkey == destination.script_pubkey())
+        .map(|output| output.value)
+        .sum();
+    Ok(TreasuryTx {
+        tx,
+        amount,
+        fee,
+        destination,
+    })
+}
+
+/// Merges the coins worth less than `below` into one change output, leaving the coins of
+/// pending writes alone.
+///
+/// Returns `None` when there are fewer than two such coins.
+///
+/// # Errors
+///
+/// Will return errors if the transaction can't be built or signed
+pub fn build_consolidation(
+    wallet: &mut Wallet,
+    below: Amount,
+    reserved: &[OutPoint],
+    fee_rate: FeeRate,
+    sign_options: SignOptions,
+) -> anyhow::Result<Option<TreasuryTx>> {
+    let outpoints: Vec<OutPoint> = wallet
+        .list_unspent()
+        .filter(|utxo| utxo.txout.value < below && !reserved.contains(&utxo.outpoint))
+        .map(|utxo| utxo.outpoint)
+        .collect();
+    if outpoints.len() < 2 {
+        return Ok(None);
+    }
+
+    let destination = wallet.reveal_next_address(KeychainKind::Internal).address;
+    info!(
+        "consolidating {} coins below {} into {}",
+        outpoints.len(),
+        below,
+        destination
+    );
+    let mut tx_builder = wallet.build_tx();
+    tx_builder
+        .add_utxos(&outpoints)?
+        .manually_selected_only()
+        .drain_to(destination.script_pubkey())
+        .fee_rate(fee_rate);
+    let psbt = tx_builder.finish()?;
+
+    sign(wallet, psbt, sign_options, destination).map(Some)
+}
+
+/// Sends whatever the spendable balance holds above `ceiling` to the cold address. The fee
+/// comes out of the change, so the hot wallet ends up just below the ceiling.
+///
+/// Returns `None` when the balance is within the ceiling.
+///
+/// # Errors
+///
+/// Will return errors if the transaction can't be built or signed
+pub fn build_excess_sweep(
+    wallet: &mut Wallet,
+    cold_address: &Address,
+    ceiling: Amount,
+    reserved: Vec<OutPoint>,
+    fee_rate: FeeRate,
+    sign_options: SignOptions,
+) -> anyhow::Result<Option<TreasuryTx>> {
+    let spendable = wallet.balance().trusted_spendable();
+    let Some(excess) = spendable
+        .checked_sub(ceiling)
+        .filter(|excess| *excess > Amount::ZERO)
+    else {
+        return Ok(None);
+    };
+
+    info!(
+        "sweeping {} above the ceiling of {} to {}",
+        excess, ceiling, cold_address
+    );
+    let mut tx_builder = wallet.build_tx();
+    tx_builder
+        .add_recipient(cold_address.script_pubkey(), excess)
+        .unspendable(reserved)
+        .fee_rate(fee_rate);
+    let psbt = tx_builder.finish()?;
+
+    sign(wallet, psbt, sign_options, cold_address.clone()).map(Some)
+}
+
+/// Sends every coin of the wallet to `destination`, for decommissioning it.
+///
+/// Returns `None` when the wallet is empty.
+///
+/// # Errors
+///
+/// Will return errors if the transaction can't be built or signed
+pub fn build_full_sweep(
+    wallet: &mut Wallet,
+    destination: &Address,
+    fee_rate: FeeRate,
+    sign_options: SignOptions,
+) -> anyhow::Result<Option<TreasuryTx>> {
+    if wallet.balance().total() == Amount::ZERO {
+        return Ok(None);
+    }
+
+    info!("sweeping the whole wallet to {}", destination);
+    let mut tx_builder = wallet.build_tx();
+    tx_builder
+        .drain_wallet()
+        .drain_to(destination.script_pubkey())
+        .fee_rate(fee_rate);
+    let psbt = tx_builder.finish()?;
+
+    sign(wallet, psbt, sign_options, destination.clone()).map(Some)
+}
@@ -1,10 +1,11 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
 use std::ops::Not;
+use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
-use tokio::sync::MutexGuard;
 // Third-party crates
-use axum::routing::get;
+use axum::routing::{delete, get, post};
 use axum::Router;
 use better_panic::Settings;
 use doc_comment::doc_comment;
@@ -15,16 +16,42 @@ use tracing::info;
 
 // BDK (Bitcoin Development Kit) related imports
 use bdk_electrum::bdk_chain::{ChainPosition, ConfirmationTimeHeightAnchor};
-use bdk_electrum::electrum_client::Client as ElectrumClient;
-use bdk_electrum::BdkElectrumClient;
+use bdk_wallet::bitcoin::hex::DisplayHex;
 use bdk_wallet::bitcoin::script::Instruction;
 use bdk_wallet::bitcoin::Network::{Bitcoin, Regtest, Signet, Testnet};
-use bdk_wallet::bitcoin::{Amount, Network, Txid};
-use bdk_wallet::{floating_rate, Wallet};
+use bdk_wallet::bitcoin::{
+    Address, Amount, Denomination, FeeRate, Network, Script, Transaction, TxIn, Txid, Weight,
+};
+use bdk_wallet::{floating_rate, KeychainKind, Wallet};
+use sqlx::PgPool;
 use tokio::sync::Mutex;
 // Local imports
-use crate::routes::{get_op_return, write_op_return};
-use crate::testenv::TestEnv;
+use crate::backend::ChainBackend;
+use crate::batch::{Batcher, SharedEconomy};
+use crate::config::{Config, DescriptorKind};
+use crate::db;
+use crate::envelope::extract_envelope;
+use crate::events::Events;
+use crate::fanout::FanOut;
+use crate::indexer::Indexer;
+use crate::monitor::{BalanceMonitor, SharedRunway};
+use crate::multisig::PendingWrites;
+use crate::queue::{QueueWorker, QueuedWrites, WriteQueue};
+use crate::routes::{
+    cancel_scheduled_write, consolidate, create_webhook, decrypt_payload, delete_webhook,
+    estimate_fee, get_economy_write, get_indexed_tx, get_op_return, get_pending_write,
+    get_queued_write, get_scheduled_write, get_write, health, list_deliveries, list_economy_writes,
+    list_pending_writes, list_queued_writes, list_scheduled_writes, list_webhooks, list_writes,
+    metrics, migrate_wallet, sandbox_fund, sandbox_mine, sandbox_mine_empty, sandbox_reorg,
+    sandbox_wait, search_index, stream_graffiti, submit_signatures, sweep_all, sweep_excess,
+    verify, wallet_address, wallet_balance, wallet_utxos, write_envelope, write_op_return,
+};
+use crate::sandbox::Sandbox;
+use crate::schedule::Scheduler;
+use crate::stream::Feed;
+use crate::tracker::Tracker;
+use crate::watcher::Watcher;
+use crate::webhooks::Dispatcher;
 
 pub const NETWORK: Network = {
     if cfg!(feature = "bitcoin") {
@@ -37,50 +64,121 @@ pub const NETWORK: Network = {
         Signet
     }
 };
-const STOP_GAP: usize = 50;
-const BATCH_SIZE: usize = 5;
-
-pub async fn sync_electrum(
-    client: MutexGuard<'_, BdkElectrumClient<ElectrumClient>>,
-    wallet: &mut Wallet,
-) -> anyhow::Result<()> {
-    info!("syncing electrum");
-    // let client = get_electrum_client()?;
+pub(crate) const STOP_GAP: usize = 50;
+pub(crate) const BATCH_SIZE: usize = 5;
+/// Confirmation target, in blocks, used when asking the server for a fee rate.
+pub const FEE_TARGET: usize = 6;
+
+/// Weight of a write carrying `data_len` bytes: one wallet input, the `OP_RETURN` output and
+/// change back to the wallet.
+pub fn estimate_write_weight(kind: DescriptorKind, data_len: usize) -> Weight {
+    // version, locktime, input and output counts, plus the segwit marker and flag
+    let overhead = Weight::from_wu(10 * 4 + 2);
+    // OP_RETURN followed by a single push of the data
+    let push_overhead = match data_len {
+        0..=75 => 1,
+        76..=255 => 2,
+        _ => 3,
+    };
+    let script_len = 1 + push_overhead + data_len as u64;
+    let script_len_prefix = if script_len < 0xfd { 1 } else { 3 };
+    let op_return = Weight::from_vb_unchecked(8 + script_len_prefix + script_len);
 
-    // Populate the electrum client's transaction cache so it doesn't redownload transaction we
-    // already have.
-    client.populate_tx_cache(&wallet);
+    overhead + kind.input_weight() + kind.output_weight() + op_return
+}
 
-    let request = wallet.start_full_scan();
+/// Writes of `data_len` bytes that `spendable` pays for at `fee_rate`, each spending the
+/// change of the one before.
+pub fn affordable_writes(
+    kind: DescriptorKind,
+    data_len: usize,
+    spendable: Amount,
+    fee_rate: FeeRate,
+) -> u64 {
+    let fee = fee_rate
+        .fee_wu(estimate_write_weight(kind, data_len))
+        .unwrap_or(Amount::MAX_MONEY)
+        .max(Amount::ONE_SAT);
+    spendable.to_sat() / fee.to_sat()
+}
 
-    // todo might be slow
-    let mut update = client
-        .full_scan(request, STOP_GAP, BATCH_SIZE, false)?
-        .with_confirmation_time_height_anchor(&client)?;
+/// A BIP21 payment URI for `address`, with the amount in BTC and the label percent-encoded.
+pub fn bip21_uri(address: &Address, amount: Option<Amount>, label: Option<&str>) -> String {
+    let mut params = Vec::new();
+    if let Some(amount) = amount {
+        params.push(format!(
+            "amount={}",
+            amount.to_string_in(Denomination::Bitcoin)
+        ));
+    }
+    if let Some(label) = label {
+        let encoded: String = label
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    char::from(byte).to_string()
+                }
+                _ => format!("%{byte:02X}"),
+            })
+            .collect();
+        params.push(format!("label={encoded}"));
+    }
+    if params.is_empty() {
+        format!("bitcoin:{address}")
+    } else {
+        format!("bitcoin:{address}?{}", params.join("&"))
+    }
+}
 
-    let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs();
-    let _ = update.graph_update.update_last_seen_unconfirmed(now);
+/// Whether every input of `tx` spends a wallet coin, or an output of a transaction that
+/// only spends wallet coins, like the commit transaction of an envelope write.
+pub fn funded_by_wallet(wallet: &Wallet, tx: &Transaction) -> bool {
+    let is_ours = |input: &TxIn| {
+        wallet
+            .tx_graph()
+            .get_txout(input.previous_output)
+            .is_some_and(|txout| wallet.is_mine(&txout.script_pubkey))
+    };
 
-    wallet.apply_update(update)?;
-    Ok(())
+    !tx.input.is_empty()
+        && tx.input.iter().all(|input| {
+            is_ours(input)
+                || wallet
+                    .get_tx(input.previous_output.txid)
+                    .is_some_and(|parent| parent.tx_node.tx.input.iter().all(is_ours))
+        })
 }
 
-pub fn get_electrum_client() -> anyhow::Result<BdkElectrumClient<ElectrumClient>> {
-    let electrum_url = if !cfg!(debug_assertions) {
-        println!("getting testenv");
-        let env = TestEnv::new().unwrap();
-        env.electrsd.electrum_url.clone()
-    } else {
-        match NETWORK {
-            Bitcoin => "ssl://electrum.blockstream.info:50002".to_string(),
-            // "ssl://mempool.space:50002",
-            _ => "ssl://mempool.space:60602".to_string(),
-        }
-    };
+/// Sweeps every coin of the legacy wallet into the next unused address of `wallet`.
+///
+/// Returns `None` when the legacy wallet is empty.
+///
+/// # Errors
+///
+/// Will return errors if the sweep can't be built or signed
+pub fn build_sweep(
+    legacy: &mut Wallet,
+    wallet: &mut Wallet,
+    fee_rate: FeeRate,
+) -> anyhow::Result<Option<Transaction>> {
+    if legacy.balance().total() == Amount::ZERO {
+        return Ok(None);
+    }
 
-    let client = ElectrumClient::new(electrum_url.as_str())?;
-    let client = BdkElectrumClient::new(client);
-    Ok(client)
+    let destination = wallet.next_unused_address(KeychainKind::External);
+    info!("sweeping legacy wallet into {}", destination);
+
+    let mut tx_builder = legacy.build_tx();
+    tx_builder
+        .drain_wallet()
+        .drain_to(destination.script_pubkey())
+        .fee_rate(fee_rate);
+    let mut psbt = tx_builder.finish()?;
+
+    let finalized = legacy.sign(&mut psbt, DescriptorKind::Wpkh.sign_options())?;
+    anyhow::ensure!(finalized, "legacy wallet could not finalize the sweep");
+
+    Ok(Some(psbt.extract_tx()?))
 }
 
 doc_comment!(
@@ -101,6 +199,8 @@ doc_comment!(
     * `txid`: The unique identifier of this transaction.
     * `chain_position`: The position of this transaction in the blockchain,
       including confirmation status and block height if confirmed.
+    * `envelope`: For reveal transactions of envelope writes, the hex encoded payload
+      recovered from the witness.
 
     ## Serialization
 
@@ -120,6 +220,7 @@ doc_comment!(
         fee_rate: 10.5,
         txid: Txid::from_str("1234...").unwrap(),
         chain_position: ChainPosition::Confirmed(ConfirmedAt { height: 700000, time: 1234567890 }),
+        envelope: None,
     };
     ```
     "#,
@@ -132,6 +233,7 @@ doc_comment!(
         pub txid: Txid,
         #[serde(serialize_with = "serialize_chain_position")]
         pub chain_position: ChainPosition<&'a ConfirmationTimeHeightAnchor>,
+        pub envelope: Option<String>,
     }
 );
 
@@ -165,6 +267,40 @@ where
     }
 }
 
+/// Adds transactions we just broadcast to the wallet, so the next write doesn't try to spend
+/// the same coins before the watcher hears about them.
+pub fn insert_broadcast(wallet: &mut Wallet, txs: &[&Transaction]) {
+    let now = std::time::UNIX_EPOCH
+        .elapsed()
+        .map_or(0, |elapsed| elapsed.as_secs());
+    wallet.apply_unconfirmed_txs(txs.iter().map(|tx| (*tx, now)));
+}
+
+/// Pushes following the `OP_RETURN` of `script`, `None` if it isn't a data carrier output.
+pub fn op_return_pushes(script: &Script) -> Option<Vec<Vec<u8>>> {
+    let mut instructions = script.instructions();
+    if !matches!(
+        instructions.next(),
+        Some(Ok(Instruction::Op(
+            bdk_wallet::bitcoin::blockdata::opcodes::all::OP_RETURN
+        )))
+    ) {
+        return None;
+    }
+    instructions
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => Some(bytes.as_bytes().to_vec()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The payload of a data carrier output, which is its first push. Later pushes carry
+/// metadata such as attestations.
+pub fn op_return_payload(script: &Script) -> Option<Vec<u8>> {
+    op_return_pushes(script).map(|pushes| pushes.into_iter().next().unwrap_or_default())
+}
+
 /// # Errors
 ///
 /// Will return errors if there is data missing
@@ -192,6 +328,7 @@ pub fn get_tx_details(wallet: &Wallet) -> anyhow::Result<Vec<TxDetail>> {
             let txid = tx.tx_node.txid;
             let chain_position = tx.chain_position;
             let tx = tx.tx_node.tx.as_ref();
+            let envelope = extract_envelope(tx).map(|data| data.to_lower_hex_string());
             let (sent, received) = wallet.sent_and_received(tx);
             let fee = wallet.calculate_fee(tx)?;
             let fee_rate = wallet.calculate_fee_rate(tx)?;
@@ -204,6 +341,7 @@ pub fn get_tx_details(wallet: &Wallet) -> anyhow::Result<Vec<TxDetail>> {
                 fee_rate,
                 txid,
                 chain_position,
+                envelope,
             };
 
             Ok(tx_detail)
@@ -218,8 +356,9 @@ pub fn setup_better_panic() {
         .install();
 }
 
-pub async fn setup_server() -> anyhow::Result<(Router, TcpListener)> {
-    let app = setup_router()?;
+/// Builds the app, against a local regtest chain when `sandbox` is set.
+pub async fn setup_server(sandbox: bool) -> anyhow::Result<(Router, TcpListener)> {
+    let app = setup_router(sandbox).await?;
 
     let listener = setup_listener().await?;
     Ok((app, listener))
@@ -238,29 +377,173 @@ async fn setup_listener() -> anyhow::Result<TcpListener> {
 }
 #[derive(Clone)]
 pub struct GrafittiState {
-    pub(crate) blockchain: Arc<Mutex<BdkElectrumClient<ElectrumClient>>>,
+    pub(crate) blockchain: Arc<ChainBackend>,
+    /// The funding wallet, kept in sync by the [`Watcher`]. Lock it before the client when
+    /// both are needed.
+    pub(crate) wallet: Arc<Mutex<Wallet>>,
+    /// Height the wallet was last synced to, kept up to date by the [`Watcher`].
+    pub(crate) wallet_tip: Arc<AtomicU32>,
+    pub(crate) config: Arc<Config>,
+    pub(crate) pending: PendingWrites,
+    /// Writes held back by the mempool ancestor and descendant limits.
+    pub(crate) queue: WriteQueue,
+    /// The last fee check for economy writes, kept up to date by the [`Batcher`].
+    pub(crate) economy: SharedEconomy,
+    pub(crate) db: PgPool,
+    pub(crate) events: Events,
+    pub(crate) feed: Feed,
+    /// Funding status of the wallet, kept up to date by the [`BalanceMonitor`].
+    pub(crate) runway: SharedRunway,
+    /// The regtest nodes of `--sandbox`, kept alive as long as the service runs.
+    pub(crate) sandbox: Option<Arc<Sandbox>>,
 }
 
 impl Debug for GrafittiState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("State")
-            .field("blockchain", &"Arc<Mutex<BdkElectrumClient<Client>>>")
+            .field("blockchain", &self.blockchain.name())
+            .field("wallet", &"Arc<Mutex<Wallet>>")
+            .field("wallet_tip", &self.wallet_tip)
+            .field("config", &self.config.descriptor_kind())
+            .field("pending", &"Arc<Mutex<HashMap<Uuid, PendingWrite>>>")
+            .field("queue", &"Arc<Mutex<QueuedWrites>>")
+            .field("economy", &self.economy)
+            .field("db", &self.db)
+            .field("events", &self.events)
+            .field("feed", &self.feed)
+            .field("runway", &self.runway)
+            .field("sandbox", &self.sandbox.is_some())
             .finish()
     }
 }
-fn setup_router() -> anyhow::Result<Router> {
-    let client = get_electrum_client()?;
+async fn setup_router(sandbox: bool) -> anyhow::Result<Router> {
+    let sandbox = if sandbox {
+        Some(Sandbox::start().await?)
+    } else {
+        None
+    };
+
+    // The sandbox decides where the chain is, whatever the environment says.
+    let config = Config::from_env_with_backend(sandbox.as_ref().map(|s| s.backend_config()))?;
+    config.validate()?;
+    if let Some(sandbox) = &sandbox {
+        sandbox.fund_wallet(&config.wallet()?).await?;
+    }
+    info!(
+        "funding wallet uses {:?} descriptors",
+        config.descriptor_kind()
+    );
+
+    // The tracker rebroadcasts writes from their stored transactions, losing them on a restart
+    // would leave reorged writes unconfirmed.
+    let database_url = config.database_url.as_deref().ok_or_else(|| {
+        anyhow::anyhow!("DATABASE_URL must be set, the write history is kept in Postgres")
+    })?;
+    let db = db::connect(database_url).await?;
+    let backend = ChainBackend::new(&config.backend)?;
+    info!("reading the chain through {}", backend.name());
+    if let Some(pool) = backend.electrum() {
+        pool.spawn_health_checks();
+    }
+
+    let feed = Feed::default();
+    if let (Some(rpc), Some(start_height)) = (&config.bitcoind_rpc, config.index_start_height) {
+        Indexer::new(db.clone(), rpc, start_height, feed.clone())?.spawn();
+    }
+
+    // Writes that were waiting for cosigners when the service last stopped.
+    let pending: HashMap<_, _> = db::pending_writes(&db)
+        .await?
+        .into_iter()
+        .map(|write| (write.id, write))
+        .collect();
+    if !pending.is_empty() {
+        info!("{} writes are waiting for cosigners", pending.len());
+    }
+    let queued = db::waiting_queued_writes(&db).await?;
+    if !queued.is_empty() {
+        info!(
+            "{} writes are queued behind the mempool limits",
+            queued.len()
+        );
+    }
 
     let grafitti_state = GrafittiState {
-        blockchain: Arc::new(Mutex::new(client)),
+        blockchain: Arc::new(backend),
+        wallet: Arc::new(Mutex::new(config.wallet()?)),
+        wallet_tip: Arc::default(),
+        config: Arc::new(config),
+        pending: Arc::new(Mutex::new(pending)),
+        queue: Arc::new(Mutex::new(QueuedWrites::restore(queued))),
+        economy: SharedEconomy::default(),
+        events: Events::new(db.clone()),
+        feed,
+        runway: SharedRunway::default(),
+        sandbox,
+        db,
     };
+    Watcher::start(grafitti_state.clone()).await?;
+    Tracker::new(grafitti_state.clone()).spawn();
+    BalanceMonitor::new(grafitti_state.clone()).spawn();
+    QueueWorker::new(grafitti_state.clone()).spawn();
+    Batcher::new(grafitti_state.clone()).spawn();
+    Scheduler::new(grafitti_state.clone()).spawn();
+    if let Some(fan_out) = grafitti_state.config.fan_out {
+        FanOut::new(grafitti_state.clone(), fan_out).spawn();
+    }
+    Dispatcher::new(grafitti_state.db.clone()).spawn();
+    if let Some(sandbox) = &grafitti_state.sandbox {
+        sandbox.spawn_miner()?;
+    }
 
-    let router = Router::new()
+    let mut router = Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics))
         .route("/get_op_return", get(get_op_return))
         .route("/write_op_return/:data", get(write_op_return))
-        .with_state(grafitti_state);
+        .route("/write_envelope", post(write_envelope))
+        .route("/estimate_fee/:data", get(estimate_fee))
+        .route("/decrypt", post(decrypt_payload))
+        .route("/verify/:txid", get(verify))
+        .route("/index/op_returns", get(search_index))
+        .route("/index/op_returns/:txid", get(get_indexed_tx))
+        .route("/writes", get(list_writes))
+        .route("/writes/:txid", get(get_write))
+        .route("/stream", get(stream_graffiti))
+        .route("/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/webhooks/:id", delete(delete_webhook))
+        .route("/webhooks/:id/deliveries", get(list_deliveries))
+        .route("/migrate_wallet", post(migrate_wallet))
+        .route("/wallet/balance", get(wallet_balance))
+        .route("/wallet/address", post(wallet_address))
+        .route("/wallet/utxos", get(wallet_utxos))
+        .route("/wallet/consolidate", post(consolidate))
+        .route("/wallet/sweep_excess", post(sweep_excess))
+        .route("/wallet/sweep_all", post(sweep_all))
+        .route("/pending_writes", get(list_pending_writes))
+        .route(
+            "/pending_writes/:id",
+            get(get_pending_write).post(submit_signatures),
+        )
+        .route("/queued_writes", get(list_queued_writes))
+        .route("/queued_writes/:id", get(get_queued_write))
+        .route("/economy_writes", get(list_economy_writes))
+        .route("/economy_writes/:id", get(get_economy_write))
+        .route("/scheduled_writes", get(list_scheduled_writes))
+        .route(
+            "/scheduled_writes/:id",
+            get(get_scheduled_write).delete(cancel_scheduled_write),
+        );
+    if grafitti_state.sandbox.is_some() {
+        router = router
+            .route("/sandbox/fund", post(sandbox_fund))
+            .route("/sandbox/mine", post(sandbox_mine))
+            .route("/sandbox/mine_empty", post(sandbox_mine_empty))
+            .route("/sandbox/reorg", post(sandbox_reorg))
+            .route("/sandbox/wait", post(sandbox_wait));
+    }
 
-    Ok(router)
+    Ok(router.with_state(grafitti_state))
 }
 
 pub fn setup_tracer() {
@@ -0,0 +1,371 @@
+use bdk_electrum::bdk_chain::local_chain::CheckPoint;
+use bdk_electrum::bdk_chain::spk_client::SyncRequest;
+use bdk_electrum::bdk_chain::Append;
+use bdk_electrum::electrum_client::{
+    Client as ElectrumClient, ElectrumApi, Error as ElectrumError, ScriptStatus,
+};
+use bdk_wallet::bitcoin::{Script, ScriptBuf};
+use bdk_wallet::wallet::ChangeSet;
+use bdk_wallet::Wallet;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::backend::ChainBackend;
+use crate::db;
+use crate::electrum::{ElectrumPool, ElectrumServer};
+use crate::util::GrafittiState;
+
+/// How often queued Electrum notifications are read.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How often the wallet is synced when the backend can't push changes.
+const ESPLORA_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// How often bitcoind is asked for new blocks and mempool transactions.
+const RPC_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Subscribes to `spk`, returning its status if it has history. Subscribing twice on one
+/// connection is not an error here.
+fn subscribe(client: &ElectrumClient, spk: &Script) -> anyhow::Result<Option<ScriptStatus>> {
+    match client.script_subscribe(spk) {
+        Ok(status) => Ok(status),
+        Err(ElectrumError::AlreadySubscribed(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Subscribes to the headers and to `spks` on a fresh connection, returning the status of
+/// every script. Blocks on the socket.
+fn subscribe_wallet(
+    client: &ElectrumClient,
+    spks: Vec<ScriptBuf>,
+) -> anyhow::Result<Vec<(ScriptBuf, Option<ScriptStatus>)>> {
+    client.block_headers_subscribe()?;
+    spks.into_iter()
+        .map(|spk| {
+            let status = subscribe(client, &spk)?;
+            Ok((spk, status))
+        })
+        .collect()
+}
+
+/// Reads the notifications queued for `subscribed` and subscribes to the new `spks`, returning
+/// whether a block came and the scripts with a new status. Blocks on the socket.
+fn poll_notifications(
+    client: &ElectrumClient,
+    subscribed: Vec<ScriptBuf>,
+    spks: Vec<ScriptBuf>,
+) -> anyhow::Result<(bool, Vec<(ScriptBuf, Option<ScriptStatus>)>)> {
+    // Reading the reply to the ping also queues whatever was pushed to us.
+    client.ping()?;
+    let mut new_block = false;
+    while client.block_headers_pop()?.is_some() {
+        new_block = true;
+    }
+    let mut statuses = Vec::new();
+    for spk in subscribed {
+        if let Some(status) = client.script_pop(&spk)? {
+            statuses.push((spk, Some(status)));
+        }
+    }
+    for spk in spks {
+        // A status means the script already has history.
+        let status = subscribe(client, &spk)?;
+        statuses.push((spk, status));
+    }
+    Ok((new_block, statuses))
+}
+
+/// Keeps the shared wallet in sync through Electrum subscriptions. Every wallet script and the
+/// block headers are subscribed once, after which only scripts whose status changed are
+/// synced, so an idle wallet costs one ping per poll. Esplora has no subscriptions, there the
+/// revealed scripts are synced on a timer instead. With bitcoind, new blocks and mempool
+/// transactions are applied to the wallet as they come, and every change is saved so a restart
+/// doesn't read the blocks from the wallet's birthday again.
+///
+/// Subscriptions live on one server of the pool. When the pool fails over to another, or the
+/// connection drops, the watcher subscribes again and syncs only the scripts whose status
+/// changed, the wallet is kept.
+///
+/// The watcher never holds the wallet and the Electrum client at the same time, handlers lock
+/// the wallet first and may then broadcast through the client.
+pub struct Watcher {
+    gs: GrafittiState,
+    /// Subscribed scripts with the status the server last reported, `None` without history.
+    subscribed: HashMap<ScriptBuf, Option<ScriptStatus>>,
+    /// The Electrum server holding the subscriptions.
+    server: Option<Arc<ElectrumServer>>,
+    /// Wallet changes of the rpc backend that could not be saved yet.
+    unsaved: Option<ChangeSet>,
+}
+
+impl Watcher {
+    /// Scans the wallet before returning, so it is usable as soon as the server takes
+    /// requests, then keeps it in sync in the background.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the initial scan fails
+    pub async fn start(gs: GrafittiState) -> anyhow::Result<JoinHandle<()>> {
+        let attempts = gs
+            .blockchain
+            .electrum()
+            .map_or(1, ElectrumPool::server_count);
+        let mut watcher = Self {
+            gs,
+            subscribed: HashMap::new(),
+            server: None,
+            unsaved: None,
+        };
+        for attempt in 1..=attempts {
+            match watcher.scan().await {
+                Ok(()) => break,
+                Err(e) if attempt < attempts => {
+                    warn!("initial scan failed, trying the next server: {e:?}");
+                    watcher.server_failed(&e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(watcher.spawn())
+    }
+
+    /// Counts a failure against the subscribed server, so the pool fails over. The next poll
+    /// subscribes again.
+    fn server_failed(&mut self, error: &anyhow::Error) {
+        if let Some(server) = self.server.take() {
+            server.record_failure(error);
+        }
+    }
+
+    fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match &*self.gs.blockchain {
+                    ChainBackend::Electrum(_) => {
+                        if let Err(e) = self.poll().await {
+                            // The client reconnects on its own but drops our subscriptions
+                            // with the old connection, so subscribe again.
+                            warn!("electrum subscriptions failed, resubscribing: {e:?}");
+                            self.server_failed(&e);
+                        }
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                    ChainBackend::Esplora(_) => {
+                        tokio::time::sleep(ESPLORA_POLL_INTERVAL).await;
+                        if let Err(e) = self.poll_revealed().await {
+                            warn!("wallet sync failed, retrying: {e:?}");
+                        }
+                    }
+                    ChainBackend::Rpc { .. } => {
+                        tokio::time::sleep(RPC_POLL_INTERVAL).await;
+                        if let Err(e) = self.gs.blockchain.sync_blocks(&self.gs.wallet).await {
+                            warn!("wallet sync failed, retrying: {e:?}");
+                        }
+                        self.note_tip(&*self.gs.wallet.lock().await);
+                        if let Err(e) = self.save_checkpoint().await {
+                            warn!("could not save the wallet, retrying: {e:?}");
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Subscribes to the headers and the wallet scripts, then replaces the shared wallet with a
+    /// full scan. Subscribing first means nothing that happens during the scan is missed, and
+    /// scripts the scan reveals are picked up by the next poll.
+    async fn scan(&mut self) -> anyhow::Result<()> {
+        let mut wallet = self.initial_wallet().await?;
+        if let Some(pool) = self.gs.blockchain.electrum() {
+            let spks: Vec<ScriptBuf> = wallet.spk_index().all_spks().values().cloned().collect();
+            let server = pool.primary();
+            self.server = Some(server.clone());
+            let client = server.subscriber(true).await?;
+            let statuses =
+                tokio::task::spawn_blocking(move || subscribe_wallet(&client.inner, spks))
+                    .await??;
+            info!(
+                "subscribed to {} wallet scripts on {}",
+                statuses.len(),
+                server.config.url
+            );
+            self.subscribed = statuses.into_iter().collect();
+        }
+
+        self.gs.blockchain.full_scan(&mut wallet).await?;
+        self.note_tip(&wallet);
+        *self.gs.wallet.lock().await = wallet;
+        Ok(())
+    }
+
+    /// Moves the subscriptions to the primary server on a fresh connection, keeping the
+    /// wallet. Only the scripts whose status changed since it was last seen are synced.
+    async fn resubscribe(&mut self) -> anyhow::Result<()> {
+        let blockchain = self.gs.blockchain.clone();
+        let pool = blockchain
+            .electrum()
+            .ok_or_else(|| anyhow::anyhow!("subscriptions need an electrum backend"))?;
+        let (chain_tip, spks) = {
+            let wallet = self.gs.wallet.lock().await;
+            let spks: Vec<ScriptBuf> = wallet.spk_index().all_spks().values().cloned().collect();
+            (wallet.latest_checkpoint(), spks)
+        };
+        let server = pool.primary();
+        self.server = Some(server.clone());
+        let client = server.subscriber(true).await?;
+        let statuses =
+            tokio::task::spawn_blocking(move || subscribe_wallet(&client.inner, spks)).await??;
+        info!(
+            "subscribed to {} wallet scripts on {}",
+            statuses.len(),
+            server.config.url
+        );
+
+        let changed: Vec<ScriptBuf> = statuses
+            .iter()
+            .filter(|(spk, status)| self.subscribed.get(spk).copied().flatten() != *status)
+            .map(|(spk, _)| spk.clone())
+            .collect();
+        // Blocks may have come while nothing was subscribed, the sync catches the tip up too.
+        self.sync_scripts(chain_tip, changed).await?;
+        self.subscribed.extend(statuses);
+        Ok(())
+    }
+
+    /// Shares the tip of `wallet`, so `/health` can report it without locking the wallet.
+    fn note_tip(&self, wallet: &Wallet) {
+        self.gs
+            .wallet_tip
+            .store(wallet.latest_checkpoint().height(), Ordering::Relaxed);
+    }
+
+    /// The wallet to scan into. The rpc backend carries on from the saved wallet, so only the
+    /// blocks mined since are read, other backends start from a fresh one.
+    async fn initial_wallet(&self) -> anyhow::Result<Wallet> {
+        if !matches!(&*self.gs.blockchain, ChainBackend::Rpc { .. }) {
+            return self.gs.config.wallet();
+        }
+        let Some(changeset) = db::wallet_checkpoint(&self.gs.db).await? else {
+            return self.gs.config.wallet();
+        };
+        match self.gs.config.wallet_from(changeset) {
+            Ok(wallet) => {
+                info!(
+                    "resuming the wallet from block {}",
+                    wallet.latest_checkpoint().height()
+                );
+                Ok(wallet)
+            }
+            Err(e) => {
+                warn!("discarding the saved wallet, scanning from the birthday: {e:?}");
+                db::clear_wallet_changesets(&self.gs.db).await?;
+                self.gs.config.wallet()
+            }
+        }
+    }
+
+    /// Saves what changed in the wallet since the last call. Changes that fail to save are
+    /// kept and go out with the next ones.
+    async fn save_checkpoint(&mut self) -> anyhow::Result<()> {
+        let staged = self.gs.wallet.lock().await.take_staged();
+        if let Some(changeset) = staged.filter(|changeset| !changeset.is_empty()) {
+            match &mut self.unsaved {
+                Some(unsaved) => unsaved.append(changeset),
+                None => self.unsaved = Some(changeset),
+            }
+        }
+        if let Some(changeset) = &self.unsaved {
+            db::save_wallet_changeset(&self.gs.db, changeset).await?;
+            self.unsaved = None;
+        }
+        Ok(())
+    }
+
+    /// Syncs every revealed script and the unconfirmed transactions of the wallet.
+    async fn poll_revealed(&self) -> anyhow::Result<()> {
+        let request = self.gs.wallet.lock().await.start_sync_with_revealed_spks();
+        let mut update = self.gs.blockchain.sync(request).await?;
+        let now = std::time::UNIX_EPOCH.elapsed()?.as_secs();
+        let _ = update.graph_update.update_last_seen_unconfirmed(now);
+        let mut wallet = self.gs.wallet.lock().await;
+        wallet.apply_update(update)?;
+        self.note_tip(&wallet);
+        Ok(())
+    }
+
+    async fn poll(&mut self) -> anyhow::Result<()> {
+        let pool = self
+            .gs
+            .blockchain
+            .electrum()
+            .ok_or_else(|| anyhow::anyhow!("subscriptions need an electrum backend"))?;
+        let primary = pool.primary();
+        let Some(server) = self.server.clone() else {
+            return self.resubscribe().await;
+        };
+        if !Arc::ptr_eq(&server, &primary) {
+            info!(
+                "moving subscriptions from {} to {}",
+                server.config.url, primary.config.url
+            );
+            return self.resubscribe().await;
+        }
+
+        // Scripts revealed since the last poll, by new addresses or change.
+        let (chain_tip, spks) = {
+            let wallet = self.gs.wallet.lock().await;
+            let spks: Vec<ScriptBuf> = wallet
+                .spk_index()
+                .all_spks()
+                .values()
+                .filter(|spk| !self.subscribed.contains_key(*spk))
+                .cloned()
+                .collect();
+            (wallet.latest_checkpoint(), spks)
+        };
+
+        let client = server.subscriber(false).await?;
+        let subscribed: Vec<ScriptBuf> = self.subscribed.keys().cloned().collect();
+        // Statuses are only recorded once synced, so a failed sync is repeated after the
+        // next subscription.
+        let (new_block, statuses) = tokio::task::spawn_blocking(move || {
+            poll_notifications(&client.inner, subscribed, spks)
+        })
+        .await??;
+        let changed: Vec<ScriptBuf> = statuses
+            .iter()
+            .filter(|(_, status)| status.is_some())
+            .map(|(spk, _)| spk.clone())
+            .collect();
+        if !changed.is_empty() || new_block {
+            debug!(
+                "syncing {} changed scripts, new block: {}",
+                changed.len(),
+                new_block
+            );
+            self.sync_scripts(chain_tip, changed).await?;
+        }
+        self.subscribed.extend(statuses);
+        Ok(())
+    }
+
+    /// Syncs `spks` and the chain from `chain_tip` into the shared wallet.
+    async fn sync_scripts(
+        &self,
+        chain_tip: CheckPoint,
+        spks: Vec<ScriptBuf>,
+    ) -> anyhow::Result<()> {
+        let request = SyncRequest::from_chain_tip(chain_tip).chain_spks(spks);
+        let mut update = self.gs.blockchain.sync(request).await?;
+
+        let now = std::time::UNIX_EPOCH.elapsed()?.as_secs();
+        let _ = update.graph_update.update_last_seen_unconfirmed(now);
+        let mut wallet = self.gs.wallet.lock().await;
+        wallet.apply_update(update)?;
+        self.note_tip(&wallet);
+        Ok(())
+    }
+}
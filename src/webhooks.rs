@@ -0,0 +1,291 @@
+use anyhow::{anyhow, bail, ensure};
+use bdk_wallet::bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use bdk_wallet::bitcoin::hex::DisplayHex;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::PgPool;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::db;
+use crate::events::{EventKind, WriteEvent};
+
+/// How often due deliveries are picked up.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries picked up per pass.
+const BATCH_SIZE: u32 = 50;
+/// A delivery is given up after this many failed attempts, about a day after the first.
+pub const MAX_ATTEMPTS: u32 = 15;
+const BASE_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// A subscription to write events.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    /// Key of the HMAC sent with every delivery, never returned by the API.
+    pub secret: String,
+    /// Event kinds to deliver, empty for all of them.
+    pub events: Vec<EventKind>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "url": self.url,
+            "events": self.events,
+            "created_at": self.created_at,
+        })
+    }
+}
+
+/// One event sent, or to be sent, to one webhook.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delivery {
+    pub id: i64,
+    pub webhook_id: Uuid,
+    pub event_id: i64,
+    /// `pending`, `delivered` or `failed`.
+    pub status: String,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub response_status: Option<u16>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+impl Delivery {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "webhook_id": self.webhook_id,
+            "event_id": self.event_id,
+            "status": self.status,
+            "attempts": self.attempts,
+            "next_attempt_at": (self.status == "pending").then_some(self.next_attempt_at),
+            "response_status": self.response_status,
+            "last_error": self.last_error,
+            "created_at": self.created_at,
+            "delivered_at": self.delivered_at,
+        })
+    }
+}
+
+/// A delivery claimed by the dispatcher, with what it needs to send it.
+#[derive(Clone, Debug)]
+pub struct DueDelivery {
+    pub id: i64,
+    pub webhook_id: Uuid,
+    pub attempts: u32,
+    pub url: String,
+    pub secret: String,
+    pub event: WriteEvent,
+}
+
+/// How a delivery attempt went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Delivered {
+        status: u16,
+    },
+    /// Try again after `retry_in`.
+    Retry {
+        status: Option<u16>,
+        error: String,
+        retry_in: Duration,
+    },
+    Failed {
+        status: Option<u16>,
+        error: String,
+    },
+}
+
+/// Wait before the next attempt once `attempts` have failed, doubling every time.
+pub fn backoff(attempts: u32) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(16);
+    BASE_BACKOFF.saturating_mul(1 << exponent).min(MAX_BACKOFF)
+}
+
+/// Hex encoded HMAC-SHA256 of the request body, sent as `X-Graffiti-Signature: sha256=<hex>`.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body);
+    hmac::Hmac::<sha256::Hash>::from_engine(engine)
+        .to_byte_array()
+        .to_lower_hex_string()
+}
+
+/// Whether `ip` is reachable on the public internet. Webhooks must not reach loopback,
+/// private, link-local or otherwise special addresses on the service's side of the network.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // 0.0.0.0/8, shared 100.64.0.0/10, benchmarking 198.18.0.0/15, reserved 240/4
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 198 && b & 0xfe == 18)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let [first, second, ..] = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local fc00::/7, link-local fe80::/10, documentation 2001:db8::/32
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+                || (first == 0x2001 && second == 0x0db8))
+        }
+    }
+}
+
+/// Resolves the host of a webhook `url`, refusing it unless every address is public.
+///
+/// # Errors
+///
+/// Will return an error if the url has no host, doesn't resolve or resolves to an address
+/// that isn't public
+pub async fn resolve_public(url: &reqwest::Url) -> anyhow::Result<Vec<SocketAddr>> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("the url has no host"))?
+        // IPv6 literals keep their brackets
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("the url has no port"))?;
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    ensure!(!addrs.is_empty(), "{host} does not resolve");
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        bail!(
+            "{host} resolves to {}, which isn't a public address",
+            addr.ip()
+        );
+    }
+    Ok(addrs)
+}
+
+/// Sends queued deliveries, retrying failed ones with exponential backoff. Deliveries live in
+/// Postgres, so retries survive restarts.
+pub struct Dispatcher {
+    db: PgPool,
+}
+
+impl Dispatcher {
+    pub const fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// A client bound to the public addresses `url` resolves to right now, so a DNS answer
+    /// that changes after the check can't send the delivery elsewhere. Redirects aren't
+    /// followed for the same reason.
+    async fn pinned_client(url: &str) -> anyhow::Result<reqwest::Client> {
+        let url = reqwest::Url::parse(url)?;
+        let addrs = resolve_public(&url).await?;
+        let host = url.host_str().unwrap_or_default();
+        Ok(reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve_to_addrs(host, &addrs)
+            .build()?)
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.dispatch().await {
+                    warn!("webhook dispatcher failed, retrying: {e:?}");
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        })
+    }
+
+    async fn dispatch(&self) -> anyhow::Result<()> {
+        let due = db::claim_due_deliveries(&self.db, BATCH_SIZE).await?;
+        for delivery in due {
+            let outcome = self.send(&delivery).await;
+            match &outcome {
+                Outcome::Delivered { .. } => {}
+                Outcome::Retry { error, .. } => {
+                    info!("delivery {} failed, will retry: {}", delivery.id, error);
+                }
+                Outcome::Failed { error, .. } => {
+                    warn!("giving up on delivery {}: {}", delivery.id, error);
+                }
+            }
+            db::record_delivery(&self.db, delivery.id, &outcome).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&self, delivery: &DueDelivery) -> Outcome {
+        let body = json!({
+            "delivery_id": delivery.id,
+            "webhook_id": delivery.webhook_id,
+            "event": delivery.event,
+        })
+        .to_string();
+
+        let response = match Self::pinned_client(&delivery.url).await {
+            Ok(http) => http
+                .post(&delivery.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("X-Graffiti-Event", delivery.event.kind.as_str())
+                .header("X-Graffiti-Delivery", delivery.id)
+                .header(
+                    "X-Graffiti-Signature",
+                    format!("sha256={}", signature(&delivery.secret, body.as_bytes())),
+                )
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(format!("refusing to deliver to {}: {e}", delivery.url)),
+        };
+
+        let (status, error) = match response {
+            Ok(response) if response.status().is_success() => {
+                return Outcome::Delivered {
+                    status: response.status().as_u16(),
+                }
+            }
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                format!("endpoint answered {}", response.status()),
+            ),
+            Err(e) => (None, e),
+        };
+
+        let attempts = delivery.attempts + 1;
+        if attempts >= MAX_ATTEMPTS {
+            Outcome::Failed { status, error }
+        } else {
+            Outcome::Retry {
+                status,
+                error,
+                retry_in: backoff(attempts),
+            }
+        }
+    }
+}